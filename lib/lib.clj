@@ -1,5 +1,11 @@
+(ns brojure.core)
+
 (do
 
+  (def ^:dynamic *print-length* nil)
+  (def ^:dynamic *print-level* nil)
+  (def ^:dynamic *print-right-margin* 72)
+
   (defn not [x]
     (if x false true))
 
@@ -7,7 +13,7 @@
     (> x 0))
 
   (defn neg? [x]
-    (if (= x 0) false (< x 0)))
+    (< x 0))
 
   (defn odd? [x]
     (if (= (mod x 2) 1) true false))
@@ -28,25 +34,51 @@
     (reduce (fn [acc x] (* acc x)) 1 v))
 
   (defn pop [v]
-    (for [i (range 0 (dec (count v)))]
-      (inc i)))
-
-  (defn take [n v]
-    (for [i (range 0 n)]
-      (nth v i)))
-
-  (defn drop [n v]
-    (for [i (range n (count v))]
-      (nth v i)))
-
-  (defn map [f v]
-    (for [i v]
-      (f i)))
-
-  (defn filter [f v]
-    (for [i v]
-      (if (f i)
-        i)))
+    (reduce conj [] (for [i (range 0 (dec (count v)))]
+                      (nth v i))))
+
+  (defn take [n coll]
+    (lazy-seq
+      (if (pos? n)
+        (let [s (seq coll)]
+          (if s
+            (cons (first s) (take (dec n) (rest s))))))))
+
+  (defn drop [n coll]
+    (lazy-seq
+      (let [s (seq coll)]
+        (if (and s (pos? n))
+          (drop (dec n) (rest s))
+          s))))
+
+  (defn take-while [pred coll]
+    (lazy-seq
+      (let [s (seq coll)]
+        (if (and s (pred (first s)))
+          (cons (first s) (take-while pred (rest s)))))))
+
+  (defn drop-while [pred coll]
+    (lazy-seq
+      (let [s (seq coll)]
+        (if (and s (pred (first s)))
+          (drop-while pred (rest s))
+          s))))
+
+  (defn iterate [f x]
+    (lazy-seq
+      (cons x (iterate f (f x)))))
+
+  (defn cycle [coll]
+    (lazy-seq
+      (if (seq coll)
+        (concat coll (cycle coll)))))
+
+  (defn interleave [c1 c2]
+    (lazy-seq
+      (let [s1 (seq c1)
+            s2 (seq c2)]
+        (if (and s1 s2)
+          (cons (first s1) (cons (first s2) (interleave (rest s1) (rest s2))))))))
 
   (defn euler1 []
     (let [multiple-of-3-or-5?
@@ -54,6 +86,4 @@
             (or (= (mod n 3) 0)
                 (= (mod n 5) 0)))]
 
-      (sum (filter multiple-of-3-or-5? (range 1 1000)))))
-
-)
+      (sum (filter multiple-of-3-or-5? (range 1 1000))))))
@@ -0,0 +1,255 @@
+use std::sync::{Arc, Mutex};
+use node::Node;
+use node::Node::{Bool, Keyword, Atom, Future, Ref, Nil, Error};
+use eval::{Env, eval_args, apply};
+use future;
+use stm;
+
+/// The state behind an atom. Clones of a `Node::Atom` share it, so changes
+/// are seen through every environment holding the atom.
+#[derive(Debug)]
+pub struct State {
+    pub value: Node,
+    validator: Option<Node>,
+    /// Functions called with the key, the atom, and the old and new values
+    /// after every change.
+    watches: Vec<(Node, Node)>,
+}
+
+fn expect_atom(n: &Node, err: &'static str) -> Result<Arc<Mutex<State>>, &'static str> {
+    match *n {
+        Atom(ref a) => Ok(a.clone()),
+        _ => Err(err),
+    }
+}
+
+/// Runs the validator, if there is one, on a value the atom is about to
+/// take.
+fn validate(validator: &Option<Node>, value: &Node) -> Result<(), Node> {
+    match *validator {
+        Some(ref f) => match apply(f, vec![value.clone()]) {
+            e @ Error(_) => Err(e),
+            Nil | Bool(false) => Err(Error("Invalid reference state")),
+            _ => Ok(()),
+        },
+        None => Ok(()),
+    }
+}
+
+/// Sets the atom to `new` if it passes validation and tells the watches.
+/// With `expected`, only does so if the atom still holds it, giving `None`
+/// if another thread changed it first.
+fn set(atom: &Node, state: &Arc<Mutex<State>>, expected: Option<&Node>, new: Node) -> Option<Node> {
+    let validator = state.lock().unwrap().validator.clone();
+    if let Err(e) = validate(&validator, &new) {
+        return Some(e);
+    }
+    let (old, watches) = {
+        let mut state = state.lock().unwrap();
+        if expected.is_some_and(|expected| state.value != *expected) {
+            return None;
+        }
+        (::std::mem::replace(&mut state.value, new.clone()), state.watches.clone())
+    };
+    for (key, f) in watches {
+        if let e @ Error(_) = apply(&f, vec![key, atom.clone(), old.clone(), new.clone()]) {
+            return Some(e);
+        }
+    }
+    Some(new)
+}
+
+/// `(atom x)` or `(atom x :validator f)`.
+pub fn atom(args: &mut [Node], env: &mut Env) -> Node {
+    let mut args = eval_args(args, env).into_iter();
+    let value = args.next().unwrap_or(Nil);
+    let mut validator = None;
+    while let Some(option) = args.next() {
+        match (option, args.next()) {
+            (Keyword(ref k), Some(f)) if k == "validator" => validator = Some(f),
+            _ => return Error("Expected :validator and a function as options to atom"),
+        }
+    }
+    if let Err(e) = validate(&validator, &value) {
+        return e;
+    }
+    Atom(Arc::new(Mutex::new(State { value, validator, watches: Vec::new() })))
+}
+
+/// `(deref a)`, also written `@a`. Futures and promises are waited for,
+/// giving up after `ms` milliseconds with `(deref f ms timeout-value)`.
+pub fn deref(args: &mut [Node], env: &mut Env) -> Node {
+    let mut args = eval_args(args, env);
+    match args.len() {
+        1 => match args.pop() {
+            Some(Atom(a)) => a.lock().unwrap().value.clone(),
+            Some(Future(t)) => t.wait(None).unwrap(),
+            Some(Ref(r)) => stm::deref(&r),
+            _ => Error("Expected a reference as argument to deref"),
+        },
+        3 => match args[0] {
+            Future(ref t) => future::deref_within(t, &args[1], args[2].clone()),
+            _ => Error("Expected a future or promise to deref with a timeout"),
+        },
+        _ => Error("Expected a future or promise to deref with a timeout"),
+    }
+}
+
+/// `(swap! a f & args)` sets the atom to `(f @a args...)` and returns it.
+/// If another thread changes the atom while `f` runs it is run again, so
+/// it should have no side effects.
+pub fn swap(args: &mut [Node], env: &mut Env) -> Node {
+    let mut args = eval_args(args, env);
+    let atom = args.remove(0);
+    let state = match expect_atom(&atom, "Expected an atom as first argument to swap!") {
+        Ok(state) => state,
+        Err(e) => return Error(e),
+    };
+    let f = args.remove(0);
+    loop {
+        let old = state.lock().unwrap().value.clone();
+        let mut call = args.clone();
+        call.insert(0, old.clone());
+        let new = match apply(&f, call) {
+            e @ Error(_) => return e,
+            new => new,
+        };
+        if let Some(result) = set(&atom, &state, Some(&old), new) {
+            return result;
+        }
+    }
+}
+
+/// `(reset! a x)` sets the atom to `x` whatever it was.
+pub fn reset(args: &mut [Node], env: &mut Env) -> Node {
+    let mut args = eval_args(args, env);
+    let new = args.pop().unwrap();
+    let atom = args.pop().unwrap();
+    match expect_atom(&atom, "Expected an atom as first argument to reset!") {
+        Ok(state) => set(&atom, &state, None, new).unwrap(),
+        Err(e) => Error(e),
+    }
+}
+
+/// `(compare-and-set! a old new)` sets the atom only if it is still `old`.
+pub fn compare_and_set(args: &mut [Node], env: &mut Env) -> Node {
+    let mut args = eval_args(args, env);
+    let new = args.pop().unwrap();
+    let old = args.pop().unwrap();
+    let atom = args.pop().unwrap();
+    let state = match expect_atom(&atom, "Expected an atom as first argument to compare-and-set!") {
+        Ok(state) => state,
+        Err(e) => return Error(e),
+    };
+    match set(&atom, &state, Some(&old), new) {
+        Some(e @ Error(_)) => e,
+        Some(_) => Bool(true),
+        None => Bool(false),
+    }
+}
+
+/// `(add-watch a key f)` calls `(f key a old new)` after every change,
+/// replacing any watch already added under `key`.
+pub fn add_watch(args: &mut [Node], env: &mut Env) -> Node {
+    let mut args = eval_args(args, env);
+    let f = args.pop().unwrap();
+    let key = args.pop().unwrap();
+    let atom = args.pop().unwrap();
+    let state = match expect_atom(&atom, "Expected an atom as first argument to add-watch") {
+        Ok(state) => state,
+        Err(e) => return Error(e),
+    };
+    let mut state = state.lock().unwrap();
+    state.watches.retain(|(k, _)| *k != key);
+    state.watches.push((key, f));
+    atom.clone()
+}
+
+/// `(remove-watch a key)`.
+pub fn remove_watch(args: &mut [Node], env: &mut Env) -> Node {
+    let mut args = eval_args(args, env);
+    let key = args.pop().unwrap();
+    let atom = args.pop().unwrap();
+    match expect_atom(&atom, "Expected an atom as first argument to remove-watch") {
+        Ok(state) => {
+            state.lock().unwrap().watches.retain(|(k, _)| *k != key);
+            atom.clone()
+        },
+        Err(e) => Error(e),
+    }
+}
+
+/// `(set-validator! a f)` checks every later value with `f`, and the
+/// current one straight away. `nil` removes the validator.
+pub fn set_validator(args: &mut [Node], env: &mut Env) -> Node {
+    let mut args = eval_args(args, env);
+    let validator = match args.pop().unwrap() {
+        Nil => None,
+        f => Some(f),
+    };
+    let state = match expect_atom(&args[0], "Expected an atom as first argument to set-validator!") {
+        Ok(state) => state,
+        Err(e) => return Error(e),
+    };
+    let value = state.lock().unwrap().value.clone();
+    if let Err(e) = validate(&validator, &value) {
+        return e;
+    }
+    state.lock().unwrap().validator = validator;
+    Nil
+}
+
+/// `(get-validator a)`, or `nil` if it has none.
+pub fn get_validator(args: &mut [Node], env: &mut Env) -> Node {
+    match eval_args(args, env).pop() {
+        Some(Atom(a)) => a.lock().unwrap().validator.clone().unwrap_or(Nil),
+        _ => Error("Expected an atom as argument to get-validator"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eval::{Env, eval_all};
+    use ns;
+
+    fn run(src: &str) -> String {
+        let mut env = Env::new();
+        ns::load(include_str!("../lib/lib.clj"), &mut env);
+        format!("{}", eval_all(src, &mut env))
+    }
+
+    #[test]
+    fn atoms_are_shared_between_environments() {
+        assert_eq!(run("(def a (atom 0)) (defn bump [] (swap! a + 1)) (bump) (bump) @a"), "2");
+        assert_eq!(run("(let [a (atom [])] (doall (map (fn [x] (swap! a conj x)) [1 2])) (deref a))"), "[1 2]");
+        assert_eq!(run("(def a (atom 1)) [(reset! a 5) @a (= a a) (= a (atom 5))]"), "[5 5 true false]");
+    }
+
+    #[test]
+    fn compares_and_sets() {
+        assert_eq!(run("(def a (atom 1)) [(compare-and-set! a 2 3) @a (compare-and-set! a 1 3) @a]"),
+                   "[false 1 true 3]");
+    }
+
+    #[test]
+    fn validates() {
+        assert_eq!(run("(def a (atom 1 :validator pos?)) [(swap! a - 5) @a]"), "[Error: Invalid reference state 1]");
+        assert_eq!(run("(atom -1 :validator pos?)"), "Error: Invalid reference state");
+        assert_eq!(run("(def a (atom 1)) (set-validator! a neg?)"), "Error: Invalid reference state");
+        assert_eq!(run("(def a (atom 1)) (set-validator! a pos?) [(get-validator a) (reset! a 0) @a]"),
+                   "[#<fn pos?> Error: Invalid reference state 1]");
+    }
+
+    #[test]
+    fn calls_watches() {
+        let src = "(def log (atom []))
+                   (def a (atom 1))
+                   (add-watch a :log (fn [k r old new] (swap! log conj [k old new (= r a)])))
+                   (swap! a inc)
+                   (reset! a 10)
+                   (remove-watch a :log)
+                   (reset! a 20)
+                   @log";
+        assert_eq!(run(src), "[[:log 1 2 true] [:log 2 10 true]]");
+    }
+}
@@ -0,0 +1,504 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use node::Node;
+use node::Node::{Int, Bool, Keyword, Symbol, List, Vector, Map, Chan, Nil, Error};
+use eval::{Env, eval, eval_args, check_waiting};
+use future;
+use limits;
+use ns;
+
+/// How a channel holds values no taker has come for yet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Buffer {
+    /// Puts wait while there are this many values, with 0 meaning each put
+    /// waits for a take.
+    Fixed(usize),
+    /// Puts to a full buffer drop the oldest value.
+    Sliding(usize),
+    /// Puts to a full buffer drop the value being put.
+    Dropping(usize),
+}
+
+/// A queue threads hand values over through, as in core.async.
+pub struct Channel {
+    queue: Mutex<Queue>,
+    /// When a channel made by `timeout` closes itself.
+    closes_at: Option<Instant>,
+}
+
+struct Queue {
+    buffer: Buffer,
+    /// Values buffered, followed by those of puts waiting for a take.
+    items: VecDeque<Node>,
+    closed: bool,
+    /// How many values have been put and taken, to tell a put waiting for
+    /// a take when its value has gone.
+    put: u64,
+    taken: u64,
+    /// Threads waiting to take, which unbuffered puts can hand values to.
+    takers: usize,
+}
+
+impl fmt::Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Channel")
+    }
+}
+
+/// What came of offering a value to a channel.
+enum Offer {
+    /// Buffered or dropped, with false if the channel was closed.
+    Done(bool),
+    /// Queued to be handed to a taker, as the put with this number.
+    Waiting(u64),
+}
+
+impl Channel {
+    fn new(buffer: Buffer, closes_at: Option<Instant>) -> Channel {
+        let queue = Queue { buffer, items: VecDeque::new(), closed: false, put: 0, taken: 0, takers: 0 };
+        Channel { queue: Mutex::new(queue), closes_at }
+    }
+
+    fn is_closed(&self, queue: &Queue) -> bool {
+        queue.closed || self.closes_at.is_some_and(|t| Instant::now() >= t)
+    }
+
+    /// Puts `value` if that can be done without waiting for room.
+    fn offer(&self, value: Node) -> Option<Offer> {
+        let mut queue = self.queue.lock().unwrap();
+        if self.is_closed(&queue) {
+            return Some(Offer::Done(false));
+        }
+        let offer = match queue.buffer {
+            Buffer::Fixed(n) if queue.items.len() < n + queue.takers => {
+                queue.items.push_back(value);
+                queue.put += 1;
+                if queue.items.len() <= n { Offer::Done(true) } else { Offer::Waiting(queue.put) }
+            },
+            Buffer::Fixed(_) => return None,
+            Buffer::Sliding(n) => {
+                queue.items.push_back(value);
+                queue.put += 1;
+                if queue.items.len() > n {
+                    queue.items.pop_front();
+                    queue.taken += 1;
+                }
+                Offer::Done(true)
+            },
+            Buffer::Dropping(n) => {
+                if queue.items.len() < n {
+                    queue.items.push_back(value);
+                    queue.put += 1;
+                }
+                Offer::Done(true)
+            },
+        };
+        drop(queue);
+        changed();
+        Some(offer)
+    }
+
+    /// Whether the put numbered `put` has been taken, or at least fits in
+    /// the buffer now.
+    fn handed_over(&self, put: u64) -> Option<()> {
+        let queue = self.queue.lock().unwrap();
+        let room = match queue.buffer {
+            Buffer::Fixed(n) => n as u64,
+            _ => 0,
+        };
+        if queue.taken + room >= put || self.is_closed(&queue) { Some(()) } else { None }
+    }
+
+    /// The next value, `nil` once the channel is closed and empty, or
+    /// `None` if there is nothing to take yet.
+    fn poll(&self) -> Option<Node> {
+        let mut queue = self.queue.lock().unwrap();
+        match queue.items.pop_front() {
+            Some(value) => {
+                queue.taken += 1;
+                drop(queue);
+                changed();
+                Some(value)
+            },
+            None if self.is_closed(&queue) => Some(Nil),
+            None => None,
+        }
+    }
+
+    fn add_taker(&self) {
+        self.queue.lock().unwrap().takers += 1;
+        changed();
+    }
+
+    fn remove_taker(&self) {
+        self.queue.lock().unwrap().takers -= 1;
+    }
+
+    fn close(&self) {
+        self.queue.lock().unwrap().closed = true;
+        changed();
+    }
+}
+
+/// Counts changes to every channel, for threads waiting on any of several
+/// to see when to look again.
+static CHANGES: Mutex<u64> = Mutex::new(0);
+static CHANGED: Condvar = Condvar::new();
+
+fn changed() {
+    *CHANGES.lock().unwrap() += 1;
+    CHANGED.notify_all();
+}
+
+/// How long a wait goes between looking for an interruption.
+const POLL: Duration = Duration::from_millis(50);
+
+/// Tries `attempt` until it succeeds, waiting for the channels to change
+/// in between.
+fn block<T, F: FnMut() -> Option<T>>(chans: &[&Channel], mut attempt: F) -> Result<T, Node> {
+    loop {
+        let seen = *CHANGES.lock().unwrap();
+        if let Some(x) = attempt() {
+            return Ok(x);
+        }
+        check_waiting()?;
+        // Timeout channels close without anything changing.
+        let mut wait = POLL;
+        for ch in chans {
+            if let Some(t) = ch.closes_at {
+                wait = wait.min(t.saturating_duration_since(Instant::now()));
+            }
+        }
+        let changes = CHANGES.lock().unwrap();
+        if *changes == seen {
+            drop(CHANGED.wait_timeout(changes, wait).unwrap());
+        }
+    }
+}
+
+/// Puts `value` on `ch`, waiting for room and, without a buffer, for a
+/// taker. False if the channel was closed.
+fn put(ch: &Channel, value: Node) -> Result<bool, Node> {
+    match block(&[ch], || ch.offer(value.clone()))? {
+        Offer::Done(put) => Ok(put),
+        Offer::Waiting(n) => block(&[ch], || ch.handed_over(n)).map(|_| true),
+    }
+}
+
+fn take(ch: &Channel) -> Result<Node, Node> {
+    ch.add_taker();
+    let value = block(&[ch], || ch.poll());
+    ch.remove_taker();
+    value
+}
+
+fn expect_chan(n: &Node, err: &'static str) -> Result<Arc<Channel>, Node> {
+    match *n {
+        Chan(ref ch) => Ok(ch.clone()),
+        _ => Err(Error(err)),
+    }
+}
+
+/// `(chan)` is a channel where each put waits for a take, `(chan n)` one
+/// buffering `n` values, and `(chan (sliding-buffer n))` or
+/// `(chan (dropping-buffer n))` one whose puts never wait.
+pub fn chan(args: &mut [Node], env: &mut Env) -> Node {
+    let buffer = match eval_args(args, env).pop() {
+        None | Some(Int(0)) => Buffer::Fixed(0),
+        Some(Int(n)) if n > 0 => Buffer::Fixed(n as usize),
+        Some(Map(ref m)) if m.len() == 1 => match m[0] {
+            (Keyword(ref k), Int(n)) if k == "sliding-buffer" && n > 0 => Buffer::Sliding(n as usize),
+            (Keyword(ref k), Int(n)) if k == "dropping-buffer" && n > 0 => Buffer::Dropping(n as usize),
+            _ => return Error("Expected a buffer size or buffer as argument to chan"),
+        },
+        _ => return Error("Expected a buffer size or buffer as argument to chan"),
+    };
+    Chan(Arc::new(Channel::new(buffer, None)))
+}
+
+fn buffer(kind: &str, args: &mut [Node], env: &mut Env) -> Node {
+    match eval_args(args, env).pop() {
+        Some(Int(n)) if n > 0 => Map(vec![(Keyword(kind.to_owned()), Int(n))]),
+        _ => Error("Expected a positive buffer size"),
+    }
+}
+
+/// `(sliding-buffer n)` keeps the last `n` values put.
+pub fn sliding_buffer(args: &mut [Node], env: &mut Env) -> Node {
+    buffer("sliding-buffer", args, env)
+}
+
+/// `(dropping-buffer n)` keeps the first `n` values put.
+pub fn dropping_buffer(args: &mut [Node], env: &mut Env) -> Node {
+    buffer("dropping-buffer", args, env)
+}
+
+/// `(timeout ms)` is a channel that closes itself after `ms` milliseconds.
+pub fn timeout(args: &mut [Node], env: &mut Env) -> Node {
+    match eval_args(args, env).pop() {
+        Some(Int(ms)) if ms >= 0 => {
+            let closes_at = Instant::now() + Duration::from_millis(ms as u64);
+            Chan(Arc::new(Channel::new(Buffer::Fixed(0), Some(closes_at))))
+        },
+        _ => Error("Expected a number of milliseconds as argument to timeout"),
+    }
+}
+
+/// `(>!! ch x)` puts `x` on `ch`, waiting for room. It gives false if the
+/// channel is closed. `>!` in a `go` block is the same.
+pub fn put_blocking(args: &mut [Node], env: &mut Env) -> Node {
+    let mut args = eval_args(args, env);
+    let value = args.pop().unwrap();
+    let ch = match expect_chan(&args[0], "Expected a channel to put to") {
+        Ok(ch) => ch,
+        Err(e) => return e,
+    };
+    if value == Nil {
+        return Error("Can't put nil on a channel");
+    }
+    put(&ch, value).map_or_else(|e| e, Bool)
+}
+
+/// `(<!! ch)` takes a value from `ch`, waiting for one, or gives `nil` once
+/// it is closed and empty. `<!` in a `go` block is the same.
+pub fn take_blocking(args: &mut [Node], env: &mut Env) -> Node {
+    let args = eval_args(args, env);
+    match expect_chan(&args[0], "Expected a channel to take from") {
+        Ok(ch) => take(&ch).unwrap_or_else(|e| e),
+        Err(e) => e,
+    }
+}
+
+/// `(close! ch)` stops puts to `ch`. Values already on it can still be
+/// taken.
+pub fn close(args: &mut [Node], env: &mut Env) -> Node {
+    let args = eval_args(args, env);
+    match expect_chan(&args[0], "Expected a channel to close") {
+        Ok(ch) => {
+            ch.close();
+            Nil
+        },
+        Err(e) => e,
+    }
+}
+
+enum Op {
+    Take(Arc<Channel>),
+    Put(Arc<Channel>, Node),
+}
+
+/// Where `alts!!` starts looking, moved on each time so no channel is
+/// always first.
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// `(alts!! [ch [ch x] ...])` does whichever of the takes and puts can be
+/// done first, giving `[value ch]`, with `true` or `false` as the value of
+/// a put. `:default x` gives `[x :default]` rather than wait, and
+/// `:priority true` tries the operations in order.
+pub fn alts(args: &mut [Node], env: &mut Env) -> Node {
+    let args = eval_args(args, env);
+    let mut ops = Vec::new();
+    let err = Error("Expected a vector of channels and [channel value] pairs as first argument to alts!!");
+    match args[0] {
+        Vector(ref v) if !v.is_empty() => for op in v {
+            match *op {
+                Chan(ref ch) => ops.push(Op::Take(ch.clone())),
+                Vector(ref pair) if pair.len() == 2 && pair[1] != Nil => match pair[0] {
+                    Chan(ref ch) => ops.push(Op::Put(ch.clone(), pair[1].clone())),
+                    _ => return err,
+                },
+                _ => return err,
+            }
+        },
+        _ => return err,
+    }
+    let mut default = None;
+    let mut priority = false;
+    if args.len().is_multiple_of(2) {
+        return Error("Expected options in pairs after the operations in alts!!");
+    }
+    for option in args[1..].chunks(2) {
+        match option[0] {
+            Keyword(ref k) if k == "default" => default = Some(option[1].clone()),
+            Keyword(ref k) if k == "priority" => priority = !matches!(option[1], Nil | Bool(false)),
+            _ => return Error("Unknown option to alts!!"),
+        }
+    }
+    if !priority {
+        let start = NEXT.fetch_add(1, Ordering::Relaxed) % ops.len();
+        ops.rotate_left(start);
+    }
+
+    let chans: Vec<&Channel> = ops.iter().map(|op| match *op {
+        Op::Take(ref ch) | Op::Put(ref ch, _) => &**ch,
+    }).collect();
+    let attempt = || ops.iter().find_map(|op| match *op {
+        Op::Take(ref ch) => ch.poll().map(|value| (value, ch.clone(), None)),
+        Op::Put(ref ch, ref value) => ch.offer(value.clone()).map(|offer| match offer {
+            Offer::Done(put) => (Bool(put), ch.clone(), None),
+            Offer::Waiting(n) => (Bool(true), ch.clone(), Some(n)),
+        }),
+    });
+    let done = match default {
+        Some(value) => match attempt() {
+            Some(done) => done,
+            None => return Vector(vec![value, Keyword("default".to_owned())]),
+        },
+        None => {
+            let takes: Vec<&Channel> = ops.iter().filter_map(|op| match *op {
+                Op::Take(ref ch) => Some(&**ch),
+                Op::Put(..) => None,
+            }).collect();
+            for ch in &takes {
+                ch.add_taker();
+            }
+            let done = block(&chans, attempt);
+            for ch in &takes {
+                ch.remove_taker();
+            }
+            match done {
+                Ok(done) => done,
+                Err(e) => return e,
+            }
+        },
+    };
+    let (value, ch, waiting) = done;
+    if let Some(n) = waiting {
+        if let Err(e) = block(&[&ch], || ch.handed_over(n)) {
+            return e;
+        }
+    }
+    Vector(vec![value, Chan(ch)])
+}
+
+/// Puts the value of `body` on a new channel, then closes it.
+fn run_into(ch: &Channel, mut body: Node, env: &mut Env) -> Node {
+    let value = eval(&mut body, env);
+    if value != Nil {
+        let _ = put(ch, value);
+    }
+    ch.close();
+    Nil
+}
+
+fn body_and_chan(args: &mut Vec<Node>) -> (Node, Arc<Channel>) {
+    let mut forms = vec![Symbol("do".to_owned())];
+    forms.append(args);
+    (List(forms), Arc::new(Channel::new(Buffer::Fixed(1), None)))
+}
+
+/// `(go body...)` evaluates the body on the thread pool, giving a channel
+/// its value is put on. Waiting in a `go` block ties up a worker, but the
+/// pool grows so that it never waits on the blocks it is running.
+pub fn go(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let (body, ch) = body_and_chan(args);
+    let result = ch.clone();
+    future::spawn(env, move |env| run_into(&ch, body, env));
+    Chan(result)
+}
+
+/// `(thread body...)` evaluates the body on a thread of its own, giving a
+/// channel its value is put on.
+pub fn thread(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let (body, ch) = body_and_chan(args);
+    let result = ch.clone();
+    let mut env = env.clone();
+    let bindings = ns::bindings();
+    let spawned = thread::Builder::new()
+        .name("brojure-thread".to_owned())
+        .stack_size(future::STACK_SIZE)
+        .spawn(move || bindings.with(|| {
+            limits::worker_thread();
+            env.enter();
+            run_into(&ch, body, &mut env)
+        }));
+    match spawned {
+        Ok(_) => Chan(result),
+        Err(_) => Error("Couldn't start a thread"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use eval::{Env, eval_all};
+    use limits::EvalLimits;
+    use ns;
+
+    fn run(env: &mut Env, src: &str) -> String {
+        format!("{}", eval_all(src, env))
+    }
+
+    fn lib() -> Env {
+        let mut env = Env::new();
+        ns::load(include_str!("../lib/lib.clj"), &mut env);
+        env
+    }
+
+    #[test]
+    fn buffers() {
+        let mut env = lib();
+        assert_eq!(run(&mut env, "(def c (chan 2)) [(>!! c 1) (>!! c 2) (alts!! [[c 3]] :default :full) (<!! c) (<!! c)]"),
+                   "[true true [:full :default] 1 2]");
+        assert_eq!(run(&mut env, "(def c (chan (sliding-buffer 2))) (>!! c 1) (>!! c 2) (>!! c 3) (close! c)
+                                  [(<!! c) (<!! c) (<!! c) (>!! c 4)]"), "[2 3 nil false]");
+        assert_eq!(run(&mut env, "(def c (chan (dropping-buffer 2))) (>!! c 1) (>!! c 2) (>!! c 3) (close! c)
+                                  [(<!! c) (<!! c) (<!! c)]"), "[1 2 nil]");
+        assert_eq!(run(&mut env, "(>!! (chan 1) nil)"), "Error: Can't put nil on a channel");
+        assert_eq!(run(&mut env, "(chan -1)"), "Error: Expected a buffer size or buffer as argument to chan");
+    }
+
+    #[test]
+    fn unbuffered_puts_wait_for_takes() {
+        let mut env = lib();
+        assert_eq!(run(&mut env, "(def c (chan)) (def log (atom []))
+                                  (def t (thread (>!! c 1) (swap! log conj :put) (>!! c 2) (close! c)))
+                                  (sleep 20) (swap! log conj :taking)
+                                  [(<!! c) (<!! c) (<!! c) (<!! t) @log]"),
+                   "[1 2 nil nil [:taking :put]]");
+    }
+
+    #[test]
+    fn alts_with_timeouts() {
+        let mut env = lib();
+        assert_eq!(run(&mut env, "(def c (chan)) (def t (timeout 20)) (= (alts!! [c t]) [nil t])"), "true");
+        assert_eq!(run(&mut env, "(def c (chan 1)) (>!! c :x) (= (alts!! [(timeout 1000) c]) [:x c])"), "true");
+        assert_eq!(run(&mut env, "(def a (chan 1)) (def b (chan 1)) (>!! a 1) (>!! b 2)
+                                  [(alts!! [a b] :priority true) (alts!! [a b] :priority true)]"),
+                   "[[1 #<chan>] [2 #<chan>]]");
+        assert_eq!(run(&mut env, "(def c (chan)) (thread (<!! c)) (= (alts!! [[c 1] (timeout 1000)] :priority true) [true c])"),
+                   "true");
+        assert_eq!(run(&mut env, "(alts!! [])"),
+                   "Error: Expected a vector of channels and [channel value] pairs as first argument to alts!!");
+    }
+
+    #[test]
+    fn go_pipelines() {
+        let mut env = lib();
+        assert_eq!(run(&mut env, "(def in (chan)) (def out (chan 10))
+                                  (defn stage [] (go (let [x (<! in)] (if x (do (>! out (* x x)) (stage)) (close! out)))))
+                                  (stage)
+                                  (go (doall (map (fn [x] (>! in x)) (range 1 6))) (close! in))
+                                  [(<!! out) (<!! out) (<!! out) (<!! out) (<!! out) (<!! out)]"),
+                   "[1 4 9 16 25 nil]");
+        assert_eq!(run(&mut env, "(def c (chan 200))
+                                  (def producers (doall (map (fn [i] (thread (doall (map (fn [x] (>!! c x)) (range 50)))))
+                                                             (range 4))))
+                                  (doall (map <!! producers))
+                                  (close! c)
+                                  [(reduce (fn [acc _] (+ acc (<!! c))) 0 (range 200)) (<!! c)]"), "[4900 nil]");
+    }
+
+    #[test]
+    fn waits_are_limited() {
+        let mut env = lib();
+        env.set_limits(EvalLimits { timeout: Some(Duration::from_millis(100)), ..EvalLimits::default() });
+        assert_eq!(run(&mut env, "(<!! (chan))"), "Error: Evaluation timed out");
+        assert_eq!(run(&mut env, "(>!! (chan) 1)"), "Error: Evaluation timed out");
+        assert_eq!(run(&mut env, "(<!! (go 1))"), "1");
+    }
+}
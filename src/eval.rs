@@ -1,170 +1,682 @@
 use std::collections::HashMap;
+use std::sync::{Arc, Weak, Mutex, RwLock};
+use std::cell::RefCell;
+use std::fmt;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
 use node::Node;
-use node::Node::{Int, Bool, Symbol, List, Vector, Lambda, Nil, Error};
+use node::Node::{Int, Bool, Symbol, List, Vector, Map, Set, Lambda, Builtin, Nil, Error};
+use seq::{self, Lazy, Seqable};
+use number;
+use string;
+use re;
+use print;
+use pretty;
+use testing;
+use ns;
+use atom;
+use io;
+use host::{self, Capabilities};
+use limits::{EvalLimits, CancelHandle};
+use future;
+use chan;
+use stm;
+use parser::{tokenize, parse, without_meta, is_dynamic};
 
-pub type Env = HashMap<String, Node>;
+/// Global definitions live in namespaces shared by every clone of an
+/// environment, while local bindings (function parameters, `let`, `for`)
+/// are copied so closures can capture them. Each environment remembers the
+/// namespace it was created in, so functions see the globals of the
+/// namespace they were defined in.
+#[derive(Clone)]
+pub struct Env {
+    namespaces: Arc<RwLock<ns::Registry>>,
+    ns: Arc<String>,
+    locals: HashMap<String, Node>,
+}
+
+thread_local! {
+    /// The namespaces of the environment evaluated in most recently, given
+    /// to builtins that `apply` calls, since it has no environment of its
+    /// own for them.
+    static CURRENT: RefCell<Weak<RwLock<ns::Registry>>> = const { RefCell::new(Weak::new()) };
+}
+
+impl Env {
+    pub fn new() -> Env {
+        Env {
+            namespaces: Arc::new(RwLock::new(ns::Registry::default())),
+            ns: Arc::new(ns::USER.to_owned()),
+            locals: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, s: &str) -> Option<Node> {
+        match self.locals.get(s) {
+            Some(n) => Some(n.clone()),
+            None    => self.namespaces.read().unwrap().resolve(&self.ns, s),
+        }
+    }
+
+    pub fn insert(&mut self, s: String, n: Node) {
+        self.locals.insert(s, n);
+    }
+
+    pub fn define(&self, s: String, n: Node) {
+        self.namespaces.write().unwrap().define(&self.ns, s, n);
+    }
+
+    pub fn ns(&self) -> &str {
+        &self.ns
+    }
+
+    pub fn set_ns(&mut self, ns: &str) {
+        self.ns = Arc::new(ns.to_owned());
+    }
+
+    pub fn registry(&self) -> Arc<RwLock<ns::Registry>> {
+        self.namespaces.clone()
+    }
+
+    /// An environment in the namespaces last evaluated in, or a new one if
+    /// they have gone.
+    fn current() -> Env {
+        match CURRENT.with(|c| c.borrow().upgrade()) {
+            Some(namespaces) => Env { namespaces, ns: Arc::new(ns::USER.to_owned()), locals: HashMap::new() },
+            None => Env::new(),
+        }
+    }
+
+    /// Makes these the namespaces builtins called through `apply` on this
+    /// thread see.
+    pub fn enter(&self) {
+        CURRENT.with(|c| {
+            if c.borrow().as_ptr() != Arc::as_ptr(&self.namespaces) {
+                *c.borrow_mut() = Arc::downgrade(&self.namespaces);
+            }
+        });
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.namespaces.read().unwrap().capabilities()
+    }
+
+    /// Limits what code evaluated in these namespaces can do, for hosts
+    /// embedding the interpreter.
+    pub fn set_capabilities(&self, capabilities: Capabilities) {
+        self.namespaces.write().unwrap().set_capabilities(capabilities);
+    }
+
+    /// Bounds the work each top-level evaluation in these namespaces can do.
+    pub fn set_limits(&self, limits: EvalLimits) {
+        self.namespaces.write().unwrap().set_limits(limits);
+    }
+
+    /// A handle other threads can interrupt evaluation in these namespaces
+    /// with.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.namespaces.read().unwrap().usage().cancel_handle()
+    }
+
+    fn start_form(&self) -> Result<(), Node> {
+        let registry = self.namespaces.read().unwrap();
+        registry.usage().enter(registry.limits())
+    }
+
+    fn finish_form(&self) -> Option<Node> {
+        self.namespaces.read().unwrap().usage().leave()
+    }
+
+    /// The same namespaces without any local bindings, for loading code.
+    pub fn top_level(&self) -> Env {
+        Env { namespaces: self.namespaces.clone(), ns: self.ns.clone(), locals: HashMap::new() }
+    }
+}
+
+/// Fails if a collection of `len` elements is more than the evaluation in
+/// progress may build, or it has already gone over one of its limits.
+pub fn check_size(len: usize) -> Result<(), Node> {
+    match CURRENT.with(|c| c.borrow().upgrade()) {
+        Some(namespaces) => match namespaces.try_read() {
+            Ok(registry) => registry.usage().check_size(registry.limits(), len),
+            Err(_) => Ok(()),
+        },
+        None => Ok(()),
+    }
+}
+
+/// Fails if the evaluation in progress has been interrupted or gone over
+/// one of its limits while this thread waits for another.
+pub fn check_waiting() -> Result<(), Node> {
+    match CURRENT.with(|c| c.borrow().upgrade()) {
+        Some(namespaces) => match namespaces.try_read() {
+            Ok(registry) => registry.usage().check_waiting(registry.limits()),
+            Err(_) => Ok(()),
+        },
+        None => Ok(()),
+    }
+}
+
+impl fmt::Debug for Env {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Env {:?}", self.locals.keys().collect::<Vec<_>>())
+    }
+}
 
 pub fn eval(node: &mut Node, env: &mut Env) -> Node {
-    match node {
-        &mut List(ref mut args) => {
-            let operator = args.remove(0);
-
-            match operator {
-                Symbol(s) => {
-                    let s = s.as_ref();
-                    match s {
-                        "+"     => add(args, env),
-                        "-"     => subtract(args, env),
-                        "*"     => multiply(args, env),
-                        "/"     => divide(args, env),
-                        ">"     => gt(args, env),
-                        "<"     => lt(args, env),
-                        ">="    => gt_or_eq(args, env),
-                        "<="    => lt_or_eq(args, env),
-                        "="     => equal(args,env),
-                        "def"   => def(args, env),
-                        "defn"  => defn(args,env),
-                        "if"    => _if(args, env),
-                        "or"    => or(args, env),
-                        "and"   => and(args, env),
-                        "do"    => _do(args, env),
-                        "fn"    => func(args),
-                        "nth"   => nth(args, env),
-                        "println" => println(args, env),
-                        "let"   => _let(args, env),
-                        "conj"  => conj(args, env),
-                        "for"   => _for(args, env),
-                        "reduce" => _reduce(args, env),
-                        "range" => range(args, env),
-                        "count" => count(args, env),
-                        "mod"   => _mod(args, env),
-                        _       => {
-                            match env.get(s) {
-                                Some(n) => {
-                                    let evaled = eval(&mut n.clone(), &mut env.clone());
-                                    match evaled {
-                                        lambda @ Lambda { .. } => {
-                                            args.insert(0, lambda);
-                                            eval(&mut List(args.clone()), &mut env.clone())
-                                        },
-                                        _ => return Error("Couldn't invoke"),
-                                    }
-                                },
-                                None => return Error("Couldn't resolve symbol"),
-                            }
-                        },
-                    }
-                },
-                Lambda { ref params, ref body } => {
-                    let mut new_env = env.clone();
-                    for p in params {
-                        new_env.insert(p.to_owned(), args.remove(0));
-                    }
-                    eval(&mut body.clone(), &mut new_env)
-                },
-                mut list @ List(_) => {
-                    let evaled = eval(&mut list, env);
-                    args.insert(0, evaled);
-                    eval(&mut List(args.clone()), env)
-                },
-                _ => Error("Couldn't invoke"),
+    match *node {
+        List(ref mut args) => {
+            if args.is_empty() {
+                return List(vec![]);
             }
+            env.enter();
+            if let Err(e) = env.start_form() {
+                return e;
+            }
+            let result = eval_list(args, env);
+            env.finish_form().unwrap_or(result)
         },
-        &mut Vector(ref mut v) => {
+        Vector(ref mut v) => {
             let v = v.iter_mut()
                 .map(|n| eval(n, env))
                 .collect::<Vec<Node>>();
             Vector(v)
         },
-        &mut Symbol(ref s) => {
-            let mut n: Node =
+        Map(ref mut m) => {
+            let mut evaled = Vec::new();
+            for (k, v) in m.iter_mut() {
+                let k = eval(k, env);
+                let v = eval(v, env);
+                evaled.push((k, v));
+            }
+            seq::map_of(evaled)
+        },
+        Set(ref mut v) => {
+            seq::set_of(v.iter_mut().map(|n| eval(n, env)).collect())
+        },
+        Symbol(ref s) => {
             match env.get(s) {
-                Some(node) => node.clone(),
-                _ => return Error("Couldn't resolve symbol"),
-            };
-            eval(&mut n, env)
+                Some(node) => node,
+                None if builtin(s).is_some() => Builtin(s.to_owned()),
+                _ => Error("Couldn't resolve symbol"),
+            }
         },
         _ => node.to_owned(),
     }
 }
 
-fn add(args: &mut Vec<Node>, env: &mut Env) -> Node {
-    let start = eval(&mut args.remove(0), env);
-    args.iter_mut().fold(start, |acc, n| acc + eval(n, env))
+/// Evaluates a non-empty list: a special form or a function call.
+fn eval_list(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let operator = args.remove(0);
+
+    match operator {
+        Symbol(s) => {
+            let s = s.as_ref();
+            if let Some((min, max, message)) = special_form_arity(s) {
+                if args.len() < min || max.is_some_and(|max| args.len() > max) {
+                    return Error(message);
+                }
+            }
+            match s {
+                "quote" => args.remove(0),
+                "def"   => def(args, env),
+                "defn"  => defn(args,env),
+                "if"    => _if(args, env),
+                "or"    => or(args, env),
+                "and"   => and(args, env),
+                "do"    => _do(args, env),
+                "fn"    => func(args, env),
+                "let"   => _let(args, env),
+                "for"   => _for(args, env),
+                "lazy-seq" => lazy_seq(args, env),
+                "ns"    => ns::ns(args, env),
+                "with-open" => io::with_open(args, env),
+                "binding" => ns::binding(args, env),
+                "set!"  => ns::set(args, env),
+                "with-redefs" => ns::with_redefs(args, env),
+                "^"     => eval(&mut args[1], env),
+                "with-out-str" => io::with_out_str(args, env),
+                "future" => future::future(args, env),
+                "go"    => chan::go(args, env),
+                "thread" => chan::thread(args, env),
+                "dosync" => stm::dosync(args, env),
+                "deftest" => testing::deftest(args, env),
+                "is"    => testing::is(args, env),
+                "are"   => testing::are(args, env),
+                "testing" => testing::testing(args, env),
+                _       => {
+                    // Locals and vars named like builtins hide them.
+                    let f = match (env.get(s), builtin(s)) {
+                        (Some(n), _) => n,
+                        (None, Some(f)) => {
+                            if !arity_ok(s, args.len()) {
+                                return Error("Wrong number of arguments passed to function");
+                            }
+                            return f(args, env);
+                        },
+                        (None, None) => return Error("Couldn't resolve symbol"),
+                    };
+                    let args = eval_args(args, env);
+                    apply(&f, args)
+                },
+            }
+        },
+        lambda @ Lambda { .. } => {
+            let args = eval_args(args, env);
+            apply(&lambda, args)
+        },
+        mut list @ List(_) => {
+            let f = eval(&mut list, env);
+            let args = eval_args(args, env);
+            apply(&f, args)
+        },
+        _ => Error("Couldn't invoke"),
+    }
+}
+
+type BuiltinFn = fn(&mut Vec<Node>, &mut Env) -> Node;
+
+/// Functions implemented in Rust. Unlike the special forms in `eval` these
+/// can also be passed around as values.
+fn builtin(name: &str) -> Option<BuiltinFn> {
+    let f: BuiltinFn = match name {
+        "+" | "+'" => |a, e| add(a, e),
+        "-" | "-'" => subtract,
+        "*" | "*'" => |a, e| multiply(a, e),
+        "/"     => divide,
+        ">"     => |a, e| gt(a, e),
+        "<"     => |a, e| lt(a, e),
+        ">="    => |a, e| gt_or_eq(a, e),
+        "<="    => |a, e| lt_or_eq(a, e),
+        "="     => |a, e| equal(a, e),
+        "not="  => |a, e| not_equal(a, e),
+        "=="    => |a, e| num_equal(a, e),
+        "compare" => compare,
+        "sort"  => |a, e| sort(a, e),
+        "nth"   => nth,
+        "conj"  => conj,
+        "reduce" => |a, e| _reduce(a, e),
+        "range" => |a, e| range(a, e),
+        "count" => count,
+        "mod"   => _mod,
+        "quot"  => quot,
+        "rem"   => rem,
+        "cons"  => cons,
+        "first" => first,
+        "rest"  => rest,
+        "next"  => next,
+        "seq"   => _seq,
+        "map"   => map,
+        "filter" => |a, e| filter(a, e, true),
+        "remove" => |a, e| filter(a, e, false),
+        "concat" => |a, e| concat(a, e),
+        "repeat" => |a, e| repeat(a, e),
+        "doall" => doall,
+        "dorun" => dorun,
+        "list"  => |a, e| List(eval_args(a, e)),
+        "hash-map" => |a, e| hash_map(a, e),
+        "hash-set" => |a, e| seq::set_of(eval_args(a, e)),
+        "get"   => |a, e| get(a, e),
+        "assoc" => |a, e| assoc(a, e),
+        "contains?" => contains,
+        "vector?" => is_vector,
+        "distinct" => distinct,
+        "hash"  => hash,
+        "print" => |a, e| print::print(a, e),
+        "println" => |a, e| print::println(a, e),
+        "pr"    => |a, e| print::pr(a, e),
+        "prn"   => |a, e| print::prn(a, e),
+        "pr-str" => |a, e| Node::Str(print::pr_str(&eval_args(a, e), e)),
+        "print-str" => |a, e| Node::Str(print::print_str(&eval_args(a, e), e)),
+        "pprint" => |a, e| pretty::print(a, e, false),
+        "pprint-code" => |a, e| pretty::print(a, e, true),
+        "format" => |a, e| print::format(a, e),
+        "printf" => |a, e| print::printf(a, e),
+        "str"   => |a, e| string::str(a, e),
+        "subs"  => |a, e| string::subs(a, e),
+        "split" => |a, e| string::split(a, e),
+        "split-lines" => |a, e| string::split_lines(a, e),
+        "join"  => |a, e| string::join(a, e),
+        "trim"  => |a, e| string::trim(a, e),
+        "upper-case" => |a, e| string::upper_case(a, e),
+        "lower-case" => |a, e| string::lower_case(a, e),
+        "starts-with?" => |a, e| string::starts_with(a, e),
+        "ends-with?" => |a, e| string::ends_with(a, e),
+        "includes?" => |a, e| string::includes(a, e),
+        "replace" => |a, e| string::replace(a, e),
+        "index-of" => |a, e| string::index_of(a, e),
+        "reverse" => |a, e| string::reverse(a, e),
+        "re-pattern" => |a, e| re::re_pattern(a, e),
+        "re-find" => |a, e| re::re_find(a, e),
+        "re-matches" => |a, e| re::re_matches(a, e),
+        "re-seq" => |a, e| re::re_seq(a, e),
+        "use-fixtures" => |a, e| testing::use_fixtures(a, e),
+        "test-var" => |a, e| testing::test_var(a, e),
+        "run-tests" => |a, e| testing::run_tests(a, e),
+        "require" => |a, e| ns::require(a, e),
+        "in-ns" => |a, e| ns::in_ns(a, e),
+        "load-file" => |a, e| ns::load_file(a, e),
+        "atom"  => |a, e| atom::atom(a, e),
+        "deref" => |a, e| atom::deref(a, e),
+        "swap!" => |a, e| atom::swap(a, e),
+        "reset!" => |a, e| atom::reset(a, e),
+        "compare-and-set!" => |a, e| atom::compare_and_set(a, e),
+        "add-watch" => |a, e| atom::add_watch(a, e),
+        "remove-watch" => |a, e| atom::remove_watch(a, e),
+        "set-validator!" => |a, e| atom::set_validator(a, e),
+        "get-validator" => |a, e| atom::get_validator(a, e),
+        "slurp" => |a, e| io::slurp(a, e),
+        "spit"  => |a, e| io::spit(a, e),
+        "reader" => |a, e| io::reader(a, e),
+        "writer" => |a, e| io::writer(a, e),
+        "line-seq" => |a, e| io::line_seq(a, e),
+        "write" => |a, e| io::write(a, e),
+        "close" => |a, e| io::close(a, e),
+        "file-exists?" => |a, e| io::file_exists(a, e),
+        "list-dir" => |a, e| io::list_dir(a, e),
+        "mkdir" => |a, e| io::mkdir(a, e),
+        "delete-file" => |a, e| io::delete_file(a, e),
+        "read-line" => |a, e| io::read_line_in(a, e),
+        "flush" => |a, e| io::flush(a, e),
+        "sh"    => |a, e| host::sh(a, e),
+        "getenv" => |a, e| host::getenv(a, e),
+        "exit"  => |a, e| host::exit(a, e),
+        "current-time-millis" => |a, e| host::current_time_millis(a, e),
+        "nano-time" => |a, e| host::nano_time(a, e),
+        "sleep" => |a, e| host::sleep(a, e),
+        "promise" => |a, e| future::promise(a, e),
+        "deliver" => |a, e| future::deliver(a, e),
+        "pmap" => |a, e| future::pmap(a, e),
+        "pcalls" => |a, e| future::pcalls(a, e),
+        "chan" => |a, e| chan::chan(a, e),
+        "sliding-buffer" => |a, e| chan::sliding_buffer(a, e),
+        "dropping-buffer" => |a, e| chan::dropping_buffer(a, e),
+        "timeout" => |a, e| chan::timeout(a, e),
+        ">!!" | ">!" => |a, e| chan::put_blocking(a, e),
+        "<!!" | "<!" => |a, e| chan::take_blocking(a, e),
+        "close!" => |a, e| chan::close(a, e),
+        "alts!!" | "alts!" => |a, e| chan::alts(a, e),
+        "ref" => |a, e| stm::new_ref(a, e),
+        "alter" => |a, e| stm::alter(a, e),
+        "commute" => |a, e| stm::commute(a, e),
+        "ref-set" => |a, e| stm::ref_set(a, e),
+        "ensure" => |a, e| stm::ensure(a, e),
+        _ => return None,
+    };
+    Some(f)
+}
+
+pub fn is_builtin(name: &str) -> bool {
+    builtin(name).is_some()
+}
+
+/// The least and, if there is one, the most arguments a builtin takes.
+pub fn builtin_arity(name: &str) -> Option<(usize, Option<usize>)> {
+    let arity = match name {
+        "+" | "+'" | "*" | "*'" | "concat" | "list" | "hash-map" | "hash-set" | "str" | "print"
+        | "println" | "pr" | "prn" | "pr-str" | "print-str" | "pprint" | "pprint-code" => (0, None),
+        "use-fixtures" => (2, None),
+        "require" | "atom" => (1, None),
+        "swap!" | "alter" | "commute" => (2, None),
+        "writer" | "write" => (1, None),
+        "spit" => (2, None),
+        "run-tests" | "read-line" | "flush" | "current-time-millis" | "nano-time" | "promise" => (0, Some(0)),
+        "pcalls" => (0, None),
+        "sh" => (1, None),
+        "getenv" | "exit" => (0, Some(1)),
+        "-" | "-'" | "/" | ">" | "<" | ">=" | "<=" | "=" | "not=" | "==" | "format" | "printf" => (1, None),
+        "map" | "pmap" => (2, None),
+        "assoc" => (3, None),
+        "count" | "first" | "rest" | "next" | "seq" | "doall" | "dorun" | "distinct" | "hash" | "vector?"
+        | "split-lines" | "trim" | "upper-case" | "lower-case" | "reverse" | "re-pattern"
+        | "test-var" | "in-ns" | "load-file" | "get-validator"
+        | "slurp" | "reader" | "line-seq" | "close" | "sliding-buffer" | "dropping-buffer" | "timeout"
+        | "<!!" | "<!" | "close!" | "ref" | "ensure" | "file-exists?" | "list-dir" | "mkdir" | "sleep" => (1, Some(1)),
+        "compare" | "nth" | "conj" | "mod" | "quot" | "rem" | "cons" | "filter" | "remove"
+        | "contains?" | "split" | "starts-with?" | "ends-with?" | "includes?" | "re-find"
+        | "re-matches" | "re-seq" | "reset!" | "deliver" | ">!!" | ">!" | "ref-set" | "remove-watch" | "set-validator!" => (2, Some(2)),
+        "replace" | "compare-and-set!" | "add-watch" => (3, Some(3)),
+        "reduce" => (2, Some(3)),
+        "range" => (0, Some(3)),
+        "deref" => (1, Some(3)),
+        "chan" => (0, Some(1)),
+        "alts!!" | "alts!" => (1, None),
+        "sort" | "repeat" | "join" | "delete-file" => (1, Some(2)),
+        "get" | "subs" | "index-of" => (2, Some(3)),
+        _ => return None,
+    };
+    Some(arity)
+}
+
+fn arity_ok(name: &str, n: usize) -> bool {
+    match builtin_arity(name) {
+        Some((min, max)) => n >= min && max.is_none_or(|max| n <= max),
+        None => true,
+    }
+}
+
+/// How many forms the special forms that need some take, and the error
+/// for being given too few or too many.
+fn special_form_arity(name: &str) -> Option<(usize, Option<usize>, &'static str)> {
+    let arity = match name {
+        "quote" => (1, Some(1), "Expected one form as argument to quote"),
+        "def"   => (2, Some(2), "Expected a name and a value as arguments to def"),
+        "defn"  => (2, None, "Expected a name and a parameter vector as arguments to defn"),
+        "if"    => (2, Some(3), "Expected a condition, a form and an optional else form as arguments to if"),
+        "fn"    => (1, None, "Expected vector as first argument to fn"),
+        "let"   => (1, None, "Expected vector as first argument to let"),
+        "for"   => (1, None, "Expected binding vector as first argument to for"),
+        "with-open" => (1, None, "Expected vector as first argument to with-open"),
+        "binding" => (1, None, "Expected vector as first argument to binding"),
+        "with-redefs" => (1, None, "Expected vector as first argument to with-redefs"),
+        "set!"  => (2, Some(2), "Expected a var and a value as arguments to set!"),
+        "^"     => (2, Some(2), "Expected metadata and a form after ^"),
+        _ => return None,
+    };
+    Some(arity)
+}
+
+/// Names `eval` handles itself, which aren't values.
+pub fn is_special_form(name: &str) -> bool {
+    matches!(name, "quote" | "def" | "defn" | "if" | "or" | "and" | "do" | "fn" | "let" | "for" | "lazy-seq"
+             | "ns" | "with-open" | "binding" | "set!" | "with-redefs" | "^" | "with-out-str" | "future" | "go" | "thread" | "dosync" | "deftest" | "is" | "are" | "testing")
+}
+
+pub fn eval_args(args: &mut [Node], env: &mut Env) -> Vec<Node> {
+    args.iter_mut().map(|n| eval(n, env)).collect()
+}
+
+/// Evaluates every form in `src` in turn, returning the value of the last
+/// one or the first error.
+pub fn eval_all(src: &str, env: &mut Env) -> Node {
+    let mut tokens = tokenize(src);
+    let mut result = Nil;
+    while !tokens.is_empty() {
+        result = eval(&mut parse(&mut tokens), env);
+        if let Error(_) = result {
+            return result;
+        }
+    }
+    result
+}
+
+/// Calls a function value with already evaluated arguments.
+pub fn apply(f: &Node, mut args: Vec<Node>) -> Node {
+    match *f {
+        Lambda { ref params, ref body, ref env, .. } => {
+            if params.len() != args.len() {
+                return Error("Wrong number of arguments passed to function");
+            }
+            let mut new_env = env.clone();
+            for p in params {
+                new_env.insert(p.to_owned(), args.remove(0));
+            }
+            eval(&mut (**body).clone(), &mut new_env)
+        },
+        Builtin(ref name) => {
+            if !arity_ok(name, args.len()) {
+                return Error("Wrong number of arguments passed to function");
+            }
+            let f = builtin(name).unwrap();
+            let mut quoted = args.into_iter()
+                .map(|n| List(vec![Symbol("quote".to_owned()), n]))
+                .collect();
+            f(&mut quoted, &mut Env::current())
+        },
+        _ => Error("Couldn't invoke"),
+    }
+}
+
+fn add(args: &mut [Node], env: &mut Env) -> Node {
+    args.iter_mut().fold(Int(0), |acc, n| acc + eval(n, env))
 }
 
 fn subtract(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    if args.len() == 1 {
+        return Int(0) - eval(&mut args[0], env);
+    }
     let start = eval(&mut args.remove(0), env);
     args.iter_mut().fold(start, |acc, n| acc - eval(n, env))
 }
 
-fn multiply(args: &mut Vec<Node>, env: &mut Env) -> Node {
-    let start = eval(&mut args.remove(0), env);
-    args.iter_mut().fold(start, |acc, n| acc * eval(n, env))
+fn multiply(args: &mut [Node], env: &mut Env) -> Node {
+    args.iter_mut().fold(Int(1), |acc, n| acc * eval(n, env))
 }
 
 fn divide(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    if args.len() == 1 {
+        return Int(1) / eval(&mut args[0], env);
+    }
     let start = eval(&mut args.remove(0), env);
     args.iter_mut().fold(start, |acc, n| acc / eval(n, env))
 }
 
 fn def(args: &mut Vec<Node>, env: &mut Env) -> Node {
-    let symbol = match args.remove(0) {
-        Symbol(s) => s,
+    let name = args.remove(0);
+    let symbol = match *without_meta(&name) {
+        Symbol(ref s) => s.clone(),
         _ => return Error("Expected symbol as first argument to def"),
     };
     let mut node = args.remove(0);
     let mut env2 = env.clone();
-    env.insert(symbol, eval(&mut node, &mut env2));
+    let value = eval(&mut node, &mut env2);
+    env.registry().write().unwrap().set_dynamic(env.ns(), &symbol, is_dynamic(&name));
+    env.define(symbol, value);
     Nil
 }
 
-fn defn(mut args: &mut Vec<Node>, env: &mut Env) -> Node {
-    let mut node = vec![args.remove(1), args.remove(1)];
-    let func = func(&mut node);
+fn defn(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let mut node = args.split_off(1);
+    let func = match (func(&mut node, env), without_meta(&args[0])) {
+        (Lambda { params, body, env, .. }, Symbol(s)) => Lambda { name: Some(s.to_owned()), params, body, env },
+        (e @ Error(_), _) => return e,
+        (n, _) => n,
+    };
     args.push(func);
-    def(&mut args, env)
+    def(args, env)
 }
 
-fn equal(args: &mut Vec<Node>, env: &mut Env) -> Node {
-    Bool(eval(&mut args[0], env) == eval(&mut args[1], env))
+fn equal(args: &mut [Node], env: &mut Env) -> Node {
+    let args = eval_args(args, env);
+    if args.is_empty() {
+        return Error("Expected at least one argument to =");
+    }
+    Bool(args.windows(2).all(|w| w[0] == w[1]))
 }
 
-fn gt(args: &mut Vec<Node>, env: &mut Env) -> Node {
-    let first  = eval(&mut args.remove(0), env);
-    let second = eval(&mut args.remove(0), env);
+fn not_equal(args: &mut [Node], env: &mut Env) -> Node {
+    match equal(args, env) {
+        Bool(b) => Bool(!b),
+        _       => Error("Expected at least one argument to not="),
+    }
+}
 
-    match (first, second) {
-        (Int(n1), Int(n2)) => Bool(n1 > n2),
-        _ => Error("Expected numbers as arguments to >"),
+/// Checks that every adjacent pair of numbers is ordered as `ok` expects,
+/// so `(< 1 2 3)` reads as a chain.
+fn compare_chain(args: &mut [Node], env: &mut Env, ok: fn(Ordering) -> bool, err: &'static str) -> Node {
+    let args = eval_args(args, env);
+    if args.is_empty() || !args.iter().all(number::is_number) {
+        return Error(err);
     }
+    Bool(args.windows(2).all(|w| number::compare(&w[0], &w[1]).is_some_and(ok)))
+}
+
+fn num_equal(args: &mut [Node], env: &mut Env) -> Node {
+    compare_chain(args, env, |o| o == Ordering::Equal, "Expected numbers as arguments to ==")
 }
 
-fn gt_or_eq(args: &mut Vec<Node>, env: &mut Env) -> Node {
+fn gt(args: &mut [Node], env: &mut Env) -> Node {
+    compare_chain(args, env, |o| o == Ordering::Greater, "Expected numbers as arguments to >")
+}
+
+fn gt_or_eq(args: &mut [Node], env: &mut Env) -> Node {
+    compare_chain(args, env, |o| o != Ordering::Less, "Expected numbers as arguments to >=")
+}
+
+fn lt(args: &mut [Node], env: &mut Env) -> Node {
+    compare_chain(args, env, |o| o == Ordering::Less, "Expected numbers as arguments to <")
+}
+
+fn lt_or_eq(args: &mut [Node], env: &mut Env) -> Node {
+    compare_chain(args, env, |o| o != Ordering::Greater, "Expected numbers as arguments to <=")
+}
+
+fn compare(args: &mut Vec<Node>, env: &mut Env) -> Node {
     let first  = eval(&mut args.remove(0), env);
     let second = eval(&mut args.remove(0), env);
 
-    match (first, second) {
-        (Int(n1), Int(n2)) => Bool(n1 > n2 || n1 == n2),
-        _ => Error("Expected numbers as arguments to >="),
+    match first.partial_cmp(&second) {
+        Some(o) => Int(o as i64),
+        None    => Error("Can't compare values of different types"),
     }
 }
 
-
-fn lt(args: &mut Vec<Node>, env: &mut Env) -> Node {
-    match gt(args, env) {
-        Bool(b) => Bool(!b),
-        _       => Error("Expected numbers as arguments to <"),
+/// Runs a user comparator, which may return a number like `compare` or a
+/// boolean like `<`.
+fn comparator_order(f: &Node, a: &Node, b: &Node) -> Result<Ordering, Node> {
+    match apply(f, vec![a.clone(), b.clone()]) {
+        Bool(true) => Ok(Ordering::Less),
+        Bool(false) | Nil => {
+            match apply(f, vec![b.clone(), a.clone()]) {
+                Bool(true) => Ok(Ordering::Greater),
+                _          => Ok(Ordering::Equal),
+            }
+        },
+        ref n if number::is_number(n) => Ok(number::compare(n, &Int(0)).unwrap_or(Ordering::Equal)),
+        e @ Error(_) => Err(e),
+        _ => Err(Error("Expected comparator to return a number or a boolean")),
     }
 }
 
-fn lt_or_eq(args: &mut Vec<Node>, env: &mut Env) -> Node {
-    let first  = eval(&mut args.remove(0), env);
-    let second = eval(&mut args.remove(0), env);
+fn sort(args: &mut [Node], env: &mut Env) -> Node {
+    let args = eval_args(args, env);
+    let (comparator, coll) = match args.as_slice() {
+        [coll]             => (None, coll),
+        [comparator, coll] => (Some(comparator), coll),
+        _ => return Error("Expected an optional comparator and a collection as arguments to sort"),
+    };
+    let mut items = match coll.to_vec() {
+        Ok(v)  => v,
+        Err(e) => return e,
+    };
+
+    let mut error = None;
+    items.sort_by(|a, b| {
+        let order = match comparator {
+            Some(f) => comparator_order(f, a, b),
+            None    => a.partial_cmp(b).ok_or(Error("Can't compare values of different types")),
+        };
+        order.unwrap_or_else(|e| {
+            error.get_or_insert(e);
+            Ordering::Equal
+        })
+    });
 
-    match (first, second) {
-        (Int(n1), Int(n2)) => Bool(n1 < n2 || n1 == n2),
-        _ => Error("Expected numbers as arguments to <="),
+    match error {
+        Some(e) => e,
+        None    => if items.is_empty() { List(vec![]) } else { Vector(items).seq() },
     }
 }
 
@@ -172,7 +684,7 @@ fn lt_or_eq(args: &mut Vec<Node>, env: &mut Env) -> Node {
 fn _if(args: &mut Vec<Node>, env: &mut Env) -> Node {
     let cond = eval(&mut args.remove(0), env);
     let mut expr1 = args.remove(0);
-    let mut expr2 = if args.len() > 0 { args.remove(0) } else { Nil };
+    let mut expr2 = if !args.is_empty() { args.remove(0) } else { Nil };
 
     match cond {
         Bool(b) => if b { eval(&mut expr1, env) } else { eval(&mut expr2, env) },
@@ -182,15 +694,14 @@ fn _if(args: &mut Vec<Node>, env: &mut Env) -> Node {
 }
 
 fn or(args: &mut Vec<Node>, env: &mut Env) -> Node {
-    if args.len() == 0 {
+    if args.is_empty() {
         return Nil
     }
     let mut last = args.pop().unwrap();
     for n in args.iter_mut() {
         let evaled = eval(n, env);
         match evaled {
-            Bool(b) => if b { return evaled },
-            Nil => (),
+            Bool(false) | Nil => (),
             _ => return evaled,
         }
     }
@@ -198,14 +709,14 @@ fn or(args: &mut Vec<Node>, env: &mut Env) -> Node {
 }
 
 fn and(args: &mut Vec<Node>, env: &mut Env) -> Node {
-    if args.len() == 0 {
+    if args.is_empty() {
         return Bool(true)
     }
     let mut last = args.pop().unwrap();
     for n in args.iter_mut() {
         let evaled = eval(n, env);
         match evaled {
-            Bool(b) => if !b { return evaled },
+            Bool(false) => return evaled,
             Nil => return Nil,
             _ => (),
         }
@@ -213,33 +724,40 @@ fn and(args: &mut Vec<Node>, env: &mut Env) -> Node {
     eval(&mut last, env)
 }
 
-fn _do(args: &mut Vec<Node>, env: &mut Env) -> Node {
+fn _do(args: &mut [Node], env: &mut Env) -> Node {
     let mut evaled = args.iter_mut()
         .map(|n| eval(n, env))
         .collect::<Vec<Node>>();
 
-    evaled.pop().unwrap()
-}
-
-fn func(args: &mut Vec<Node>) -> Node {
-    let fn_params =
-        match args.remove(0) {
-            Vector(v) => {
-                v.iter()
-                    .map(|n| {
-                        match n {
-                            &Symbol(ref s) => s.to_owned(),
-                            _ => panic!("Expected symbols as function parameters"),
-                        }
-                    })
-                    .collect()
-            },
-            _ => return Error("Expected vector as second argument to fn")
-        };
+    evaled.pop().unwrap_or(Nil)
+}
 
-    let fn_body = Box::new(args.remove(0));
+fn func(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let mut fn_params = Vec::new();
+    match args.remove(0) {
+        Vector(v) => {
+            for n in v {
+                match n {
+                    Symbol(s) => fn_params.push(s),
+                    _ => return Error("Expected symbols as function parameters"),
+                }
+            }
+        },
+        _ => return Error("Expected vector as first argument to fn")
+    }
 
-    Lambda { params: fn_params, body: fn_body }
+    // Several body forms are evaluated in turn, as with `do`.
+    if args.len() == 1 {
+        let fn_body = Arc::new(args.remove(0));
+        return Lambda { name: None, params: fn_params, body: fn_body, env: env.clone() };
+    }
+    closure(fn_params, args, env)
+}
+
+/// Wraps a body in a function of the given parameters, closing over `env`.
+fn closure(params: Vec<String>, body: &mut Vec<Node>, env: &Env) -> Node {
+    body.insert(0, Symbol("do".to_owned()));
+    Lambda { name: None, params, body: Arc::new(List(body.clone())), env: env.clone() }
 }
 
 fn nth(args: &mut Vec<Node>, env: &mut Env) -> Node {
@@ -247,33 +765,35 @@ fn nth(args: &mut Vec<Node>, env: &mut Env) -> Node {
     let i = eval(&mut args.remove(0), env);
 
     match (v, i) {
-        (Vector(v), Int(i)) => {
-            v.get(i as usize).unwrap().clone()
+        (v, Int(i)) if seq::is_seqable(&v) => {
+            if i < 0 {
+                return Error("Index out of bounds");
+            }
+            let found = match v {
+                Vector(_) | List(_) | Node::Str(_) => v.nth(i as usize),
+                _ => seq::nth_of_seq(seq::into_seq(v), i as usize),
+            };
+            match found {
+                Some(n) => n,
+                None    => Error("Index out of bounds"),
+            }
         },
         (_, Int(_)) => {
-            Error("Expected vector as first argument to nth")
+            Error("Expected collection as first argument to nth")
         },
-        (Vector(_), _) => {
+        (ref v, _) if seq::is_seqable(v) => {
             Error("Expected number as second argument to nth")
         },
-        _ => Error("Expected vector and number as arguments to nth")
+        _ => Error("Expected collection and number as arguments to nth")
     }
 }
 
-fn println(args: &mut Vec<Node>, env: &mut Env) -> Node {
-    for mut arg in args {
-        print!("{} ", eval(&mut arg, env));
-    }
-    print!("\n");
-    Nil
-}
-
 fn _let(args: &mut Vec<Node>, env: &mut Env) -> Node {
     let bindings = match args.remove(0) {
         Vector(v) => v,
         _         => return Error("Expected vector as first argument to let"),
     };
-    let mut body = args;
+    let body = args;
     let mut new_env = env.clone();
 
     if bindings.len() % 2 != 0 {
@@ -283,110 +803,479 @@ fn _let(args: &mut Vec<Node>, env: &mut Env) -> Node {
     let idxs = (0..bindings.len()).filter(|x| (x + 1) % 2 != 0);
 
     for i in idxs {
-        let symbol_str = match bindings.get(i).unwrap() {
-            &Symbol(ref s) => s.to_owned(),
-            _              => return Error("Expected odd items in binding vector to be symbols"),
+        let symbol_str = match bindings[i] {
+            Symbol(ref s) => s.to_owned(),
+            _             => return Error("Expected odd items in binding vector to be symbols"),
         };
-        let val_node = bindings.get(i + 1).unwrap();
+        let val_node = &bindings[i + 1];
         let mut _env = new_env.clone();
 
         new_env.insert(symbol_str, eval(&mut val_node.clone(), &mut _env));
     }
 
-    _do(&mut body, &mut new_env)
+    _do(body, &mut new_env)
 }
 
 fn conj(args: &mut Vec<Node>, env: &mut Env) -> Node {
     let v = eval(&mut args.remove(0), env);
     let n = eval(&mut args.remove(0), env);
 
-    match v {
-        Vector(mut v) => {
-            v.push(n);
-            Vector(v)
-        },
-        _ => Error("Expected vector as first argument to conj"),
+    if seq::is_seqable(&v) {
+        v.conj(n)
+    } else {
+        Error("Expected collection as first argument to conj")
     }
 }
 
+/// `for` is lazy: the body becomes a closure mapped over the bound
+/// sequence, with `nil` results left out.
 fn _for(args: &mut Vec<Node>, env: &mut Env) -> Node {
     let binding = match args.remove(0) {
         Vector(v) => v,
         _         => return Error("Expected binding vector as first argument to for"),
     };
 
-    let symbol_str = match binding.get(0).unwrap() {
-        &Symbol(ref s) => s.to_owned(),
-        _              => return Error("Expected first item in binding vector to be a symbol"),
+    let symbol_str = match binding.first() {
+        Some(Symbol(s)) => s.to_owned(),
+        _               => return Error("Expected first item in binding vector to be a symbol"),
     };
 
-    let binding_vec = match eval(&mut binding.get(1).unwrap().clone(), env) {
-        Vector(ref v) => {
-            match eval(&mut Vector(v.clone()), env) {
-                Vector(v) => v,
-                _         => return Error("Expected vector as second item in binding vector"),
-            }
-        },
-        _         => return Error("Expected vector as second item in binding vector"),
+    let coll = match binding.get(1) {
+        Some(n) => eval(&mut n.clone(), env),
+        None    => return Error("Expected binding vector to contain an even number of forms"),
     };
-
-    let body = args;
-    let mut results = Vec::new();
-    let mut new_env = env.clone();
-
-    for n in binding_vec {
-        new_env.insert(symbol_str.clone(), n);
-        let iter_result = _do(&mut body.clone(), &mut new_env);
-        match iter_result {
-            Nil => (),
-            _   => results.push(iter_result),
-        }
+    if !seq::is_seqable(&coll) {
+        return Error("Expected collection as second item in binding vector");
     }
-    Vector(results)
+
+    let body = closure(vec![symbol_str], args, env);
+    seq::lazy(Lazy::Map(body, vec![coll], true))
 }
 
-fn range(args: &mut Vec<Node>, env: &mut Env) -> Node {
-    let from = eval(&mut args.remove(0), env);
-    let to = eval(&mut args.remove(0), env);
+fn range(args: &mut [Node], env: &mut Env) -> Node {
+    let args = eval_args(args, env);
 
-    match (from, to) {
-        (Int(n1), Int(n2)) => {
-            let nodes = (n1..n2).map(|n| Int(n)).collect();
-            Vector(nodes)
-        }
+    let (from, to, step) = match args.as_slice() {
+        []                          => (0, None, 1),
+        [Int(n2)]                   => (0, Some(*n2), 1),
+        [Int(n1), Int(n2)]          => (*n1, Some(*n2), 1),
+        [Int(n1), Int(n2), Int(n3)] => (*n1, Some(*n2), *n3),
         _ => return Error("Expected numbers as arguments to range"),
-    }
+    };
+    seq::lazy(Lazy::Range(from, to, step))
 }
 
 fn count(args: &mut Vec<Node>, env: &mut Env) -> Node {
     let n = eval(&mut args.remove(0), env);
-    match n {
-        Vector(v) => Int(v.len() as i64),
-        _ => Error("Expected vector as argument to count"),
+    if !seq::is_seqable(&n) {
+        return Error("Expected collection as argument to count");
+    }
+    let counted = match n {
+        Node::Cons(..) | Node::ChunkedSeq { .. } | Node::LazySeq(_) => seq::count_seq(seq::into_seq(n)),
+        _ => n.count(),
+    };
+    match counted {
+        Ok(c)  => Int(c as i64),
+        Err(e) => e,
     }
 }
 
 fn _mod(args: &mut Vec<Node>, env: &mut Env) -> Node {
     let dividend = eval(&mut args.remove(0), env);
     let divisor = eval(&mut args.remove(0), env);
+    number::modulo(dividend, divisor)
+}
+
+fn quot(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let dividend = eval(&mut args.remove(0), env);
+    let divisor = eval(&mut args.remove(0), env);
+    number::quot(dividend, divisor)
+}
 
-    match (dividend, divisor) {
-        (Int(n1), Int(n2)) => Int(n1 % n2),
-        _ => Error("Expected numbers as arguments to modulo")
-    }
+fn rem(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let dividend = eval(&mut args.remove(0), env);
+    let divisor = eval(&mut args.remove(0), env);
+    number::rem(dividend, divisor)
 }
 
-fn _reduce(args: &mut Vec<Node>, env: &mut Env) -> Node {
-    let reducer = eval(&mut args.remove(0), env);
-    let mut acc = eval(&mut args.remove(0), env);
-    let v = match eval(&mut args.remove(0), env) {
-        Vector(v) => v,
-        _ => return Error("Expected vector as third argument to reduce"),
+/// `(reduce f init coll)`, or `(reduce f coll)` starting from the first
+/// element, with `(f)` as the result for an empty collection.
+fn _reduce(args: &mut [Node], env: &mut Env) -> Node {
+    let mut args = eval_args(args, env);
+    let coll = args.pop().unwrap();
+    let reducer = args.remove(0);
+    if !seq::is_seqable(&coll) {
+        return Error("Expected collection as last argument to reduce");
+    }
+    let mut s = seq::into_seq(coll);
+    let mut acc = match args.pop() {
+        Some(init) => init,
+        None => match s {
+            Nil => return apply(&reducer, vec![]),
+            Error(_) => return s,
+            _ => {
+                let first = s.first();
+                s = s.next();
+                first
+            },
+        },
     };
 
-    for n in v {
-        acc = eval(&mut List(vec![reducer.clone(), acc, n]), env);
+    while s != Nil {
+        if let Error(_) = s {
+            return s;
+        }
+        acc = apply(&reducer, vec![acc, s.first()]);
+        if let Error(_) = acc {
+            return acc;
+        }
+        s = s.next();
     }
 
     acc
 }
+
+fn lazy_seq(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let thunk = closure(vec![], args, env);
+    seq::lazy(Lazy::Thunk(thunk))
+}
+
+fn cons(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let x = eval(&mut args.remove(0), env);
+    let coll = eval(&mut args.remove(0), env);
+
+    if seq::is_seqable(&coll) {
+        seq::cons(x, coll)
+    } else {
+        Error("Expected sequence as second argument to cons")
+    }
+}
+
+fn first(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    eval(&mut args.remove(0), env).first()
+}
+
+fn rest(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    eval(&mut args.remove(0), env).rest()
+}
+
+fn next(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    eval(&mut args.remove(0), env).next()
+}
+
+fn _seq(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    eval(&mut args.remove(0), env).seq()
+}
+
+fn map(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let f = eval(&mut args.remove(0), env);
+    let colls = eval_args(args, env);
+    seq::lazy(Lazy::Map(f, colls, false))
+}
+
+fn filter(args: &mut Vec<Node>, env: &mut Env, keep: bool) -> Node {
+    let pred = eval(&mut args.remove(0), env);
+    let coll = eval(&mut args.remove(0), env);
+    seq::lazy(Lazy::Filter(pred, coll, keep))
+}
+
+fn concat(args: &mut [Node], env: &mut Env) -> Node {
+    let colls = eval_args(args, env);
+    seq::lazy(Lazy::Concat(colls))
+}
+
+fn repeat(args: &mut [Node], env: &mut Env) -> Node {
+    let args = eval_args(args, env);
+
+    match args.as_slice() {
+        [x]         => seq::lazy(Lazy::Repeat(x.clone(), None)),
+        [Int(n), x] => seq::lazy(Lazy::Repeat(x.clone(), Some(*n))),
+        _ => Error("Expected a count and a value as arguments to repeat"),
+    }
+}
+
+fn doall(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let s = eval(&mut args.remove(0), env);
+    match s.to_vec() {
+        Ok(_)  => s,
+        Err(e) => e,
+    }
+}
+
+fn dorun(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let s = eval(&mut args.remove(0), env);
+    if !seq::is_seqable(&s) {
+        return Error("Expected collection as argument to dorun");
+    }
+    match seq::count_seq(seq::into_seq(s)) {
+        Ok(_)  => Nil,
+        Err(e) => e,
+    }
+}
+
+fn hash_map(args: &mut [Node], env: &mut Env) -> Node {
+    let args = eval_args(args, env);
+    if !args.len().is_multiple_of(2) {
+        return Error("Expected an even number of arguments to hash-map");
+    }
+    seq::map_of(args.chunks(2).map(|kv| (kv[0].clone(), kv[1].clone())).collect())
+}
+
+fn get(args: &mut [Node], env: &mut Env) -> Node {
+    let args = eval_args(args, env);
+    let (coll, key, default) = match args.as_slice() {
+        [coll, key]          => (coll, key, Nil),
+        [coll, key, default] => (coll, key, default.clone()),
+        _ => return Error("Expected a collection, a key and an optional default as arguments to get"),
+    };
+
+    let found = match (coll, key) {
+        (Map(m), k) => m.iter().find(|(mk, _)| mk == k).map(|(_, v)| v.clone()),
+        (Set(v), k) => v.iter().find(|n| *n == k).cloned(),
+        (Vector(v), &Int(i)) if i >= 0 => v.get(i as usize).cloned(),
+        _ => None,
+    };
+    found.unwrap_or(default)
+}
+
+fn assoc(args: &mut [Node], env: &mut Env) -> Node {
+    let mut args = eval_args(args, env);
+    if args.len() % 2 != 1 {
+        return Error("Expected a collection followed by key value pairs as arguments to assoc");
+    }
+    let coll = args.remove(0);
+
+    args.chunks(2).fold(coll, |coll, kv| {
+        match (coll, &kv[0]) {
+            (Map(m), k) => seq::assoc(m, k.clone(), kv[1].clone()),
+            (Nil, k) => seq::assoc(vec![], k.clone(), kv[1].clone()),
+            (Vector(mut v), &Int(i)) if i >= 0 && i as usize <= v.len() => {
+                if i as usize == v.len() {
+                    v.push(kv[1].clone());
+                } else {
+                    v[i as usize] = kv[1].clone();
+                }
+                Vector(v)
+            },
+            (e @ Error(_), _) => e,
+            _ => Error("Expected map or vector as first argument to assoc"),
+        }
+    })
+}
+
+fn contains(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let coll = eval(&mut args.remove(0), env);
+    let key = eval(&mut args.remove(0), env);
+
+    match (coll, key) {
+        (Map(m), k) => Bool(m.iter().any(|(mk, _)| *mk == k)),
+        (Set(v), k) => Bool(v.contains(&k)),
+        (Vector(v), Int(i)) => Bool(i >= 0 && (i as usize) < v.len()),
+        (Vector(_), _) | (Nil, _) => Bool(false),
+        _ => Error("Expected map, set or vector as first argument to contains?"),
+    }
+}
+
+fn distinct(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let coll = eval(&mut args.remove(0), env);
+    seq::lazy(Lazy::Distinct(coll, Arc::new(Mutex::new(HashSet::new()))))
+}
+
+fn is_vector(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    Bool(matches!(eval(&mut args.remove(0), env), Vector(_)))
+}
+
+fn hash(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let n = eval(&mut args.remove(0), env);
+    let mut hasher = DefaultHasher::new();
+    n.hash(&mut hasher);
+    Int(hasher.finish() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Env, eval_all, builtin, builtin_arity, is_special_form};
+
+    /// The printed value of the last form in `src`.
+    fn run(src: &str) -> String {
+        format!("{}", eval_all(src, &mut Env::new()))
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(run("(+)"), "0");
+        assert_eq!(run("(+ 1 2 3)"), "6");
+        assert_eq!(run("(+' 9223372036854775807 1)"), "9223372036854775808N");
+        assert_eq!(run("(- 5)"), "-5");
+        assert_eq!(run("(- 10 1 2)"), "7");
+        assert_eq!(run("(-' 1 2)"), "-1");
+        assert_eq!(run("(* 2 3 4)"), "24");
+        assert_eq!(run("(*' 2 3)"), "6");
+        assert_eq!(run("(/ 3 6)"), "1/2");
+        assert_eq!(run("(/ 2)"), "1/2");
+        assert_eq!(run("(mod -7 3)"), "2");
+        assert_eq!(run("(quot -7 2)"), "-3");
+        assert_eq!(run("(rem -7 3)"), "-1");
+        assert_eq!(run("(+ 1 :a)"), "Error: Can only add numbers");
+    }
+
+    #[test]
+    fn comparisons() {
+        assert_eq!(run("(< 1 2 3)"), "true");
+        assert_eq!(run("(> 3 2 2)"), "false");
+        assert_eq!(run("(<= 1 1 2)"), "true");
+        assert_eq!(run("(>= 1 2)"), "false");
+        assert_eq!(run("(= [1 2] '(1 2))"), "true");
+        assert_eq!(run("(not= 1 1)"), "false");
+        assert_eq!(run("(== 1 1.0 1N)"), "true");
+        assert_eq!(run("(< 9007199254740992.0 9007199254740993)"), "true");
+        assert_eq!(run("(compare \"b\" \"a\")"), "1");
+        assert_eq!(run("(sort [3 1 2])"), "(1 2 3)");
+        assert_eq!(run("(sort > [3 1 2])"), "(3 2 1)");
+    }
+
+    #[test]
+    fn collections() {
+        assert_eq!(run("(list 1 (+ 1 1))"), "(1 2)");
+        assert_eq!(run("(hash-map :a 1)"), "{:a 1}");
+        assert_eq!(run("(hash-set 1 1 2)"), "#{1 2}");
+        assert_eq!(run("(hash-map :a 1 :b 2 :a 3)"), "{:a 3, :b 2}");
+        assert_eq!(run("(let [x 1] #{x 2 1.0})"), "#{1 2}");
+        assert_eq!(run("(count \"abc\")"), "3");
+        assert_eq!(run("(nth [1 2] 1)"), "2");
+        assert_eq!(run("(nth [1 2] 2)"), "Error: Index out of bounds");
+        assert_eq!(run("(conj [1] 2)"), "[1 2]");
+        assert_eq!(run("(conj '(1) 2)"), "(2 1)");
+        assert_eq!(run("(get {:a 1} :a)"), "1");
+        assert_eq!(run("(get {:a 1} :b 0)"), "0");
+        assert_eq!(run("(assoc {} :a 1 :b 2)"), "{:a 1, :b 2}");
+        assert_eq!(run("(contains? #{1} 1)"), "true");
+        assert_eq!(run("(distinct [1 1 2])"), "(1 2)");
+        assert_eq!(run("(= (hash {:a 1}) (hash {:a 1}))"), "true");
+    }
+
+    #[test]
+    fn sequences() {
+        assert_eq!(run("(range 3)"), "(0 1 2)");
+        assert_eq!(run("(range 1 10 4)"), "(1 5 9)");
+        assert_eq!(run("(range 9223372036854775800 9223372036854775807 5)"), "(9223372036854775800 9223372036854775805)");
+        assert_eq!(run("(count (range -9223372036854775807 -9223372036854775808 -1))"), "1");
+        assert_eq!(run("(cons 1 [2])"), "(1 2)");
+        assert_eq!(run("(first [1 2])"), "1");
+        assert_eq!(run("(rest [1])"), "()");
+        assert_eq!(run("(next [1])"), "nil");
+        assert_eq!(run("(seq {})"), "nil");
+        assert_eq!(run("(map + [1 2] [3 4])"), "(4 6)");
+        assert_eq!(run("(filter (fn [x] (> x 1)) [1 2 3])"), "(2 3)");
+        assert_eq!(run("(remove (fn [x] (> x 1)) [1 2 3])"), "(1)");
+        assert_eq!(run("(map (fn [x] (/ 1 x)) [0 1])"), "(Error: Divide by zero)");
+        assert_eq!(run("(doall (map (fn [x] (/ 1 x)) [1 0 2]))"), "Error: Divide by zero");
+        assert_eq!(run("(count (map (fn [x y] (/ x y)) [1 2] [1 0]))"), "Error: Divide by zero");
+        assert_eq!(run("(doall (filter (fn [x] (/ 1 x)) [0 1]))"), "Error: Divide by zero");
+        assert_eq!(run("(first (filter (fn [x] (/ 1 x)) (cons 1 (cons 0 nil))))"), "1");
+        assert_eq!(run("(nth (for [x [1 0]] (/ 1 x)) 1)"), "Error: Divide by zero");
+        assert_eq!(run("(reduce + 0 (range 5))"), "10");
+        assert_eq!(run("(reduce + [1 2 3])"), "6");
+        assert_eq!(run("(reduce + [])"), "0");
+        assert_eq!(run("(reduce - [5])"), "5");
+        assert_eq!(run("(reduce + 1)"), "Error: Expected collection as last argument to reduce");
+        assert_eq!(run("(concat [1] '(2) [])"), "(1 2)");
+        assert_eq!(run("(defn cyc [c] (lazy-seq (concat c (cyc c)))) (nth (cyc [1 2]) 2001)"), "2");
+        assert_eq!(run("(repeat 2 :x)"), "(:x :x)");
+        assert_eq!(run("(doall (map - [1]))"), "(-1)");
+        assert_eq!(run("(dorun (map - [1]))"), "nil");
+    }
+
+    #[test]
+    fn printing() {
+        assert_eq!(run("(pr-str \"a\" \\b)"), "\"\\\"a\\\" \\\\b\"");
+        assert_eq!(run("(print-str \"a\" \\b)"), "\"a b\"");
+        assert_eq!(run("(format \"%03d %s\" 7 :k)"), "\"007 :k\"");
+        assert_eq!(run("(format \"%d\" \"x\")"), "Error: Expected an integer for %d in format");
+        for f in &["print", "println", "pr", "prn", "pprint", "pprint-code"] {
+            assert_eq!(run(&format!("({} [1])", f)), "nil");
+        }
+        assert_eq!(run("(printf \"%s\" 1)"), "nil");
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(run("(str \"a\" 1 nil :b)"), "\"a1:b\"");
+        assert_eq!(run("(subs \"hello\" 1)"), "\"ello\"");
+        assert_eq!(run("(split \"a b\" \" \")"), "[\"a\" \"b\"]");
+        assert_eq!(run("(split-lines \"a\\nb\")"), "[\"a\" \"b\"]");
+        assert_eq!(run("(join \"-\" [1 2])"), "\"1-2\"");
+        assert_eq!(run("(trim \" a \")"), "\"a\"");
+        assert_eq!(run("(upper-case \"a\")"), "\"A\"");
+        assert_eq!(run("(lower-case \"A\")"), "\"a\"");
+        assert_eq!(run("(starts-with? \"abc\" \"ab\")"), "true");
+        assert_eq!(run("(ends-with? \"abc\" \"ab\")"), "false");
+        assert_eq!(run("(includes? \"abc\" \"b\")"), "true");
+        assert_eq!(run("(replace \"aa\" \"a\" \"b\")"), "\"bb\"");
+        assert_eq!(run("(index-of \"abc\" \"c\")"), "2");
+        assert_eq!(run("(reverse \"ab\")"), "\"ba\"");
+    }
+
+    #[test]
+    fn regular_expressions() {
+        assert_eq!(run("(re-pattern \"a|b\")"), "#\"a|b\"");
+        assert_eq!(run("(re-find #\"\\d+\" \"a12\")"), "\"12\"");
+        assert_eq!(run("(re-matches #\"(a)(b)\" \"ab\")"), "[\"ab\" \"a\" \"b\"]");
+        assert_eq!(run("(re-seq #\"\\w\" \"a b\")"), "(\"a\" \"b\")");
+        assert_eq!(run("(replace \"a1\" #\"\\d\" (fn [m] \"#\"))"), "\"a#\"");
+    }
+
+    #[test]
+    fn test_framework() {
+        assert_eq!(run("(deftest t (is (= 1 1))) (use-fixtures :each (fn [f] (f))) (test-var 't)"), "nil");
+        assert_eq!(run("(deftest t (is (= 1 1))) (run-tests)"), "{:test 1, :pass 1, :fail 0, :error 0}");
+    }
+
+    #[test]
+    fn special_forms() {
+        assert_eq!(run("(def x 1) x"), "1");
+        assert_eq!(run("(defn f [a] (* a 2)) (f 2)"), "4");
+        assert_eq!(run("(if false 1 2)"), "2");
+        assert_eq!(run("(or nil 2)"), "2");
+        assert_eq!(run("(and 1 nil)"), "nil");
+        assert_eq!(run("(do 1 2)"), "2");
+        assert_eq!(run("((fn [a b] (+ a b)) 1 2)"), "3");
+        assert_eq!(run("(let [a 1 b (+ a 1)] b)"), "2");
+        assert_eq!(run("(for [x [1 2]] (* x x))"), "(1 4)");
+        assert_eq!(run("(first (lazy-seq (cons 1 nil)))"), "1");
+        assert_eq!(run("(quote (a b))"), "(a b)");
+    }
+
+    #[test]
+    fn locals_and_vars_hide_builtins() {
+        assert_eq!(run("(let [first (fn [x] :local)] (first [1]))"), ":local");
+        assert_eq!(run("((fn [count] (count 1)) -)"), "-1");
+        assert_eq!(run("(defn join [a b] :mine) (join 1 2)"), ":mine");
+        assert_eq!(run("(defn join [a b] :mine) (brojure.string/join \"-\" [1 2])"), "\"1-2\"");
+        assert_eq!(run("(first [1])"), "1");
+    }
+
+    #[test]
+    fn builtins_are_values_checked_for_arity() {
+        assert_eq!(run("(map count [[1] [1 2]])"), "(1 2)");
+        assert_eq!(run("((fn [f] (f [1])) nth)"), "Error: Wrong number of arguments passed to function");
+        assert_eq!(run("(nth [1])"), "Error: Wrong number of arguments passed to function");
+    }
+
+    #[test]
+    fn arities_are_only_given_for_builtins() {
+        for name in &["+", "nth", "reduce", "range", "get", "re-seq", "run-tests"] {
+            assert!(builtin(name).is_some() && builtin_arity(name).is_some(), "{}", name);
+        }
+        assert!(builtin_arity("if").is_none() && is_special_form("if"));
+    }
+
+    #[test]
+    fn long_seqs_are_walked_and_freed_without_the_stack_growing() {
+        assert_eq!(run("(reduce + (range 1000000))"), "499999500000");
+        assert_eq!(run("(count (map - (range 1000000)))"), "1000000");
+        assert_eq!(run("(nth (range) 1000000)"), "1000000");
+        assert_eq!(run("(dorun (range 1000000))"), "nil");
+        assert_eq!(run("(count (doall (filter (fn [x] (= 0 (mod x 2))) (range 1000000))))"), "500000");
+    }
+}
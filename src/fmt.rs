@@ -0,0 +1,422 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use parser::{tokenize, tokenize_with_trivia};
+
+/// Source as written: forms along with the comments, commas and line
+/// breaks between them, so formatting only ever changes whitespace.
+#[derive(Debug)]
+enum Cst {
+    Token(String),
+    Comment(String),
+    Comma,
+    Newline,
+    /// `'` or `@` and the form after it.
+    Prefix(String, Box<Cst>),
+    /// `^` with the metadata and the form it's attached to.
+    Meta(Box<Cst>, Box<Cst>),
+    Coll { open: String, close: String, children: Vec<Cst> },
+}
+
+use self::Cst::{Token, Comment, Comma, Newline, Prefix, Meta, Coll};
+
+impl Cst {
+    fn is_form(&self) -> bool {
+        matches!(*self, Token(_) | Prefix(..) | Meta(..) | Coll { .. })
+    }
+}
+
+fn is_prefix(token: &str) -> bool {
+    token == "'" || token == "@" || token == "^"
+}
+
+/// Reads source into its top level nodes.
+fn read(src: &str) -> Result<Vec<Cst>, &'static str> {
+    let mut stack: Vec<(String, Vec<Cst>)> = vec![(String::new(), Vec::new())];
+
+    for t in tokenize_with_trivia(src) {
+        let mut node = match t.as_ref() {
+            "(" | "[" | "{" | "#{" | "'" | "@" | "^" => {
+                stack.push((t, Vec::new()));
+                continue;
+            },
+            ")" | "]" | "}" => {
+                if stack.len() == 1 {
+                    return Err("Unmatched closing bracket");
+                }
+                let (open, children) = stack.pop().unwrap();
+                match (open.as_ref(), t.as_ref()) {
+                    ("(", ")") | ("[", "]") | ("{", "}") | ("#{", "}") => (),
+                    _ => return Err("Mismatched brackets"),
+                }
+                Coll { open, close: t, children }
+            },
+            "\n" => Newline,
+            "," => Comma,
+            _ if t.starts_with(';') => Comment(t),
+            _ => Token(t),
+        };
+        if node.is_form() {
+            while stack.last().is_some_and(|top| is_prefix(&top.0) && !(top.0 == "^" && top.1.is_empty())) {
+                let (prefix, mut meta) = stack.pop().unwrap();
+                node = match meta.pop() {
+                    Some(meta) => Meta(Box::new(meta), Box::new(node)),
+                    None => Prefix(prefix, Box::new(node)),
+                };
+            }
+        } else if matches!(node, Newline) && stack.last().is_some_and(|top| top.0 == "^") {
+            // Metadata is joined to its form.
+            continue;
+        } else if stack.last().is_some_and(|top| is_prefix(&top.0)) {
+            return Err("Expected a form after a quote, @ or ^");
+        }
+        stack.last_mut().unwrap().1.push(node);
+    }
+    if stack.len() != 1 {
+        return Err("Unclosed bracket");
+    }
+    Ok(stack.pop().unwrap().1)
+}
+
+/// Forms whose body is indented by two under the opening paren rather
+/// than lined up with the first argument.
+fn is_body_form(head: &str) -> bool {
+    matches!(head, "def" | "defn" | "defn-" | "defmacro" | "deftest" | "fn" | "let" | "if" | "if-not"
+        | "if-let" | "when" | "when-not" | "when-let" | "do" | "for" | "doseq" | "dotimes" | "loop"
+        | "binding" | "with-open" | "with-redefs" | "lazy-seq" | "ns" | "testing" | "try" | "catch"
+        | "finally" | "cond" | "case" | "are" | "dosync" | "future" | "thread" | "go" | "with-out-str")
+}
+
+/// Forms whose first argument is a vector of name and value pairs.
+fn is_binding_form(head: &str) -> bool {
+    matches!(head, "let" | "if-let" | "when-let" | "for" | "doseq" | "dotimes" | "loop" | "binding"
+        | "with-open" | "with-redefs")
+}
+
+struct Writer {
+    out: String,
+}
+
+impl Writer {
+    fn column(&self) -> usize {
+        match self.out.rfind('\n') {
+            Some(i) => self.out[i + 1..].chars().count(),
+            None    => self.out.chars().count(),
+        }
+    }
+
+    fn write(&mut self, node: &Cst) {
+        match *node {
+            Token(ref s) | Comment(ref s) => self.out.push_str(s),
+            Comma => self.out.push(','),
+            Newline => self.out.push('\n'),
+            Prefix(ref prefix, ref form) => {
+                self.out.push_str(prefix);
+                self.write(form);
+            },
+            Meta(ref meta, ref form) => {
+                self.out.push('^');
+                self.write(meta);
+                self.out.push(' ');
+                self.write(form);
+            },
+            Coll { ref open, ref close, ref children } => {
+                let col = self.column();
+                self.out.push_str(open);
+                let head = match children.iter().find(|c| c.is_form()) {
+                    Some(Token(s)) if open == "(" => Some(s.as_str()),
+                    _ => None,
+                };
+                let indent = match head {
+                    _ if open != "(" => Indent::Fixed(col + open.len()),
+                    Some(h) if is_body_form(h) => Indent::Fixed(col + 2),
+                    Some(h) if h.starts_with(|c: char| c.is_ascii_digit() || c == '"' || c == '\\') => Indent::Fixed(col + 1),
+                    _ => Indent::FirstArg(col + 1),
+                };
+                let bindings = head.is_some_and(is_binding_form);
+                self.write_children(children, indent, bindings, None);
+                self.out.push_str(close);
+            },
+        }
+    }
+
+    /// Writes the nodes inside a collection, or the top level of a file.
+    /// Line breaks are kept as written, with at most one blank line, and
+    /// everything else on a line is separated by single spaces.
+    fn write_children(&mut self, children: &[Cst], mut indent: Indent, bindings: bool, pad: Option<usize>) {
+        let mut forms = 0;
+        let mut newlines = 0;
+        let mut written = false;
+        let mut after_comment = false;
+        let mut head_line = true;
+        let mut name_width = 0;
+
+        for child in children {
+            match *child {
+                Newline => {
+                    if written {
+                        newlines += 1;
+                        head_line = false;
+                    }
+                    continue;
+                },
+                Comma if written && newlines == 0 => {
+                    self.out.push(',');
+                    continue;
+                },
+                Comma => continue,
+                _ => (),
+            }
+            if newlines > 0 || after_comment {
+                let blank = if newlines > 1 { "\n" } else { "" };
+                self.out.push_str(&format!("\n{}{}", blank, " ".repeat(indent.column())));
+            } else if written {
+                let spaces = match pad {
+                    Some(width) if forms % 2 == 1 => width - name_width + 1,
+                    _ => 1,
+                };
+                self.out.push_str(&" ".repeat(spaces));
+            }
+            if forms == 1 && head_line {
+                if let Indent::FirstArg(_) = indent {
+                    indent = Indent::Fixed(self.column());
+                }
+            }
+            match *child {
+                Coll { ref open, ref close, ref children } if bindings && forms == 1 && open == "[" => {
+                    let col = self.column();
+                    self.out.push_str(open);
+                    self.write_children(children, Indent::Fixed(col + 1), false, binding_width(children));
+                    self.out.push_str(close);
+                },
+                _ => {
+                    let start = self.out.len();
+                    self.write(child);
+                    name_width = self.out[start..].chars().count();
+                },
+            }
+            if child.is_form() {
+                forms += 1;
+            }
+            after_comment = matches!(*child, Comment(_));
+            written = true;
+            newlines = 0;
+        }
+        if after_comment {
+            self.out.push_str(&format!("\n{}", " ".repeat(indent.column())));
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Indent {
+    Fixed(usize),
+    /// Lines up with the first argument if it shares a line with the head,
+    /// otherwise the given column.
+    FirstArg(usize),
+}
+
+impl Indent {
+    fn column(&self) -> usize {
+        match *self {
+            Indent::Fixed(c) | Indent::FirstArg(c) => c,
+        }
+    }
+}
+
+/// The width to pad binding names to, when every pair sits on a line of
+/// its own and the names are simple tokens.
+fn binding_width(children: &[Cst]) -> Option<usize> {
+    let mut width = 0;
+    let mut forms = 0;
+    let mut line_breaks = 0;
+    let mut broken = false;
+    for child in children {
+        match *child {
+            Newline if forms % 2 == 1 => return None,
+            Newline => broken = forms > 0,
+            Token(ref s) if forms % 2 == 0 => {
+                if forms > 0 && !broken {
+                    return None;
+                }
+                if broken {
+                    line_breaks += 1;
+                }
+                width = width.max(s.chars().count());
+                broken = false;
+                forms += 1;
+            },
+            Comment(_) => return None,
+            ref c if c.is_form() => {
+                if forms % 2 == 0 {
+                    return None;
+                }
+                forms += 1;
+            },
+            _ => (),
+        }
+    }
+    if forms % 2 == 0 && line_breaks > 0 { Some(width) } else { None }
+}
+
+/// Formats source, keeping comments and the line breaks between forms.
+pub fn format(src: &str) -> Result<String, &'static str> {
+    let nodes = read(src)?;
+    let mut w = Writer { out: String::new() };
+    w.write_children(&nodes, Indent::Fixed(0), false, None);
+    let mut out = w.out.trim_end().to_owned();
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    if tokenize(&out) != tokenize(src) {
+        return Err("Formatting would change the code");
+    }
+    Ok(out)
+}
+
+/// `path` itself, or the `.clj` files under it if it is a directory.
+pub fn clj_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if path.is_dir() {
+        let mut entries: Vec<PathBuf> = fs::read_dir(path)?.filter_map(|e| e.ok().map(|e| e.path())).collect();
+        entries.sort();
+        for entry in entries {
+            if entry.is_dir() || entry.extension().is_some_and(|e| e == "clj") {
+                clj_files(&entry, files)?;
+            }
+        }
+    } else {
+        files.push(path.to_owned());
+    }
+    Ok(())
+}
+
+/// `brojure fmt [--check] [paths...]` formats files in place, or standard
+/// input to standard output when no paths are given. With `--check`
+/// nothing is written and the exit code says whether everything was
+/// already formatted.
+pub fn run(args: &[String]) -> i32 {
+    let check = args.iter().any(|a| a == "--check");
+    let paths: Vec<&String> = args.iter().filter(|a| *a != "--check").collect();
+
+    if paths.is_empty() {
+        let mut src = String::new();
+        if io::stdin().read_to_string(&mut src).is_err() {
+            eprintln!("Couldn't read standard input");
+            return 2;
+        }
+        return match format(&src) {
+            Ok(out) if check => if out == src { 0 } else { 1 },
+            Ok(out) => {
+                print!("{}", out);
+                0
+            },
+            Err(e) => {
+                eprintln!("<stdin>: {}", e);
+                2
+            },
+        };
+    }
+
+    let mut files = Vec::new();
+    for path in paths {
+        if let Err(e) = clj_files(Path::new(path), &mut files) {
+            eprintln!("{}: {}", path, e);
+            return 2;
+        }
+    }
+    let mut status = 0;
+    for file in files {
+        let src = match fs::read_to_string(&file) {
+            Ok(src) => src,
+            Err(e) => {
+                eprintln!("{}: {}", file.display(), e);
+                status = 2;
+                continue;
+            },
+        };
+        match format(&src) {
+            Ok(ref out) if *out == src => (),
+            Ok(_) if check => {
+                println!("{} is not formatted", file.display());
+                status = status.max(1);
+            },
+            Ok(out) => {
+                if let Err(e) = fs::write(&file, out) {
+                    eprintln!("{}: {}", file.display(), e);
+                    status = 2;
+                }
+            },
+            Err(e) => {
+                eprintln!("{}: {}", file.display(), e);
+                status = 2;
+            },
+        }
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format;
+
+    fn assert_idempotent(src: &str) -> String {
+        let once = format(src).unwrap();
+        assert_eq!(format(&once).unwrap(), once);
+        once
+    }
+
+    #[test]
+    fn indents_bodies_by_two() {
+        let src = "(defn f [x]\n(if x\n   1\n      2))\n";
+        assert_eq!(assert_idempotent(src), "(defn f [x]\n  (if x\n    1\n    2))\n");
+    }
+
+    #[test]
+    fn lines_up_arguments_with_the_first() {
+        let src = "(+ 1\n2\n   3)\n(foo\n  bar)\n";
+        assert_eq!(assert_idempotent(src), "(+ 1\n   2\n   3)\n(foo\n bar)\n");
+    }
+
+    #[test]
+    fn aligns_binding_vectors() {
+        let src = "(let [a 1\n x-long 2]\n  (+ a x-long))\n";
+        assert_eq!(assert_idempotent(src), "(let [a      1\n      x-long 2]\n  (+ a x-long))\n");
+    }
+
+    #[test]
+    fn keeps_comments_and_commas() {
+        let src = ";; header\n\n\n(def m {:a 1,   :b 2}) ; trailing   \n(do\n  ;; inside\n  1 ; last\n)\n";
+        assert_eq!(assert_idempotent(src), ";; header\n\n(def m {:a 1, :b 2}) ; trailing\n(do\n  ;; inside\n  1 ; last\n  )\n");
+    }
+
+    #[test]
+    fn tidies_whitespace_inside_brackets() {
+        let src = "(  foo   [ 1  2 ]\n\n   )   ";
+        assert_eq!(assert_idempotent(src), "(foo [1 2])\n");
+    }
+
+    #[test]
+    fn keeps_strings_and_quotes_as_written() {
+        let src = "(println \"a  b\\n\"   '(1   2) #\"x  y\" \\space @a)\n";
+        assert_eq!(assert_idempotent(src), "(println \"a  b\\n\" '(1 2) #\"x  y\" \\space @a)\n");
+    }
+
+    #[test]
+    fn keeps_metadata_with_its_form() {
+        let src = "(def  ^:dynamic   *x* 1)\n(def ^{:dynamic true}\n   y 2)\n";
+        assert_eq!(assert_idempotent(src), "(def ^:dynamic *x* 1)\n(def ^{:dynamic true} y 2)\n");
+        assert!(format("(def ^:dynamic ; comment\n x 1)").is_err());
+    }
+
+    #[test]
+    fn rejects_unbalanced_brackets() {
+        assert!(format("(foo [1 2)").is_err());
+        assert!(format("(foo").is_err());
+        assert!(format("foo)").is_err());
+    }
+
+    #[test]
+    fn lib_is_idempotent() {
+        assert_idempotent(include_str!("../lib/lib.clj"));
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, LazyLock, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use node::Node;
+use node::Node::{Int, Symbol, List, Future, Nil, Error};
+use eval::{Env, eval, eval_args, apply, check_waiting};
+use limits;
+use ns;
+use seq::Seqable;
+
+type Job = Box<dyn FnOnce() -> Node + Send>;
+
+enum State {
+    Queued(Job),
+    Running,
+    /// A promise nothing has been delivered to yet.
+    Waiting,
+    Done(Node),
+}
+
+/// The value of a future, worked out on the thread pool, or of a promise,
+/// handed over with `deliver`.
+pub struct Task {
+    state: Mutex<State>,
+    done: Condvar,
+    pub promise: bool,
+}
+
+impl fmt::Debug for Task {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", if self.promise { "Promise" } else { "Future" })
+    }
+}
+
+/// How long a wait goes between looking for an interruption.
+const POLL: Duration = Duration::from_millis(50);
+
+impl Task {
+    fn new(state: State, promise: bool) -> Arc<Task> {
+        Arc::new(Task { state: Mutex::new(state), done: Condvar::new(), promise })
+    }
+
+    /// Runs the job unless some thread already has. A job that panics gives
+    /// an error, so nothing waits for it forever.
+    fn run(&self) {
+        let job = {
+            let mut state = self.state.lock().unwrap();
+            match mem::replace(&mut *state, State::Running) {
+                State::Queued(job) => job,
+                other => {
+                    *state = other;
+                    return;
+                },
+            }
+        };
+        let value = panic::catch_unwind(AssertUnwindSafe(job))
+            .unwrap_or(Error("Panicked while running on another thread"));
+        *self.state.lock().unwrap() = State::Done(value);
+        self.done.notify_all();
+    }
+
+    /// The value once there is one, or `None` if `timeout` passes first.
+    /// A job no worker has started yet is run on this thread instead, so
+    /// jobs waiting on other jobs can't hold up every worker.
+    pub fn wait(&self, timeout: Option<Duration>) -> Option<Node> {
+        self.run();
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let State::Done(ref value) = *state {
+                return Some(value.clone());
+            }
+            if let Err(e) = check_waiting() {
+                return Some(e);
+            }
+            let wait = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(left) => left.min(POLL),
+                    None => return None,
+                },
+                None => POLL,
+            };
+            state = self.done.wait_timeout(state, wait).unwrap().0;
+        }
+    }
+
+    /// The value, if there is one yet.
+    pub fn value(&self) -> Option<Node> {
+        match *self.state.lock().unwrap() {
+            State::Done(ref value) => Some(value.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Worker threads taking queued jobs in turn. A job queued while every
+/// worker is busy starts another worker, up to `max_workers`. After that it
+/// waits for a worker, or is run by the first thread to wait for it.
+struct Pool {
+    jobs: Mutex<Jobs>,
+    ready: Condvar,
+}
+
+struct Jobs {
+    queue: VecDeque<Arc<Task>>,
+    idle: usize,
+    /// Idle workers told to wake up that haven't yet.
+    wakeups: usize,
+    workers: usize,
+}
+
+static POOL: LazyLock<Arc<Pool>> = LazyLock::new(|| Arc::new(Pool {
+    jobs: Mutex::new(Jobs { queue: VecDeque::new(), idle: 0, wakeups: 0, workers: 0 }),
+    ready: Condvar::new(),
+}));
+
+/// Enough workers that go blocks waiting on each other rarely use them all
+/// up, however few processors there are.
+fn max_workers() -> usize {
+    (thread::available_parallelism().map_or(1, |n| n.get()) * 4).max(16)
+}
+
+/// Workers get as much stack as the main thread, since evaluation recurses
+/// just as deeply on them.
+pub const STACK_SIZE: usize = 8 * 1024 * 1024;
+
+impl Pool {
+    fn submit(pool: &Arc<Pool>, task: Arc<Task>) {
+        let mut jobs = pool.jobs.lock().unwrap();
+        jobs.queue.push_back(task);
+        if jobs.idle > 0 {
+            jobs.idle -= 1;
+            jobs.wakeups += 1;
+            pool.ready.notify_one();
+            return;
+        }
+        if jobs.workers == max_workers() {
+            return;
+        }
+        jobs.workers += 1;
+        drop(jobs);
+        let worker = pool.clone();
+        let spawned = thread::Builder::new()
+            .name("brojure-worker".to_owned())
+            .stack_size(STACK_SIZE)
+            .spawn(move || worker.work());
+        // If no thread can be started the job is run by whoever waits for it.
+        if spawned.is_err() {
+            pool.jobs.lock().unwrap().workers -= 1;
+        }
+    }
+
+    fn work(&self) {
+        limits::worker_thread();
+        let mut jobs = self.jobs.lock().unwrap();
+        loop {
+            if let Some(task) = jobs.queue.pop_front() {
+                drop(jobs);
+                task.run();
+                jobs = self.jobs.lock().unwrap();
+                continue;
+            }
+            jobs.idle += 1;
+            while jobs.wakeups == 0 {
+                jobs = self.ready.wait(jobs).unwrap();
+            }
+            jobs.wakeups -= 1;
+        }
+    }
+}
+
+/// Queues `job` on the thread pool, to run in a copy of `env` with this
+/// thread's bindings.
+pub fn spawn(env: &Env, job: impl FnOnce(&mut Env) -> Node + Send + 'static) -> Arc<Task> {
+    let mut env = env.clone();
+    let bindings = ns::bindings();
+    let task = Task::new(State::Queued(Box::new(move || bindings.with(|| {
+        env.enter();
+        job(&mut env)
+    }))), false);
+    Pool::submit(&POOL, task.clone());
+    task
+}
+
+/// `(future body...)` evaluates the body on another thread. Dereferencing
+/// the future waits for the value.
+pub fn future(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let mut forms = vec![Symbol("do".to_owned())];
+    forms.append(args);
+    let mut body = List(forms);
+    Future(spawn(env, move |env| eval(&mut body, env)))
+}
+
+/// `(promise)` is a value to `deliver` later, which dereferencing waits for.
+pub fn promise(_args: &mut [Node], _env: &mut Env) -> Node {
+    Future(Task::new(State::Waiting, true))
+}
+
+/// `(deliver p x)` gives a promise its value, returning the promise, or
+/// `nil` if it already had one.
+pub fn deliver(args: &mut [Node], env: &mut Env) -> Node {
+    let mut args = eval_args(args, env);
+    let value = args.pop().unwrap();
+    let task = match args.pop() {
+        Some(Future(ref task)) if task.promise => task.clone(),
+        _ => return Error("Expected a promise as first argument to deliver"),
+    };
+    {
+        let mut state = task.state.lock().unwrap();
+        if !matches!(*state, State::Waiting) {
+            return Nil;
+        }
+        *state = State::Done(value);
+    }
+    task.done.notify_all();
+    Future(task)
+}
+
+/// `(deref f ms timeout-value)` waits at most `ms` milliseconds for a
+/// future or promise.
+pub fn deref_within(task: &Task, ms: &Node, timeout_value: Node) -> Node {
+    match *ms {
+        Int(ms) if ms >= 0 => task.wait(Some(Duration::from_millis(ms as u64))).unwrap_or(timeout_value),
+        _ => Error("Expected a number of milliseconds to deref within"),
+    }
+}
+
+/// Makes each call on the thread pool, a couple more at a time than there
+/// are processors, and gives the results in order, or the first error.
+fn call_all<I: Iterator<Item = (Node, Vec<Node>)>>(env: &Env, mut calls: I) -> Node {
+    let width = thread::available_parallelism().map_or(1, |n| n.get()) + 2;
+    let mut running = VecDeque::new();
+    let mut results = Vec::new();
+    loop {
+        while running.len() < width {
+            match calls.next() {
+                Some((f, args)) => running.push_back(spawn(env, move |_| apply(&f, args))),
+                None => break,
+            }
+        }
+        let task = match running.pop_front() {
+            Some(task) => task,
+            None => return List(results),
+        };
+        let value = task.wait(None).unwrap();
+        // An interruption or exceeded limit stops the lot.
+        if let Err(e) = check_waiting() {
+            return e;
+        }
+        if let Error(_) = value {
+            return value;
+        }
+        results.push(value);
+    }
+}
+
+/// `(pmap f coll & colls)` is `map` with the calls to `f` made on other
+/// threads, which pays off when `f` does a lot of work. Unlike `map` it
+/// isn't lazy.
+pub fn pmap(args: &mut [Node], env: &mut Env) -> Node {
+    let mut args = eval_args(args, env).into_iter();
+    let f = args.next().unwrap();
+    let mut colls = Vec::new();
+    for coll in args {
+        match coll.to_vec() {
+            Ok(items) => colls.push(items),
+            Err(e) => return e,
+        }
+    }
+    let n = colls.iter().map(Vec::len).min().unwrap_or(0);
+    call_all(env, (0..n).map(|i| (f.clone(), colls.iter().map(|c| c[i].clone()).collect())))
+}
+
+/// `(pcalls f g ...)` calls each function on another thread, giving their
+/// results in order.
+pub fn pcalls(args: &mut [Node], env: &mut Env) -> Node {
+    let fs = eval_args(args, env);
+    call_all(env, fs.into_iter().map(|f| (f, Vec::new())))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+    use node::Node::{Nil, Error};
+    use eval::{Env, eval_all};
+    use limits::EvalLimits;
+    use ns;
+    use super::{POOL, State, Task, max_workers, spawn};
+
+    fn run(env: &mut Env, src: &str) -> String {
+        format!("{}", eval_all(src, env))
+    }
+
+    fn lib() -> Env {
+        let mut env = Env::new();
+        ns::load(include_str!("../lib/lib.clj"), &mut env);
+        env
+    }
+
+    #[test]
+    fn futures_and_promises() {
+        let mut env = lib();
+        assert_eq!(run(&mut env, "(def f (future (sleep 20) (+ 1 2))) [@f @f (deref f 0 :late) f]"),
+                   "[3 3 3 #<future 3>]");
+        assert_eq!(run(&mut env, "@(future @(future (* 6 7)))"), "42");
+        assert_eq!(run(&mut env, "(def p (promise)) [(deref p 10 :late) p]"), "[:late #<promise pending>]");
+        assert_eq!(run(&mut env, "(future (sleep 10) (deliver p 5)) [@p (deliver p 6) @p]"), "[5 nil 5]");
+        assert_eq!(run(&mut env, "@(future (/ 1 0))"), "Error: Divide by zero");
+        assert_eq!(run(&mut env, "(deliver (future 1) 2)"), "Error: Expected a promise as first argument to deliver");
+        assert_eq!(run(&mut env, "(deref (atom 1) 10 :late)"), "Error: Expected a future or promise to deref with a timeout");
+    }
+
+    #[test]
+    fn workers_are_capped() {
+        let env = lib();
+        let tasks: Vec<_> = (0..max_workers() * 2)
+            .map(|_| spawn(&env, |_| { thread::sleep(Duration::from_millis(20)); Nil }))
+            .collect();
+        assert!(POOL.jobs.lock().unwrap().workers <= max_workers());
+        for task in tasks {
+            assert_eq!(task.wait(None), Some(Nil));
+        }
+    }
+
+    #[test]
+    fn panicking_jobs_give_errors() {
+        let task = Task::new(State::Queued(Box::new(|| panic!("job"))), false);
+        assert_eq!(task.wait(None), Some(Error("Panicked while running on another thread")));
+    }
+
+    #[test]
+    fn pmap_is_map_in_parallel() {
+        let mut env = lib();
+        assert_eq!(run(&mut env, "(= (pmap + (range 50) (range 10 40)) (map + (range 50) (range 10 40)))"), "true");
+        assert_eq!(run(&mut env, "(pmap inc [])"), "()");
+        assert_eq!(run(&mut env, "(pcalls (fn [] 1) (fn [] (do (sleep 10) 2)) (fn [] 3))"), "(1 2 3)");
+        assert_eq!(run(&mut env, "(pmap (fn [x] (/ 1 x)) [1 0 2])"), "Error: Divide by zero");
+        assert_eq!(run(&mut env, "(pcalls (fn [] 1) (fn [] (nth [] 0)))"), "Error: Index out of bounds");
+    }
+
+    #[test]
+    fn atoms_are_changed_atomically() {
+        let mut env = lib();
+        assert_eq!(run(&mut env, "(def a (atom 0))
+                                  (def fs (doall (map (fn [_] (future (doall (map (fn [_] (swap! a inc)) (range 200)))))
+                                                      (range 8))))
+                                  (doall (map deref fs))
+                                  @a"), "1600");
+    }
+
+    #[test]
+    fn waits_are_limited() {
+        let mut env = lib();
+        env.set_limits(EvalLimits { timeout: Some(Duration::from_millis(100)), ..EvalLimits::default() });
+        assert_eq!(run(&mut env, "@(promise)"), "Error: Evaluation timed out");
+        assert_eq!(run(&mut env, "(pmap (fn [_] (sum (range))) [1 2])"), "Error: Evaluation timed out");
+        assert_eq!(run(&mut env, "@(future 1)"), "1");
+    }
+
+    #[test]
+    fn pmap_makes_its_calls_at_the_same_time() {
+        let mut env = lib();
+        // Each call waits for the others to start, so this only finishes in
+        // time with a thread for each of them.
+        assert_eq!(run(&mut env, "(def ps [(promise) (promise) (promise)])
+                                  (pmap (fn [i]
+                                          (deliver (nth ps i) i)
+                                          (doall (map (fn [p] (deref p 5000 :alone)) ps)))
+                                        [0 1 2])"),
+                   "((0 1 2) (0 1 2) (0 1 2))");
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, Write};
+use std::process::{self, Command, Stdio};
+use std::sync::LazyLock;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use node::Node;
+use node::Node::{Int, Str, Keyword, Map, Nil, Error};
+use eval::{Env, eval_args};
+use seq;
+use string::to_str;
+
+/// What code run by the interpreter may do outside of it. Everything is
+/// allowed unless a host embedding the interpreter says otherwise.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Capabilities {
+    /// Running programs, reading environment variables, sleeping and
+    /// exiting.
+    pub host: bool,
+    /// Files, loading code from the load path, and the standard streams.
+    pub io: bool,
+}
+
+impl Capabilities {
+    /// Nothing outside the interpreter, for code that can't be trusted.
+    pub fn sandbox() -> Capabilities {
+        Capabilities { host: false, io: false }
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Capabilities {
+        Capabilities { host: true, io: true }
+    }
+}
+
+/// Evaluates the arguments if host access is allowed.
+fn host_args(args: &mut [Node], env: &mut Env) -> Result<Vec<Node>, Node> {
+    if !env.capabilities().host {
+        return Err(Error("Host access is disabled"));
+    }
+    Ok(eval_args(args, env))
+}
+
+/// `(sh "cmd" "arg"... :in s :env {"K" "v"} :dir path)` runs a program and
+/// waits for it, giving `{:exit code :out s :err s}`. `:env` replaces the
+/// whole environment, as with `clojure.java.shell/sh`.
+pub fn sh(args: &mut [Node], env: &mut Env) -> Node {
+    let args = match host_args(args, env) {
+        Ok(args) => args,
+        Err(e) => return e,
+    };
+    let split = args.iter().position(|a| matches!(*a, Keyword(_))).unwrap_or(args.len());
+    let (command, options) = args.split_at(split);
+    let mut words = Vec::new();
+    for word in command {
+        match *word {
+            Str(ref s) => words.push(s.clone()),
+            _ => return Error("Expected strings for the command to sh"),
+        }
+    }
+    if words.is_empty() {
+        return Error("Expected a command as first argument to sh");
+    }
+    if !options.len().is_multiple_of(2) {
+        return Error("Expected options in pairs");
+    }
+    let mut cmd = Command::new(&words[0]);
+    cmd.args(&words[1..]).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut input = String::new();
+    for pair in options.chunks(2) {
+        match (&pair[0], &pair[1]) {
+            (Keyword(k), v) if k == "in" => input = to_str(v),
+            (Keyword(k), Str(dir)) if k == "dir" => {
+                cmd.current_dir(dir);
+            },
+            (Keyword(k), Map(vars)) if k == "env" => {
+                let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (to_str(k), to_str(v))).collect();
+                cmd.env_clear().envs(vars);
+            },
+            _ => return Error("Unsupported option, expected :in, :env or :dir"),
+        }
+    }
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Error("Program not found"),
+        Err(_) => return Error("Couldn't start program"),
+    };
+    // Written from another thread so a program that fills its output before
+    // reading all of its input can't block us both.
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = match child.wait_with_output() {
+        Ok(output) => output,
+        Err(_) => return Error("Couldn't run program"),
+    };
+    let _ = writer.join();
+    seq::map_of(vec![
+        (Keyword("exit".to_owned()), output.status.code().map_or(Nil, |c| Int(c as i64))),
+        (Keyword("out".to_owned()), Str(String::from_utf8_lossy(&output.stdout).into_owned())),
+        (Keyword("err".to_owned()), Str(String::from_utf8_lossy(&output.stderr).into_owned())),
+    ])
+}
+
+/// `(getenv "NAME")`, or `nil` if it isn't set. `(getenv)` is a map of the
+/// whole environment.
+pub fn getenv(args: &mut [Node], env: &mut Env) -> Node {
+    match host_args(args, env).as_deref() {
+        Ok([]) => seq::map_of(env::vars().map(|(k, v)| (Str(k), Str(v))).collect()),
+        Ok([Str(name)]) => env::var(name).map_or(Nil, Str),
+        Ok(_) => Error("Expected a string as argument to getenv"),
+        Err(e) => e.clone(),
+    }
+}
+
+/// `(exit)` or `(exit code)` ends the process straight away.
+pub fn exit(args: &mut [Node], env: &mut Env) -> Node {
+    let code = match host_args(args, env).as_deref() {
+        Ok([]) => 0,
+        Ok([Int(code)]) => *code as i32,
+        Ok(_) => return Error("Expected an integer as argument to exit"),
+        Err(e) => return e.clone(),
+    };
+    let _ = io::stdout().flush();
+    process::exit(code)
+}
+
+/// `(current-time-millis)` since the Unix epoch.
+pub fn current_time_millis(args: &mut [Node], env: &mut Env) -> Node {
+    if let Err(e) = host_args(args, env) {
+        return e;
+    }
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => Int(d.as_millis() as i64),
+        Err(_) => Error("System clock is before the Unix epoch"),
+    }
+}
+
+static START: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// `(nano-time)` is only good for measuring how long something takes, as
+/// with `System/nanoTime`.
+pub fn nano_time(args: &mut [Node], env: &mut Env) -> Node {
+    if let Err(e) = host_args(args, env) {
+        return e;
+    }
+    Int(START.elapsed().as_nanos() as i64)
+}
+
+/// `(sleep ms)`.
+pub fn sleep(args: &mut [Node], env: &mut Env) -> Node {
+    match host_args(args, env).as_deref() {
+        Ok([Int(ms)]) if *ms >= 0 => {
+            thread::sleep(Duration::from_millis(*ms as u64));
+            Nil
+        },
+        Ok(_) => Error("Expected a non-negative number of milliseconds as argument to sleep"),
+        Err(e) => e.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eval::{Env, eval_all};
+    use ns;
+    use super::Capabilities;
+
+    fn run(env: &mut Env, src: &str) -> String {
+        format!("{}", eval_all(src, env))
+    }
+
+    fn lib() -> Env {
+        let mut env = Env::new();
+        ns::load(include_str!("../lib/lib.clj"), &mut env);
+        env
+    }
+
+    #[test]
+    fn runs_programs() {
+        let mut env = lib();
+        assert_eq!(run(&mut env, "(sh \"cat\" :in \"hi\")"), "{:exit 0, :out \"hi\", :err \"\"}");
+        assert_eq!(run(&mut env, "(sh \"sh\" \"-c\" \"echo $X; pwd >&2; exit 3\" :env {\"X\" 1} :dir \"/\")"),
+                   "{:exit 3, :out \"1\\n\", :err \"/\\n\"}");
+        assert_eq!(run(&mut env, "(sh \"brojure-no-such-program\")"), "Error: Program not found");
+        assert_eq!(run(&mut env, "(sh :in \"x\")"), "Error: Expected a command as first argument to sh");
+    }
+
+    #[test]
+    fn reads_the_environment_and_clock() {
+        let mut env = lib();
+        assert_eq!(run(&mut env, "[(getenv \"BROJURE_SURELY_UNSET\") (= (getenv \"PATH\") (get (getenv) \"PATH\"))]"),
+                   "[nil true]");
+        assert_eq!(run(&mut env, "(def t (nano-time)) (sleep 5) (>= (- (nano-time) t) 5000000)"), "true");
+        assert_eq!(run(&mut env, "(> (current-time-millis) 1500000000000)"), "true");
+    }
+
+    #[test]
+    fn can_be_disabled() {
+        let mut env = lib();
+        env.set_capabilities(Capabilities { host: false, ..Capabilities::default() });
+        assert_eq!(run(&mut env, "(sh \"true\")"), "Error: Host access is disabled");
+        assert_eq!(run(&mut env, "(exit 1)"), "Error: Host access is disabled");
+        assert_eq!(run(&mut env, "(first (map getenv [\"PATH\"]))"), "Error: Host access is disabled");
+    }
+}
@@ -0,0 +1,548 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, LazyLock, Mutex};
+use node::Node;
+use node::Node::{Str, Bool, Symbol, Keyword, Vector, Stream, Nil, Error};
+use eval::{Env, eval, eval_args};
+use seq::{self, Lazy};
+use string::to_str;
+use ns;
+
+/// An open file, read a line at a time or written to, until it is closed.
+pub enum Handle {
+    Reader(Box<dyn BufRead + Send>),
+    Writer(Box<dyn Write + Send>),
+    Stdin,
+    Stdout,
+    Stderr,
+    /// Output kept in memory, for `with-out-str`.
+    Buffer(String),
+    Closed,
+}
+
+impl fmt::Debug for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Handle::Reader(_) => write!(f, "Reader"),
+            Handle::Writer(_) => write!(f, "Writer"),
+            Handle::Stdin     => write!(f, "Stdin"),
+            Handle::Stdout    => write!(f, "Stdout"),
+            Handle::Stderr    => write!(f, "Stderr"),
+            Handle::Buffer(_) => write!(f, "Buffer"),
+            Handle::Closed    => write!(f, "Closed"),
+        }
+    }
+}
+
+static STDIN: LazyLock<Arc<Mutex<Handle>>> = LazyLock::new(|| Arc::new(Mutex::new(Handle::Stdin)));
+static STDOUT: LazyLock<Arc<Mutex<Handle>>> = LazyLock::new(|| Arc::new(Mutex::new(Handle::Stdout)));
+static STDERR: LazyLock<Arc<Mutex<Handle>>> = LazyLock::new(|| Arc::new(Mutex::new(Handle::Stderr)));
+
+/// `*in*`, `*out*` and `*err*` as they are until `binding` changes them.
+pub fn standard_stream(name: &str) -> Option<Node> {
+    let handle = match name {
+        "*in*"  => &STDIN,
+        "*out*" => &STDOUT,
+        "*err*" => &STDERR,
+        _ => return None,
+    };
+    Some(Stream(Arc::clone(handle)))
+}
+
+fn stream(h: Handle) -> Node {
+    Stream(Arc::new(Mutex::new(h)))
+}
+
+/// The error value for a failed operation, saying why where the reason is
+/// a common one.
+fn error(e: &io::Error, otherwise: &'static str) -> Node {
+    match e.kind() {
+        io::ErrorKind::NotFound         => Error("File not found"),
+        io::ErrorKind::PermissionDenied => Error("Permission denied"),
+        io::ErrorKind::AlreadyExists    => Error("File already exists"),
+        _ => Error(otherwise),
+    }
+}
+
+/// Fails unless the interpreter may use files and the standard streams.
+pub fn allowed(env: &Env) -> Result<(), Node> {
+    if env.capabilities().io { Ok(()) } else { Err(Error("I/O is disabled")) }
+}
+
+/// Fails unless the stream is kept in memory or I/O is allowed.
+fn usable(h: &Mutex<Handle>, env: &Env) -> Result<(), Node> {
+    match *h.lock().unwrap() {
+        Handle::Buffer(_) => Ok(()),
+        _ => allowed(env),
+    }
+}
+
+fn path_arg(args: &[Node], name: &'static str) -> Result<String, Node> {
+    match args.first() {
+        Some(Str(s)) => Ok(s.clone()),
+        _ => Err(Error(name)),
+    }
+}
+
+/// Whether the options after the first `skip` arguments include `:append
+/// true`.
+fn append(args: &[Node], skip: usize) -> Result<bool, Node> {
+    let options = &args[skip.min(args.len())..];
+    if !options.len().is_multiple_of(2) {
+        return Err(Error("Expected options in pairs"));
+    }
+    let mut append = false;
+    for pair in options.chunks(2) {
+        match pair[0] {
+            Keyword(ref k) if k == "append" => append = !matches!(pair[1], Nil | Bool(false)),
+            _ => return Err(Error("Unsupported option, expected :append")),
+        }
+    }
+    Ok(append)
+}
+
+fn open_writer(path: &str, append: bool) -> Result<File, Node> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .map_err(|e| error(&e, "Couldn't open file for writing"))
+}
+
+/// The contents of a file.
+pub fn read_file(path: &str) -> Result<String, Node> {
+    fs::read_to_string(path).map_err(|e| error(&e, "Couldn't read file"))
+}
+
+/// `(slurp path)` reads a whole file into a string.
+pub fn slurp(args: &mut [Node], env: &mut Env) -> Node {
+    if let Err(e) = allowed(env) {
+        return e;
+    }
+    let args = eval_args(args, env);
+    match path_arg(&args, "Expected a path as argument to slurp").and_then(|p| read_file(&p)) {
+        Ok(s) => Str(s),
+        Err(e) => e,
+    }
+}
+
+/// `(spit path x)` writes `x` to a file, replacing it unless `:append true`
+/// is given.
+pub fn spit(args: &mut [Node], env: &mut Env) -> Node {
+    if let Err(e) = allowed(env) {
+        return e;
+    }
+    let args = eval_args(args, env);
+    let path = match path_arg(&args, "Expected a path as first argument to spit") {
+        Ok(path) => path,
+        Err(e) => return e,
+    };
+    let append = match append(&args, 2) {
+        Ok(append) => append,
+        Err(e) => return e,
+    };
+    let mut file = match open_writer(&path, append) {
+        Ok(file) => file,
+        Err(e) => return e,
+    };
+    match file.write_all(to_str(&args[1]).as_bytes()) {
+        Ok(()) => Nil,
+        Err(e) => error(&e, "Couldn't write file"),
+    }
+}
+
+/// `(reader path)` opens a file to read with `line-seq`.
+pub fn reader(args: &mut [Node], env: &mut Env) -> Node {
+    if let Err(e) = allowed(env) {
+        return e;
+    }
+    let args = eval_args(args, env);
+    let path = match path_arg(&args, "Expected a path as argument to reader") {
+        Ok(path) => path,
+        Err(e) => return e,
+    };
+    match File::open(path) {
+        Ok(f) => stream(Handle::Reader(Box::new(BufReader::new(f)))),
+        Err(e) => error(&e, "Couldn't open file"),
+    }
+}
+
+/// `(writer path)` or `(writer path :append true)` opens a file to `write`
+/// to.
+pub fn writer(args: &mut [Node], env: &mut Env) -> Node {
+    if let Err(e) = allowed(env) {
+        return e;
+    }
+    let args = eval_args(args, env);
+    let path = match path_arg(&args, "Expected a path as first argument to writer") {
+        Ok(path) => path,
+        Err(e) => return e,
+    };
+    match append(&args, 1).and_then(|append| open_writer(&path, append)) {
+        Ok(f) => stream(Handle::Writer(Box::new(BufWriter::new(f)))),
+        Err(e) => e,
+    }
+}
+
+/// Reads the next line from a reader, without its line ending, or `None`
+/// at the end.
+pub fn read_line(h: &Mutex<Handle>) -> Result<Option<String>, Node> {
+    let mut line = String::new();
+    let read = match *h.lock().unwrap() {
+        Handle::Reader(ref mut r) => r.read_line(&mut line),
+        Handle::Stdin => io::stdin().read_line(&mut line),
+        Handle::Closed => return Err(Error("Stream is closed")),
+        _ => return Err(Error("Can't read from a writer")),
+    };
+    match read {
+        Ok(0) => Ok(None),
+        Ok(_) => {
+            if line.ends_with('\n') {
+                line.pop();
+                if line.ends_with('\r') {
+                    line.pop();
+                }
+            }
+            Ok(Some(line))
+        },
+        Err(e) => Err(error(&e, "Couldn't read file")),
+    }
+}
+
+/// Writes to a writer, standard output being flushed straight away so
+/// prompts show up before reading.
+pub fn write_str(h: &Mutex<Handle>, s: &str) -> Result<(), Node> {
+    let written = match *h.lock().unwrap() {
+        Handle::Writer(ref mut w) => w.write_all(s.as_bytes()),
+        Handle::Stdout => {
+            let mut out = io::stdout();
+            out.write_all(s.as_bytes()).and_then(|()| out.flush())
+        },
+        Handle::Stderr => io::stderr().write_all(s.as_bytes()),
+        Handle::Buffer(ref mut b) => {
+            b.push_str(s);
+            Ok(())
+        },
+        Handle::Closed => return Err(Error("Stream is closed")),
+        _ => return Err(Error("Can't write to a reader")),
+    };
+    written.map_err(|e| error(&e, "Couldn't write file"))
+}
+
+fn flush_handle(h: &Mutex<Handle>) -> Node {
+    let flushed = match *h.lock().unwrap() {
+        Handle::Writer(ref mut w) => w.flush(),
+        Handle::Stdout => io::stdout().flush(),
+        Handle::Stderr => io::stderr().flush(),
+        _ => Ok(()),
+    };
+    match flushed {
+        Ok(()) => Nil,
+        Err(e) => error(&e, "Couldn't write file"),
+    }
+}
+
+/// The stream a standard one like `*out*` is bound to.
+fn bound_stream(name: &str, env: &Env) -> Result<Arc<Mutex<Handle>>, Node> {
+    match env.get(name) {
+        Some(Stream(h)) => Ok(h),
+        _ => Err(Error("Expected a stream bound to *in*, *out* or *err*")),
+    }
+}
+
+/// Writes to `*out*`, which is standard output unless it has been rebound.
+pub fn out(s: &str, env: &Env) -> Node {
+    match bound_stream("*out*", env).and_then(|h| usable(&h, env).and_then(|()| write_str(&h, s))) {
+        Ok(()) => Nil,
+        Err(e) => e,
+    }
+}
+
+/// `(read-line)` is the next line of `*in*`, or `nil` at the end.
+pub fn read_line_in(_args: &mut [Node], env: &mut Env) -> Node {
+    if let Err(e) = allowed(env) {
+        return e;
+    }
+    match bound_stream("*in*", env).and_then(|h| read_line(&h)) {
+        Ok(Some(line)) => Str(line),
+        Ok(None) => Nil,
+        Err(e) => e,
+    }
+}
+
+/// `(flush)` writes out anything `*out*` is holding on to.
+pub fn flush(_args: &mut [Node], env: &mut Env) -> Node {
+    match bound_stream("*out*", env).and_then(|h| usable(&h, env).map(|()| h)) {
+        Ok(h) => flush_handle(&h),
+        Err(e) => e,
+    }
+}
+
+/// `(line-seq rdr)` is a lazy seq of the lines left in a reader.
+pub fn line_seq(args: &mut [Node], env: &mut Env) -> Node {
+    if let Err(e) = allowed(env) {
+        return e;
+    }
+    match eval_args(args, env).pop() {
+        Some(Stream(h)) => seq::lazy(Lazy::Lines(h)),
+        _ => Error("Expected a reader as argument to line-seq"),
+    }
+}
+
+/// `(write w x...)` writes its arguments as `str` would join them.
+pub fn write(args: &mut [Node], env: &mut Env) -> Node {
+    let args = eval_args(args, env);
+    let h = match args.first() {
+        Some(Stream(h)) => h.clone(),
+        _ => return Error("Expected a writer as first argument to write"),
+    };
+    let s: String = args[1..].iter().map(to_str).collect();
+    usable(&h, env).and_then(|()| write_str(&h, &s)).err().unwrap_or(Nil)
+}
+
+/// `(close s)` flushes a writer and closes either kind of stream. Closing
+/// twice does nothing, and the standard streams are only flushed.
+pub fn close(args: &mut [Node], env: &mut Env) -> Node {
+    match eval_args(args, env).pop() {
+        Some(Stream(h)) => close_handle(&h),
+        _ => Error("Expected a stream as argument to close"),
+    }
+}
+
+fn close_handle(h: &Mutex<Handle>) -> Node {
+    if matches!(*h.lock().unwrap(), Handle::Stdin | Handle::Stdout | Handle::Stderr) {
+        return flush_handle(h);
+    }
+    match ::std::mem::replace(&mut *h.lock().unwrap(), Handle::Closed) {
+        Handle::Writer(mut w) => match w.flush() {
+            Ok(()) => Nil,
+            Err(e) => error(&e, "Couldn't write file"),
+        },
+        _ => Nil,
+    }
+}
+
+/// `(with-open [name stream ...] body...)` evaluates the body with the
+/// streams bound, then closes them in reverse order, even if the body
+/// evaluated to an error.
+pub fn with_open(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let bindings = match args.remove(0) {
+        Vector(v) => v,
+        _ => return Error("Expected vector as first argument to with-open"),
+    };
+    if bindings.len() % 2 != 0 {
+        return Error("Expected binding vector to contain an even number of forms");
+    }
+    let mut new_env = env.clone();
+    let mut opened = Vec::new();
+    let mut result = Nil;
+    for pair in bindings.chunks(2) {
+        let name = match pair[0] {
+            Symbol(ref s) => s.clone(),
+            _ => {
+                result = Error("Expected odd items in binding vector to be symbols");
+                break;
+            },
+        };
+        match eval(&mut pair[1].clone(), &mut new_env.clone()) {
+            e @ Error(_) => {
+                result = e;
+                break;
+            },
+            value => {
+                opened.push(value.clone());
+                new_env.insert(name, value);
+            },
+        }
+    }
+    if opened.len() == bindings.len() / 2 {
+        for form in args.iter_mut() {
+            result = eval(form, &mut new_env);
+        }
+    }
+    for s in opened.iter().rev() {
+        if let Stream(ref h) = *s {
+            if let e @ Error(_) = close_handle(h) {
+                if !matches!(result, Error(_)) {
+                    result = e;
+                }
+            }
+        }
+    }
+    result
+}
+
+/// `(with-out-str body...)` evaluates the body with `*out*` bound to a
+/// string, and gives what was printed to it.
+pub fn with_out_str(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let buffer = Arc::new(Mutex::new(Handle::Buffer(String::new())));
+    let mut form = vec![Vector(vec![Symbol("*out*".to_owned()), Stream(buffer.clone())])];
+    form.append(args);
+    if let e @ Error(_) = ns::binding(&mut form, env) {
+        return e;
+    }
+    let printed = ::std::mem::replace(&mut *buffer.lock().unwrap(), Handle::Closed);
+    match printed {
+        Handle::Buffer(s) => Str(s),
+        _ => Error("Stream is closed"),
+    }
+}
+
+/// `(file-exists? path)`, for files and directories alike.
+pub fn file_exists(args: &mut [Node], env: &mut Env) -> Node {
+    if let Err(e) = allowed(env) {
+        return e;
+    }
+    let args = eval_args(args, env);
+    match path_arg(&args, "Expected a path as argument to file-exists?") {
+        Ok(path) => Bool(Path::new(&path).exists()),
+        Err(e) => e,
+    }
+}
+
+/// `(list-dir path)` is a sorted vector of the names in a directory.
+pub fn list_dir(args: &mut [Node], env: &mut Env) -> Node {
+    if let Err(e) = allowed(env) {
+        return e;
+    }
+    let args = eval_args(args, env);
+    let path = match path_arg(&args, "Expected a path as argument to list-dir") {
+        Ok(path) => path,
+        Err(e) => return e,
+    };
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) => return error(&e, "Couldn't list directory"),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        match entry {
+            Ok(entry) => names.push(entry.file_name().to_string_lossy().into_owned()),
+            Err(e) => return error(&e, "Couldn't list directory"),
+        }
+    }
+    names.sort();
+    Vector(names.into_iter().map(Str).collect())
+}
+
+/// `(mkdir path)` creates a directory along with any missing parents.
+pub fn mkdir(args: &mut [Node], env: &mut Env) -> Node {
+    if let Err(e) = allowed(env) {
+        return e;
+    }
+    let args = eval_args(args, env);
+    match path_arg(&args, "Expected a path as argument to mkdir") {
+        Ok(path) => match fs::create_dir_all(path) {
+            Ok(()) => Nil,
+            Err(e) => error(&e, "Couldn't create directory"),
+        },
+        Err(e) => e,
+    }
+}
+
+/// `(delete-file path)` deletes a file or empty directory. With a true
+/// second argument failing gives `false` rather than an error.
+pub fn delete_file(args: &mut [Node], env: &mut Env) -> Node {
+    if let Err(e) = allowed(env) {
+        return e;
+    }
+    let args = eval_args(args, env);
+    let path = match path_arg(&args, "Expected a path as first argument to delete-file") {
+        Ok(path) => path,
+        Err(e) => return e,
+    };
+    let silently = args.get(1).is_some_and(|s| !matches!(*s, Nil | Bool(false)));
+    let result = if Path::new(&path).is_dir() { fs::remove_dir(&path) } else { fs::remove_file(&path) };
+    match result {
+        Ok(()) => Bool(true),
+        Err(_) if silently => Bool(false),
+        Err(e) => error(&e, "Couldn't delete file"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use eval::{Env, eval_all};
+    use ns;
+
+    /// Evaluates `src` with `dir` standing for a fresh scratch directory.
+    fn run(name: &str, src: &str) -> String {
+        let dir = env::temp_dir().join(format!("brojure-io-{}-{}", name, ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut env = Env::new();
+        ns::load(include_str!("../lib/lib.clj"), &mut env);
+        let src = src.replace("$dir", &dir.display().to_string());
+        format!("{}", eval_all(&src, &mut env))
+    }
+
+    #[test]
+    fn slurps_and_spits() {
+        assert_eq!(run("spit", "(mkdir \"$dir\")
+                                (spit \"$dir/a.txt\" \"one\\n\")
+                                (spit \"$dir/a.txt\" 2 :append true)
+                                (slurp \"$dir/a.txt\")"), "\"one\\n2\"");
+        assert_eq!(run("missing", "(slurp \"$dir/none.txt\")"), "Error: File not found");
+    }
+
+    #[test]
+    fn reads_lines_lazily() {
+        assert_eq!(run("lines", "(mkdir \"$dir\")
+                                 (spit \"$dir/l.txt\" \"a\\r\\nb\\n\\nc\")
+                                 (with-open [r (reader \"$dir/l.txt\")]
+                                   (doall (line-seq r)))"), "(\"a\" \"b\" \"\" \"c\")");
+        assert_eq!(run("closed", "(mkdir \"$dir\")
+                                  (spit \"$dir/l.txt\" \"a\\nb\")
+                                  (def r (reader \"$dir/l.txt\"))
+                                  (def lines (line-seq r))
+                                  (first lines)
+                                  (close r)
+                                  [(first lines) (count lines)]"), "[\"a\" Error: Stream is closed]");
+    }
+
+    #[test]
+    fn writes_and_closes() {
+        assert_eq!(run("write", "(mkdir \"$dir\")
+                                 (with-open [w (writer \"$dir/w.txt\")]
+                                   (write w \"x=\" 1 \"\\n\"))
+                                 (with-open [w (writer \"$dir/w.txt\" :append true)]
+                                   (write w :y))
+                                 (slurp \"$dir/w.txt\")"), "\"x=1\\n:y\"");
+        assert_eq!(run("closes", "(mkdir \"$dir\")
+                                  (def w (writer \"$dir/w.txt\"))
+                                  [(with-open [v w] (nth [] 1)) (write w 1)]"),
+                   "[Error: Index out of bounds Error: Stream is closed]");
+    }
+
+    #[test]
+    fn captures_and_redirects_output() {
+        assert_eq!(run("capture", "[(with-out-str (println \"a\") (print (with-out-str (pr :b)) 1))
+                                   (with-out-str (def outer *out*) (with-out-str (binding [*out* outer] (print 2))))]"),
+                   "[\"a\\n:b 1\" \"2\"]");
+        assert_eq!(run("apply", "(with-out-str (doall (map println [1 2])))"), "\"1\\n2\\n\"");
+        assert_eq!(run("restore", "(def out *out*)
+                                   [(with-out-str (nth [] 1)) (binding [*out* *err*] (nth [] 1)) (= out *out*)]"),
+                   "[Error: Index out of bounds Error: Index out of bounds true]");
+        assert_eq!(run("flush", "(mkdir \"$dir\")
+                                 (def w (writer \"$dir/f.txt\"))
+                                 (binding [*out* w] (print \"x\") (flush))
+                                 (slurp \"$dir/f.txt\")"), "\"x\"");
+    }
+
+    #[test]
+    fn manages_files() {
+        assert_eq!(run("files", "(mkdir \"$dir/sub\")
+                                 (spit \"$dir/b\" \"\")
+                                 (spit \"$dir/a\" \"\")
+                                 [(list-dir \"$dir\") (file-exists? \"$dir/a\") (delete-file \"$dir/a\")
+                                  (file-exists? \"$dir/a\") (delete-file \"$dir/a\" true) (delete-file \"$dir/sub\")]"),
+                   "[[\"a\" \"b\" \"sub\"] true true false false true]");
+        assert_eq!(run("delete", "(delete-file \"$dir/gone\")"), "Error: File not found");
+        assert_eq!(run("list", "(list-dir \"$dir\")"), "Error: File not found");
+    }
+}
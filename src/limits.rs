@@ -0,0 +1,321 @@
+use std::cell::Cell;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use node::Node;
+use node::Node::Error;
+
+pub const OUT_OF_FUEL: &str = "Ran out of fuel";
+pub const TOO_DEEP: &str = "Maximum call depth exceeded";
+pub const TOO_LARGE: &str = "Collection is larger than the size limit";
+pub const TIMED_OUT: &str = "Evaluation timed out";
+pub const INTERRUPTED: &str = "Interrupted";
+
+/// Bounds on the work each top-level evaluation may do, for running code
+/// that can't be trusted. `None` leaves that kind of work unbounded.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EvalLimits {
+    /// How many lists may be evaluated, function calls included.
+    pub fuel: Option<u64>,
+    /// How deeply lists may be evaluated inside each other, which keeps
+    /// deep recursion from overflowing the native stack.
+    pub depth: Option<usize>,
+    /// How many elements any one collection may have.
+    pub size: Option<usize>,
+    pub timeout: Option<Duration>,
+}
+
+/// Stops the evaluation in progress with an "Interrupted" error the next
+/// time it calls a function or goes round a loop. It can be sent to and
+/// used from any thread.
+#[derive(Clone, Debug, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Forgets a cancellation that nothing has been interrupted by yet.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+
+    /// Whether there was a cancellation, which only interrupts once.
+    fn take(&self) -> bool {
+        self.0.load(Ordering::Relaxed) && self.0.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// How often, in steps, the clock is looked at for the timeout.
+const CLOCK_EVERY: u64 = 256;
+
+/// The errors `Usage` can fail with, numbered from 1 so that 0 means none.
+const EXCEEDED: [&str; 5] = [OUT_OF_FUEL, TOO_DEEP, TOO_LARGE, TIMED_OUT, INTERRUPTED];
+
+thread_local! {
+    /// How deeply lists are being evaluated on this thread.
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    /// Steps and elements counted on this thread, for when to look at the
+    /// clock.
+    static TICKS: Cell<u64> = const { Cell::new(0) };
+    static WORKER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Marks this thread as running work for futures, which counts towards the
+/// evaluation that started it rather than starting a fresh one.
+pub fn worker_thread() {
+    WORKER.with(|w| w.set(true));
+}
+
+/// What the evaluation in progress has used of its limits, shared by every
+/// thread working on it.
+#[derive(Debug, Default)]
+pub struct Usage {
+    steps: AtomicU64,
+    started: Mutex<Option<Instant>>,
+    cancel: CancelHandle,
+    /// The first limit gone over, or the interruption, as a number from
+    /// `EXCEEDED`. Everything still being evaluated fails with it, so it
+    /// isn't lost when functions given the error fail in their own way.
+    exceeded: AtomicUsize,
+}
+
+impl Usage {
+    /// Counts a list about to be evaluated, starting afresh when nothing
+    /// else is being evaluated. Every `Ok` must be matched by a `leave`.
+    pub fn enter(&self, limits: &EvalLimits) -> Result<(), Node> {
+        let depth = DEPTH.with(Cell::get);
+        if depth == 0 && !WORKER.with(Cell::get) {
+            self.steps.store(0, Ordering::Relaxed);
+            *self.started.lock().unwrap() = limits.timeout.map(|_| Instant::now());
+            self.exceeded.store(0, Ordering::SeqCst);
+        }
+        if let Some(e) = self.exceeded().or_else(|| self.check(limits, depth)) {
+            return Err(self.exceed(e));
+        }
+        DEPTH.with(|d| d.set(depth + 1));
+        Ok(())
+    }
+
+    fn check(&self, limits: &EvalLimits, depth: usize) -> Option<&'static str> {
+        if self.cancel.take() {
+            return Some(INTERRUPTED);
+        }
+        // Only counted with a limit, since every thread would be counting.
+        if let Some(fuel) = limits.fuel {
+            if self.steps.fetch_add(1, Ordering::Relaxed) >= fuel {
+                return Some(OUT_OF_FUEL);
+            }
+        }
+        if limits.depth.is_some_and(|limit| depth >= limit) {
+            return Some(TOO_DEEP);
+        }
+        self.check_clock(limits)
+    }
+
+    /// Looks at the clock every `CLOCK_EVERY` ticks of this thread.
+    fn check_clock(&self, limits: &EvalLimits) -> Option<&'static str> {
+        let timeout = limits.timeout?;
+        let ticks = TICKS.with(|t| {
+            t.set(t.get() + 1);
+            t.get()
+        });
+        if !ticks.is_multiple_of(CLOCK_EVERY) {
+            return None;
+        }
+        self.timed_out(timeout)
+    }
+
+    fn timed_out(&self, timeout: Duration) -> Option<&'static str> {
+        match *self.started.lock().unwrap() {
+            Some(started) if started.elapsed() > timeout => Some(TIMED_OUT),
+            _ => None,
+        }
+    }
+
+    fn exceeded(&self) -> Option<&'static str> {
+        match self.exceeded.load(Ordering::SeqCst) {
+            0 => None,
+            i => Some(EXCEEDED[i - 1]),
+        }
+    }
+
+    /// Records the first limit gone over and gives the error for it.
+    fn exceed(&self, e: &'static str) -> Node {
+        let i = EXCEEDED.iter().position(|&x| x == e).unwrap() + 1;
+        let _ = self.exceeded.compare_exchange(0, i, Ordering::SeqCst, Ordering::SeqCst);
+        Error(self.exceeded().unwrap_or(e))
+    }
+
+    /// Ends a list `enter` let through, giving the limit that was gone
+    /// over while evaluating it, if any.
+    pub fn leave(&self) -> Option<Node> {
+        DEPTH.with(|d| d.set(d.get() - 1));
+        self.exceeded().map(Error)
+    }
+
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Fails if a collection of `len` elements is over the size limit, or
+    /// another limit has been gone over or the evaluation interrupted, so
+    /// building it is wasted work.
+    pub fn check_size(&self, limits: &EvalLimits, len: usize) -> Result<(), Node> {
+        let exceeded = if self.cancel.take() {
+            Some(INTERRUPTED)
+        } else if limits.size.is_some_and(|size| len > size) {
+            Some(TOO_LARGE)
+        } else {
+            self.exceeded().or_else(|| self.check_clock(limits))
+        };
+        match exceeded {
+            Some(e) => Err(self.exceed(e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Fails if the evaluation has been interrupted or gone over a limit,
+    /// for threads waiting on others rather than doing any work.
+    pub fn check_waiting(&self, limits: &EvalLimits) -> Result<(), Node> {
+        let exceeded = if self.cancel.take() {
+            Some(INTERRUPTED)
+        } else {
+            self.exceeded().or_else(|| limits.timeout.and_then(|t| self.timed_out(t)))
+        };
+        match exceeded {
+            Some(e) => Err(self.exceed(e)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+    use eval::{Env, eval_all};
+    use host::Capabilities;
+    use ns;
+    use super::EvalLimits;
+
+    fn env(limits: EvalLimits) -> Env {
+        let mut env = Env::new();
+        ns::load(include_str!("../lib/lib.clj"), &mut env);
+        env.set_limits(limits);
+        env
+    }
+
+    fn run(env: &mut Env, src: &str) -> String {
+        format!("{}", eval_all(src, env))
+    }
+
+    #[test]
+    fn runs_out_of_fuel() {
+        let mut env = env(EvalLimits { fuel: Some(1000), ..EvalLimits::default() });
+        assert_eq!(run(&mut env, "(defn inc-all [xs] (map (fn [x] (+ x 1)) xs)) (count (inc-all (range)))"),
+                   "Error: Ran out of fuel");
+        assert_eq!(run(&mut env, "(count (inc-all (range 10)))"), "10");
+    }
+
+    #[test]
+    fn limits_depth() {
+        let mut env = env(EvalLimits { depth: Some(100), ..EvalLimits::default() });
+        assert_eq!(run(&mut env, "(defn forever [n] (+ 1 (forever n))) (forever 1)"),
+                   "Error: Maximum call depth exceeded");
+        assert_eq!(run(&mut env, "(defn down [n] (if (= n 0) :done (down (- n 1)))) (down 10)"), ":done");
+    }
+
+    #[test]
+    fn limits_collection_size() {
+        let mut env = env(EvalLimits { size: Some(100), ..EvalLimits::default() });
+        assert_eq!(run(&mut env, "(count (range))"), "Error: Collection is larger than the size limit");
+        assert_eq!(run(&mut env, "(reduce conj [] (range 1000))"), "Error: Collection is larger than the size limit");
+        assert_eq!(run(&mut env, "(count (range 100))"), "100");
+    }
+
+    #[test]
+    fn times_out() {
+        let mut env = env(EvalLimits { timeout: Some(Duration::from_millis(20)), ..EvalLimits::default() });
+        assert_eq!(run(&mut env, "(count (map (fn [x] (+ x 1)) (range)))"), "Error: Evaluation timed out");
+        assert_eq!(run(&mut env, "(count (range))"), "Error: Evaluation timed out");
+        assert_eq!(run(&mut env, "(count (map (fn [x] (+ x 1)) (range 10)))"), "10");
+    }
+
+    #[test]
+    fn can_be_cancelled_from_another_thread() {
+        let mut env = env(EvalLimits::default());
+        let cancel = env.cancel_handle();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            cancel.cancel();
+        });
+        assert_eq!(run(&mut env, "(def kept 1) (count (map (fn [x] (+ x 1)) (range)))"), "Error: Interrupted");
+        canceller.join().unwrap();
+        assert_eq!(run(&mut env, "[kept (count (range 5))]"), "[1 5]");
+    }
+
+    #[test]
+    fn malformed_forms_are_errors() {
+        let mut env = env(EvalLimits::default());
+        for &(src, expected) in &[
+            ("(quote)", "Error: Expected one form as argument to quote"),
+            ("(quote a b)", "Error: Expected one form as argument to quote"),
+            ("(def)", "Error: Expected a name and a value as arguments to def"),
+            ("(def x)", "Error: Expected a name and a value as arguments to def"),
+            ("(def 1 2)", "Error: Expected symbol as first argument to def"),
+            ("(defn f)", "Error: Expected a name and a parameter vector as arguments to defn"),
+            ("(defn f x)", "Error: Expected vector as first argument to fn"),
+            ("(if)", "Error: Expected a condition, a form and an optional else form as arguments to if"),
+            ("(if true)", "Error: Expected a condition, a form and an optional else form as arguments to if"),
+            ("(if true 1 2 3)", "Error: Expected a condition, a form and an optional else form as arguments to if"),
+            ("(fn)", "Error: Expected vector as first argument to fn"),
+            ("(fn [1] 1)", "Error: Expected symbols as function parameters"),
+            ("(let)", "Error: Expected vector as first argument to let"),
+            ("(let [1 2] 1)", "Error: Expected odd items in binding vector to be symbols"),
+            ("(for)", "Error: Expected binding vector as first argument to for"),
+            ("(for [x] x)", "Error: Expected binding vector to contain an even number of forms"),
+            ("(ns)", "Error: Expected a symbol as first argument to ns"),
+            ("(with-open)", "Error: Expected vector as first argument to with-open"),
+            ("(binding)", "Error: Expected vector as first argument to binding"),
+            ("(with-redefs)", "Error: Expected vector as first argument to with-redefs"),
+            ("(set!)", "Error: Expected a var and a value as arguments to set!"),
+            ("(deftest)", "Error: Expected a symbol as first argument to deftest"),
+            ("(is)", "Error: Expected a form and an optional message as arguments to is"),
+            ("(are)", "Error: Expected a vector of names as first argument to are"),
+            ("(testing)", "Error: Expected a context string as first argument to testing"),
+        ] {
+            assert_eq!(run(&mut env, src), expected, "{}", src);
+        }
+        for src in &["(or)", "(and)", "(do)", "(lazy-seq)", "(with-out-str)", "(dosync)",
+                     "@(future)", "(<!! (go))", "(<!! (thread))", "((fn []))", "(defn f []) (f)"] {
+            assert_ne!(run(&mut env, src).split(':').next(), Some("Error"), "{}", src);
+        }
+        assert_eq!(run(&mut env, "((fn [x] (def y x) (* y 2)) 3)"), "6");
+    }
+
+    #[test]
+    fn sandbox_has_no_io() {
+        let mut env = env(EvalLimits::default());
+        env.set_capabilities(Capabilities::sandbox());
+        assert_eq!(run(&mut env, "[(slurp \"Cargo.toml\") (println 1) (read-line) (require 'a.b) (sh \"true\")]"),
+                   "[Error: I/O is disabled Error: I/O is disabled Error: I/O is disabled \
+                    Error: I/O is disabled Error: Host access is disabled]");
+        assert_eq!(run(&mut env, "(with-out-str (println 1) (flush))"), "\"1\\n\"");
+    }
+
+    #[test]
+    fn printing_comparing_and_hashing_infinite_seqs_are_limited() {
+        let mut env = env(EvalLimits { size: Some(1000), timeout: Some(Duration::from_millis(500)),
+                                       fuel: Some(100000), ..EvalLimits::default() });
+        env.set_capabilities(Capabilities::sandbox());
+        for src in &["(count (str (range)))", "(count (pr-str (iterate inc 0)))", "(= (range) (range))",
+                     "(hash (range))", "(hash [(range)])", "(with-out-str (pprint (range)))",
+                     "(with-out-str (prn (cycle [1 2])))"] {
+            assert_eq!(run(&mut env, src), "Error: Collection is larger than the size limit", "{}", src);
+        }
+        assert_eq!(run(&mut env, "[(count (str (range 10))) (= (range 10) (range 10)) (with-out-str (pprint (range 3)))]"),
+                   "[21 true \"(0 1 2)\\n\"]");
+    }
+}
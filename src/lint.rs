@@ -0,0 +1,453 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use node::Node;
+use node::Node::{Symbol, Keyword, List, Vector, Map, Set, Error};
+use parser::{tokenize_with_positions, parse_spanned, without_meta, Pos, Span};
+use eval::{is_builtin, is_special_form, builtin_arity};
+use fmt::clj_files;
+use ns::split_qualified;
+use io::standard_stream;
+
+#[derive(Debug, PartialEq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub pos: Pos,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error   => "error",
+        };
+        write!(f, "{}:{}: {}: {}", self.pos.line, self.pos.col, severity, self.message)
+    }
+}
+
+struct Local {
+    name: String,
+    pos: Pos,
+    used: bool,
+}
+
+struct Linter {
+    /// Names defined at the top level, with the arity of those that are
+    /// functions of known parameters.
+    globals: HashMap<String, Option<usize>>,
+    locals: Vec<Local>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// The span of the `i`th form inside `span`, or `span` itself for forms
+/// that were built rather than read.
+fn child(span: &Span, i: usize) -> &Span {
+    span.children.get(i).unwrap_or(span)
+}
+
+/// Records top level `def`s, `defn`s and `deftest`s, including those inside
+/// `do`, and names referred from required namespaces.
+fn collect(n: &Node, globals: &mut HashMap<String, Option<usize>>) {
+    if let List(ref items) = *n {
+        match (items.first(), items.get(1).map(without_meta), items.get(2)) {
+            (Some(Symbol(head)), Some(Symbol(name)), params) if head == "defn" => {
+                let arity = match params {
+                    Some(Vector(p)) => Some(p.len()),
+                    _ => None,
+                };
+                globals.insert(name.clone(), arity);
+            },
+            (Some(Symbol(head)), Some(Symbol(name)), _) if head == "deftest" => {
+                globals.insert(name.clone(), Some(0));
+            },
+            (Some(Symbol(head)), Some(Symbol(name)), value) if head == "def" => {
+                let arity = match value {
+                    Some(List(f)) if f.first() == Some(&Symbol("fn".to_owned())) => match f.get(1) {
+                        Some(Vector(p)) => Some(p.len()),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                globals.insert(name.clone(), arity);
+            },
+            (Some(Symbol(head)), _, _) if head == "ns" || head == "require" => {
+                for item in &items[1..] {
+                    referred(item, globals);
+                }
+            },
+            (Some(Symbol(head)), _, _) if head == "do" => {
+                for item in &items[1..] {
+                    collect(item, globals);
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Names brought in by the `:refer [...]` of a require spec, found inside
+/// an `ns` clause or a quoted argument to `require`.
+fn referred(n: &Node, globals: &mut HashMap<String, Option<usize>>) {
+    match *n {
+        List(ref items) | Vector(ref items) => {
+            for pair in items.windows(2) {
+                if let (Keyword(k), Vector(names)) = (&pair[0], &pair[1]) {
+                    if k == "refer" {
+                        for name in names {
+                            if let Symbol(ref s) = *name {
+                                globals.insert(s.clone(), None);
+                            }
+                        }
+                    }
+                }
+            }
+            for item in items {
+                referred(item, globals);
+            }
+        },
+        _ => (),
+    }
+}
+
+fn plural(n: usize) -> &'static str {
+    if n == 1 { "" } else { "s" }
+}
+
+impl Linter {
+    fn report(&mut self, pos: Pos, severity: Severity, message: String) {
+        self.diagnostics.push(Diagnostic { pos, severity, message });
+    }
+
+    fn is_local(&self, name: &str) -> bool {
+        self.locals.iter().any(|l| l.name == name)
+    }
+
+    fn check_shadowing(&mut self, name: &str, pos: Pos) {
+        if is_builtin(name) || is_special_form(name) {
+            self.report(pos, Severity::Warning, format!("`{}` shadows a builtin", name));
+        }
+    }
+
+    fn bind(&mut self, name: &Node, span: &Span) {
+        match *name {
+            Symbol(ref s) => {
+                self.check_shadowing(s, span.pos);
+                self.locals.push(Local { name: s.clone(), pos: span.pos, used: false });
+            },
+            _ => self.report(span.pos, Severity::Error, "Expected a symbol to bind".to_owned()),
+        }
+    }
+
+    /// Drops the locals bound since there were `start` of them, reporting
+    /// those that were never used. Names starting with `_` are meant to be.
+    fn unbind(&mut self, start: usize) {
+        let unused: Vec<Local> = self.locals.drain(start..).filter(|l| !l.used && !l.name.starts_with('_')).collect();
+        for l in unused {
+            self.report(l.pos, Severity::Warning, format!("Unused binding `{}`", l.name));
+        }
+    }
+
+    fn resolve(&mut self, name: &str, pos: Pos) {
+        if let Some(local) = self.locals.iter_mut().rev().find(|l| l.name == name) {
+            local.used = true;
+            return;
+        }
+        // Other namespaces aren't read, so qualified names are taken on trust.
+        if split_qualified(name).is_some() {
+            return;
+        }
+        if !self.globals.contains_key(name) && !is_builtin(name) && !is_special_form(name)
+            && standard_stream(name).is_none() {
+            self.report(pos, Severity::Error, format!("Unresolved symbol `{}`", name));
+        }
+    }
+
+    fn forms(&mut self, items: &[Node], span: &Span, from: usize) {
+        for (i, item) in items.iter().enumerate().skip(from) {
+            self.form(item, child(span, i));
+        }
+    }
+
+    fn form(&mut self, n: &Node, span: &Span) {
+        match *n {
+            Symbol(ref s) => self.resolve(s, span.pos),
+            List(ref items) if !items.is_empty() => self.list(items, span),
+            Vector(ref items) | Set(ref items) => self.forms(items, span, 0),
+            Map(ref m) => {
+                for (i, (k, v)) in m.iter().enumerate() {
+                    self.form(k, child(span, 2 * i));
+                    self.form(v, child(span, 2 * i + 1));
+                }
+            },
+            Error(e) => self.report(span.pos, Severity::Error, e.to_owned()),
+            _ => (),
+        }
+    }
+
+    fn list(&mut self, items: &[Node], span: &Span) {
+        let head = match items[0] {
+            Symbol(ref s) if !self.is_local(s) => s.as_str(),
+            _ => return self.forms(items, span, 0),
+        };
+        let args = items.len() - 1;
+        match head {
+            "quote" | "ns" => (),
+            "def" => {
+                if args != 2 {
+                    self.report(span.pos, Severity::Error, "def expects a name and a value".to_owned());
+                }
+                match items.get(1).map(without_meta) {
+                    Some(Symbol(name)) => self.check_shadowing(name, child(span, 1).pos),
+                    _ => self.report(child(span, 1).pos, Severity::Error, "Expected a symbol to def".to_owned()),
+                }
+                self.forms(items, span, 2);
+            },
+            "defn" => {
+                match items.get(1).map(without_meta) {
+                    Some(Symbol(name)) => self.check_shadowing(name, child(span, 1).pos),
+                    _ => self.report(child(span, 1).pos, Severity::Error, "Expected a symbol to defn".to_owned()),
+                }
+                self.function(items, span, 2);
+            },
+            "fn" => self.function(items, span, 1),
+            "let" | "for" | "with-open" => {
+                let (bindings, bspan) = match items.get(1) {
+                    Some(Vector(b)) => (b, child(span, 1)),
+                    _ => return self.report(span.pos, Severity::Error, format!("{} expects a binding vector", head)),
+                };
+                if bindings.len() % 2 != 0 {
+                    self.report(bspan.pos, Severity::Error, "Binding vector needs an even number of forms".to_owned());
+                }
+                if head == "for" && bindings.len() != 2 {
+                    self.report(bspan.pos, Severity::Error, "for takes a single binding".to_owned());
+                }
+                let start = self.locals.len();
+                for (i, pair) in bindings.chunks(2).enumerate() {
+                    if let Some(value) = pair.get(1) {
+                        self.form(value, child(bspan, 2 * i + 1));
+                    }
+                    self.bind(&pair[0], child(bspan, 2 * i));
+                }
+                self.forms(items, span, 2);
+                self.unbind(start);
+            },
+            "deftest" => {
+                if !matches!(items.get(1), Some(Symbol(_))) {
+                    self.report(child(span, 1).pos, Severity::Error, "Expected a symbol to deftest".to_owned());
+                }
+                self.forms(items, span, 2);
+            },
+            "is" => match items.get(1) {
+                Some(List(test)) if test.first() == Some(&Symbol("thrown?".to_owned())) => {
+                    self.forms(test, child(span, 1), 1);
+                    self.forms(items, span, 2);
+                },
+                _ => self.forms(items, span, 1),
+            },
+            "are" => {
+                let (names, nspan) = match items.get(1) {
+                    Some(Vector(n)) => (n, child(span, 1)),
+                    _ => return self.report(span.pos, Severity::Error, "are expects a vector of names".to_owned()),
+                };
+                if args < 2 {
+                    return self.report(span.pos, Severity::Error, "are expects names and a template".to_owned());
+                }
+                if !names.is_empty() && !(args - 2).is_multiple_of(names.len()) {
+                    self.report(span.pos, Severity::Error, "are needs arguments for every name".to_owned());
+                }
+                let start = self.locals.len();
+                for (i, name) in names.iter().enumerate() {
+                    self.bind(name, child(nspan, i));
+                }
+                self.form(&items[2], child(span, 2));
+                self.unbind(start);
+                self.forms(items, span, 3);
+            },
+            "if" => {
+                if args > 3 {
+                    self.report(span.pos, Severity::Error, format!("if has too many branches ({} forms after the test)", args - 1));
+                } else if args < 2 {
+                    self.report(span.pos, Severity::Error, "if needs a test and a then branch".to_owned());
+                }
+                self.forms(items, span, 1);
+            },
+            _ if is_special_form(head) => self.forms(items, span, 1),
+            _ => {
+                let arity = match self.globals.get(head) {
+                    Some(&Some(n)) => Some((n, Some(n))),
+                    Some(&None) => None,
+                    None => builtin_arity(head),
+                };
+                if let Some((min, max)) = arity {
+                    let expected = match max {
+                        Some(max) if max == min => format!("{} argument{}", min, plural(min)),
+                        Some(max) => format!("{} to {} arguments", min, max),
+                        None => format!("at least {} argument{}", min, plural(min)),
+                    };
+                    if args < min || max.is_some_and(|max| args > max) {
+                        self.report(span.pos, Severity::Error,
+                                    format!("`{}` takes {} but was called with {}", head, expected, args));
+                    }
+                }
+                self.forms(items, span, 0);
+            },
+        }
+    }
+
+    /// The parameter vector at `at` and the body after it.
+    fn function(&mut self, items: &[Node], span: &Span, at: usize) {
+        let params = match items.get(at) {
+            Some(Vector(p)) => p,
+            _ => return self.report(span.pos, Severity::Error, "Expected a parameter vector".to_owned()),
+        };
+        let start = self.locals.len();
+        for (i, p) in params.iter().enumerate() {
+            self.bind(p, child(child(span, at), i));
+        }
+        self.forms(items, span, at + 1);
+        self.unbind(start);
+    }
+}
+
+/// Lints source, with `lib` supplying definitions it can rely on.
+pub fn lint(src: &str, lib: &str) -> Vec<Diagnostic> {
+    let mut forms = Vec::new();
+    let mut tokens = tokenize_with_positions(src);
+    while !tokens.is_empty() {
+        forms.push(parse_spanned(&mut tokens));
+    }
+
+    let mut globals = HashMap::new();
+    let mut lib_tokens = tokenize_with_positions(lib);
+    while !lib_tokens.is_empty() {
+        collect(&parse_spanned(&mut lib_tokens).0, &mut globals);
+    }
+    for (n, _) in &forms {
+        collect(n, &mut globals);
+    }
+
+    let mut linter = Linter { globals, locals: Vec::new(), diagnostics: Vec::new() };
+    for (n, span) in &forms {
+        linter.form(n, span);
+    }
+    linter.diagnostics.sort_by_key(|d| (d.pos.line, d.pos.col));
+    linter.diagnostics
+}
+
+/// `brojure lint [paths...]` reports problems as `file:line:col` lines,
+/// reading standard input when no paths are given. Exits with 1 if there
+/// was anything to report.
+pub fn run(args: &[String]) -> i32 {
+    let lib = fs::read_to_string("lib/lib.clj").unwrap_or_default();
+    let mut sources = Vec::new();
+
+    if args.is_empty() {
+        let mut src = String::new();
+        if io::stdin().read_to_string(&mut src).is_err() {
+            eprintln!("Couldn't read standard input");
+            return 2;
+        }
+        sources.push(("<stdin>".to_owned(), src));
+    }
+    let mut files = Vec::new();
+    for path in args {
+        if let Err(e) = clj_files(Path::new(path), &mut files) {
+            eprintln!("{}: {}", path, e);
+            return 2;
+        }
+    }
+    for file in files {
+        match fs::read_to_string(&file) {
+            Ok(src) => sources.push((file.display().to_string(), src)),
+            Err(e) => {
+                eprintln!("{}: {}", file.display(), e);
+                return 2;
+            },
+        }
+    }
+
+    let mut status = 0;
+    for (name, src) in sources {
+        for d in lint(&src, &lib) {
+            println!("{}:{}", name, d);
+            status = 1;
+        }
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lint, Severity};
+
+    fn messages(src: &str) -> Vec<String> {
+        lint(src, "(defn inc [x] (+ x 1))").iter().map(|d| d.to_string()).collect()
+    }
+
+    #[test]
+    fn clean_code_has_nothing_to_report() {
+        assert!(messages("(defn f [x] (let [y (inc x)] (* y y)))\n(f 2)").is_empty());
+    }
+
+    #[test]
+    fn reports_unresolved_symbols_with_positions() {
+        assert_eq!(messages("(defn f [x]\n  (+ x y))"), vec!["2:8: error: Unresolved symbol `y`"]);
+    }
+
+    #[test]
+    fn reports_arity_mismatches() {
+        assert_eq!(messages("(nth [1 2])\n(inc 1 2)\n(range 1 2 3 4)"), vec![
+            "1:1: error: `nth` takes 2 arguments but was called with 1",
+            "2:1: error: `inc` takes 1 argument but was called with 2",
+            "3:1: error: `range` takes 0 to 3 arguments but was called with 4",
+        ]);
+    }
+
+    #[test]
+    fn reports_bindings() {
+        assert_eq!(messages("(let [count 1 y] count)\n(fn [a _b] 1)"), vec![
+            "1:6: error: Binding vector needs an even number of forms",
+            "1:7: warning: `count` shadows a builtin",
+            "1:15: warning: Unused binding `y`",
+            "2:6: warning: Unused binding `a`",
+        ]);
+    }
+
+    #[test]
+    fn trusts_namespaces() {
+        assert!(messages("(ns a (:require [b :as bb :refer [f]]))\n(f (bb/g 1) (c.d/h))").is_empty());
+        assert!(messages("(require '[b :refer [f]])\n(f 1)").is_empty());
+    }
+
+    #[test]
+    fn checks_with_open_bindings() {
+        assert_eq!(messages("(with-open [r (reader \"f\") w] (line-seq r))"), vec![
+            "1:12: error: Binding vector needs an even number of forms",
+            "1:28: warning: Unused binding `w`",
+        ]);
+    }
+
+    #[test]
+    fn checks_binding_vars() {
+        assert_eq!(messages("(binding [*out* *err* x 1] (with-out-str (println 1)))"), vec![
+            "1:23: error: Unresolved symbol `x`",
+        ]);
+    }
+
+    #[test]
+    fn reports_if_with_too_many_branches() {
+        let d = lint("(if true 1 2 3)", "");
+        assert_eq!(d.len(), 1);
+        assert_eq!(d[0].severity, Severity::Error);
+        assert_eq!(d[0].to_string(), "1:1: error: if has too many branches (3 forms after the test)");
+    }
+}
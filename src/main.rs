@@ -1,29 +1,84 @@
+extern crate num_bigint;
+extern crate num_rational;
+extern crate num_integer;
+extern crate num_traits;
+extern crate bigdecimal;
+extern crate regex;
+extern crate ctrlc;
+
 use std::io::{stdin,stdout,Write};
-use std::io::prelude::*;
-use std::fs::File;
-use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
 mod node;
 mod parser;
 mod eval;
+mod seq;
+mod number;
+mod string;
+mod re;
+mod print;
+mod pretty;
+mod fmt;
+mod lint;
+mod testing;
+mod ns;
+mod atom;
+mod io;
+mod host;
+mod limits;
+mod future;
+mod chan;
+mod stm;
 
 use parser::{tokenize, parse};
-use eval::{Env, eval};
+use node::Node;
+use eval::{Env, eval, eval_all};
+use print::Printer;
+use host::Capabilities;
+use limits::EvalLimits;
 
 fn main() {
-    let mut env: Env = HashMap::new();
-    let mut initialized = false;
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let env = Env::new();
+    if let Err(e) = options(&mut args, &env) {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    }
+
+    match args.first().map(String::as_str) {
+        Some("fmt")  => std::process::exit(fmt::run(&args[1..])),
+        Some("lint") => std::process::exit(lint::run(&args[1..])),
+        Some("test") => std::process::exit(testing::run(&args[1..])),
+        Some("-e")   => std::process::exit(eval_expr(&args[1..], env)),
+        _ => (),
+    }
+
+    let mut env = env;
+    let lib = load_lib(&mut env);
+    if let Node::Error(_) = lib {
+        println!("{}", lib);
+    }
+    let evaluating = Arc::new(AtomicBool::new(false));
+    interrupt_on_ctrl_c(&env, evaluating.clone());
 
     loop {
-        if !initialized {
-            let std = read_file("lib/lib.clj");
-            eval(&mut parse(&mut tokenize(std.as_ref())), &mut env);
-            initialized = true;
-        }
         let mut input = String::new();
         print!("λ> ");
         let _ = stdout().flush();
-        stdin().read_line(&mut input).expect("");
+        match stdin().read_line(&mut input) {
+            Ok(0) => {
+                println!();
+                std::process::exit(0);
+            },
+            Ok(_) => (),
+            Err(e) => {
+                eprintln!("Couldn't read standard input: {}", e);
+                std::process::exit(1);
+            },
+        }
 
         if let Some('\n') = input.chars().next_back() {
             input.pop();
@@ -33,23 +88,110 @@ fn main() {
         }
 
         if input == ":exit" {
-            println!("{}", "Bye!");
+            println!("Bye!");
             std::process::exit(0);
-        } else if input == ":load" {
-            input = read_file("lib/lib.clj");
         }
 
-        let mut tokens = tokenize(input.as_ref());
-        let mut parsed = parse(&mut tokens);
+        evaluating.store(true, Ordering::SeqCst);
+        let result = if input == ":load" {
+            load_lib(&mut env)
+        } else {
+            let mut tokens = tokenize(input.as_ref());
+            let mut parsed = parse(&mut tokens);
+            eval(&mut parsed, &mut env)
+        };
+        println!("{}", Printer::with_env(true, &env).print(&result));
+        evaluating.store(false, Ordering::SeqCst);
+        // A Ctrl-C that came too late to interrupt anything.
+        env.cancel_handle().reset();
+    }
+}
+
+/// Makes Ctrl-C interrupt the evaluation in progress, keeping everything
+/// defined so far, and only quit when waiting for input.
+fn interrupt_on_ctrl_c(env: &Env, evaluating: Arc<AtomicBool>) {
+    let cancel = env.cancel_handle();
+    let handler = ctrlc::set_handler(move || {
+        if evaluating.load(Ordering::SeqCst) {
+            cancel.cancel();
+        } else {
+            println!();
+            std::process::exit(130);
+        }
+    });
+    if let Err(e) = handler {
+        eprintln!("Couldn't handle Ctrl-C: {}", e);
+    }
+}
+
+/// Takes the options before the command off `args`, limiting what programs
+/// evaluated in `env` may do:
+///
+/// - `--no-host` stops them running commands, reading the environment,
+///   sleeping or exiting.
+/// - `--sandbox` also stops them using files and the standard streams.
+/// - `--fuel=N`, `--max-depth=N`, `--max-size=N` and `--timeout=MS` set the
+///   `EvalLimits` for each top-level form.
+fn options(args: &mut Vec<String>, env: &Env) -> Result<(), String> {
+    let mut capabilities = Capabilities::default();
+    let mut limits = EvalLimits::default();
+    while let Some(arg) = args.first().cloned() {
+        let (name, value) = match arg.find('=') {
+            Some(i) => (&arg[..i], Some(&arg[i + 1..])),
+            None => (&arg[..], None),
+        };
+        match (name, value) {
+            ("--no-host", None) => capabilities.host = false,
+            ("--sandbox", None) => capabilities = Capabilities::sandbox(),
+            ("--fuel", Some(n)) => limits.fuel = Some(number(name, n)?),
+            ("--max-depth", Some(n)) => limits.depth = Some(number(name, n)?),
+            ("--max-size", Some(n)) => limits.size = Some(number(name, n)?),
+            ("--timeout", Some(n)) => limits.timeout = Some(Duration::from_millis(number(name, n)?)),
+            _ if name.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ => break,
+        }
+        args.remove(0);
+    }
+    env.set_capabilities(capabilities);
+    env.set_limits(limits);
+    Ok(())
+}
+
+fn number<T: FromStr>(option: &str, n: &str) -> Result<T, String> {
+    n.parse().map_err(|_| format!("Expected a number for {}", option))
+}
 
-        println!("{}", eval(&mut parsed, &mut env))
+/// `brojure -e expr` evaluates the forms in `expr` with lib.clj loaded and
+/// prints the value of the last unless it is nil, so that
+/// `cat data.csv | brojure -e '...'` works like a Unix filter.
+fn eval_expr(args: &[String], mut env: Env) -> i32 {
+    let src = match args.first() {
+        Some(src) => src,
+        None => {
+            eprintln!("Expected an expression after -e");
+            return 2;
+        },
+    };
+    if let e @ Node::Error(_) = load_lib(&mut env) {
+        eprintln!("{}", e);
+    }
+    match eval_all(src, &mut env) {
+        e @ Node::Error(_) => {
+            eprintln!("{}", e);
+            1
+        },
+        Node::Nil => 0,
+        result => {
+            println!("{}", Printer::with_env(true, &env).print(&result));
+            0
+        },
     }
 }
 
-fn read_file(filename: &str) -> String {
-    let mut f = File::open(filename).expect("File not found");
-    let mut s = String::new();
-    f.read_to_string(&mut s)
-        .expect("Something went wrong reading the file");
-    s
+/// Loads lib/lib.clj, giving the error if it can't be read or evaluated.
+fn load_lib(env: &mut Env) -> Node {
+    match io::read_file("lib/lib.clj") {
+        Ok(src) => ns::load(&src, env),
+        Err(e) => e,
+    }
 }
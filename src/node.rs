@@ -1,73 +1,318 @@
 use std::ops;
 use std::fmt;
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::sync::{Arc, Mutex};
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use bigdecimal::BigDecimal;
+use regex;
+use atom;
+use io;
+use future;
+use chan;
+use stm;
+use eval::{Env, check_size};
+use seq::{Lazy, Seqable};
+use number::{self, Pair};
+use num_traits::ToPrimitive;
+use print::Printer;
 use self::Node::*;
 
 #[derive(Debug, Clone)]
 pub enum Node {
     Int(i64),
+    Big(BigInt),
+    Ratio(BigRational),
+    Decimal(BigDecimal),
     Float(f64),
     Str(String),
+    Char(char),
     Symbol(String),
+    Keyword(String),
+    Regex(regex::Regex),
     List(Vec<Node>),
     Vector(Vec<Node>),
+    /// Entries in the order their keys first came. `get`, `contains?` and
+    /// `assoc` go through them in turn and `assoc` copies them, so each takes
+    /// time in line with the size of the map; `seq::map_of` builds a big one
+    /// from its entries in one go, and comparing two maps indexes one by hash.
+    Map(Vec<(Node, Node)>),
+    /// Items in the order they first came, looked up and compared like the
+    /// keys of a `Map`.
+    Set(Vec<Node>),
     Bool(bool),
-    Lambda { params: Vec<String>, body: Box<Node> },
+    Lambda { name: Option<String>, params: Vec<String>, body: Arc<Node>, env: Env },
+    Builtin(String),
+    Atom(Arc<Mutex<atom::State>>),
+    Stream(Arc<Mutex<io::Handle>>),
+    Future(Arc<future::Task>),
+    Chan(Arc<chan::Channel>),
+    Ref(Arc<stm::Ref>),
+    Cons(Arc<Node>, Arc<Node>),
+    ChunkedSeq { items: Arc<Vec<Node>>, offset: usize, more: Box<Node> },
+    LazySeq(Arc<Mutex<Lazy>>),
     Error(&'static str),
     Nil,
 }
 
 impl fmt::Display for Node {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &Int(n)          => write!(f, "{}", n),
-            &Float(n)        => write!(f, "{}", n),
-            &Str(ref s)      => write!(f, "\"{}\"", s),
-            &Symbol(ref s)   => write!(f, "{}", s),
-            &Bool(b)         => write!(f, "{}", b),
-            &List(ref v)     => write!(f, "List {:?}", v),
-            &Vector(ref v)   => {
-                let _ = write!(f, "[");
-                for (i, n) in v.iter().enumerate() {
-                    if i == v.len() - 1 {
-                        let _ = write!(f, "{}", n);
-                    } else {
-                        let _ = write!(f, "{}, ", n);
-                    }
+        match *self {
+            Int(n)          => write!(f, "{}", n),
+            Big(ref n)      => write!(f, "{}N", n),
+            Ratio(ref n)    => write!(f, "{}", n),
+            Decimal(ref n)  => write!(f, "{}M", n),
+            Float(n) if n.is_nan() => write!(f, "##NaN"),
+            Float(n) if n.is_infinite() => write!(f, "{}", if n > 0.0 { "##Inf" } else { "##-Inf" }),
+            Float(n) if n.fract() == 0.0 => write!(f, "{}.0", n),
+            Float(n)        => write!(f, "{}", n),
+            Str(ref s)      => {
+                let _ = write!(f, "\"");
+                for c in s.chars() {
+                    let _ = match c {
+                        '"'  => write!(f, "\\\""),
+                        '\\' => write!(f, "\\\\"),
+                        '\n' => write!(f, "\\n"),
+                        '\t' => write!(f, "\\t"),
+                        '\r' => write!(f, "\\r"),
+                        _    => write!(f, "{}", c),
+                    };
+                }
+                write!(f, "\"")
+            },
+            Char(c)         => {
+                match c {
+                    '\n' => write!(f, "\\newline"),
+                    ' '  => write!(f, "\\space"),
+                    '\t' => write!(f, "\\tab"),
+                    '\r' => write!(f, "\\return"),
+                    _    => write!(f, "\\{}", c),
                 }
-                write!(f, "]")
             },
-            &Nil             => write!(f, "{}", "nil"),
-            &Lambda{ref params, ref body}
-                             => write!(f, "Lambda params:{:?} body:{}", params, body),
-            &Error(ref s)    => write!(f, "Error: {}", s),
+            Symbol(ref s)   => write!(f, "{}", s),
+            Keyword(ref s)  => write!(f, ":{}", s),
+            Regex(ref r)    => write!(f, "#\"{}\"", r.as_str().replace('"', "\\\"")),
+            Bool(b)         => write!(f, "{}", b),
+            Nil             => write!(f, "nil"),
+            List(_) | Vector(_) | Map(_) | Set(_) | Cons(..) | ChunkedSeq { .. } | LazySeq(_)
+            | Lambda { .. } | Builtin(_) | Atom(_) | Stream(_) | Future(_) | Chan(_) | Ref(_)
+                            => write!(f, "{}", Printer::new(true).print(self)),
+            Error(s)        => write!(f, "Error: {}", s),
         }
     }
 }
 
+/// Lists, vectors and seqs are sequential and equal to each other when
+/// their elements are. Functions and reference types like atoms, refs,
+/// streams, futures and channels are only equal to themselves.
 impl PartialEq for Node {
     fn eq(&self, other: &Node) -> bool {
+        if number::is_number(self) && number::is_number(other) {
+            return number::compare(self, other) == Some(Ordering::Equal);
+        }
+        if is_sequential(self) && is_sequential(other) {
+            return sequential_eq(self, other);
+        }
         match (self, other) {
-            (&Int(n1), &Int(n2))         => n1 == n2,
-            (&Int(n1), &Float(n2))       => n1 as f64 == n2,
-            (&Float(n1), &Float(n2))     => n1 == n2,
-            (&Float(n1), &Int(n2))       => n1 == n2 as f64,
-            (&Str(ref s1), &Str(ref s2)) => s1 == s2,
+            (Str(s1), Str(s2))           => s1 == s2,
+            (&Char(c1), &Char(c2))       => c1 == c2,
+            (Symbol(s1), Symbol(s2))     => s1 == s2,
+            (Keyword(s1), Keyword(s2))   => s1 == s2,
+            (Regex(r1), Regex(r2))       => r1.as_str() == r2.as_str(),
             (&Bool(b1), &Bool(b2))       => b1 == b2,
             (&Nil, &Nil)                 => true,
+            (Map(m1), Map(m2))           => {
+                m1.len() == m2.len() && {
+                    let index = by_hash(m2, |e| &e.0);
+                    m1.iter().all(|(k, v)| {
+                        index.get(&hash_of(k)).is_some_and(|same| same.iter().any(|(k2, v2)| k == k2 && v == v2))
+                    })
+                }
+            },
+            (Set(s1), Set(s2))           => {
+                s1.len() == s2.len() && {
+                    let index = by_hash(s2, |n| n);
+                    s1.iter().all(|n| index.get(&hash_of(n)).is_some_and(|same| same.contains(&n)))
+                }
+            },
+            (Lambda { body: b1, .. }, Lambda { body: b2, .. }) => Arc::ptr_eq(b1, b2),
+            (Builtin(s1), Builtin(s2))   => s1 == s2,
+            (Atom(a1), Atom(a2))         => Arc::ptr_eq(a1, a2),
+            (Stream(s1), Stream(s2))     => Arc::ptr_eq(s1, s2),
+            (Future(f1), Future(f2))     => Arc::ptr_eq(f1, f2),
+            (Chan(c1), Chan(c2))         => Arc::ptr_eq(c1, c2),
+            (Ref(r1), Ref(r2))           => Arc::ptr_eq(r1, r2),
+            (Error(s1), Error(s2))       => s1 == s2,
             _                            => false,
         }
     }
 }
 
+impl Eq for Node {}
+
+fn is_sequential(n: &Node) -> bool {
+    matches!(*n, List(_) | Vector(_) | Cons(..) | ChunkedSeq { .. } | LazySeq(_))
+}
+
+fn sequential_eq(a: &Node, b: &Node) -> bool {
+    match (a, b) {
+        (List(v1), List(v2)) | (List(v1), Vector(v2)) |
+        (Vector(v1), List(v2)) | (Vector(v1), Vector(v2)) => return v1 == v2,
+        _ => (),
+    }
+    let mut s1 = a.seq();
+    let mut s2 = b.seq();
+    let mut len = 0;
+    loop {
+        // Going over a limit fails the evaluation comparing them.
+        if check_size(len).is_err() {
+            return false;
+        }
+        len += 1;
+        match (&s1, &s2) {
+            (&Nil, &Nil)       => return true,
+            (&Nil, _) | (_, &Nil) => return false,
+            (&Error(_), _) | (_, &Error(_)) => return false,
+            _ => (),
+        }
+        if s1.first() != s2.first() {
+            return false;
+        }
+        s1 = s1.next();
+        s2 = s2.next();
+    }
+}
+
+pub fn hash_of(n: &Node) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    n.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// `items` grouped by the hash of their key, so that comparing two maps or
+/// sets looks each entry up instead of going through all of the other's.
+fn by_hash<T, F: Fn(&T) -> &Node>(items: &[T], key: F) -> HashMap<u64, Vec<&T>> {
+    let mut index: HashMap<u64, Vec<&T>> = HashMap::new();
+    for item in items {
+        index.entry(hash_of(key(item))).or_default().push(item);
+    }
+    index
+}
+
+/// Consistent with `PartialEq`: numbers hash by their exact value so `1`,
+/// `1N` and `1.0` collide, sequential collections hash alike, and maps and
+/// sets ignore the order of their entries.
+impl Hash for Node {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match *self {
+            Int(i) => { 0u8.hash(state); i.hash(state) },
+            Big(_) | Ratio(_) | Decimal(_) | Float(_) => {
+                0u8.hash(state);
+                match number::to_exact(self) {
+                    Some(ref r) if r.is_integer() => match r.numer().to_i64() {
+                        Some(i) => i.hash(state),
+                        None    => r.numer().hash(state),
+                    },
+                    Some(r) => r.hash(state),
+                    None    => number::to_f64(self).to_bits().hash(state),
+                }
+            },
+            List(_) | Vector(_) | Cons(..) | ChunkedSeq { .. } | LazySeq(_) => {
+                1u8.hash(state);
+                let mut s = self.seq();
+                let mut len = 0;
+                while s != Nil {
+                    if let Err(e) = check_size(len) {
+                        s = e;
+                    }
+                    if let Error(e) = s {
+                        e.hash(state);
+                        return;
+                    }
+                    len += 1;
+                    s.first().hash(state);
+                    s = s.next();
+                }
+            },
+            Str(ref s)     => { 2u8.hash(state); s.hash(state) },
+            Symbol(ref s)  => { 3u8.hash(state); s.hash(state) },
+            Keyword(ref s) => { 4u8.hash(state); s.hash(state) },
+            Bool(b)        => { 5u8.hash(state); b.hash(state) },
+            Nil            => 6u8.hash(state),
+            Map(ref m)     => {
+                7u8.hash(state);
+                m.iter()
+                    .fold(0u64, |acc, (k, v)| acc.wrapping_add(hash_of(k) ^ hash_of(v).rotate_left(1)))
+                    .hash(state)
+            },
+            Set(ref v)     => {
+                8u8.hash(state);
+                v.iter().fold(0u64, |acc, n| acc.wrapping_add(hash_of(n))).hash(state)
+            },
+            Lambda { ref body, .. } => { 9u8.hash(state); (&**body as *const Node).hash(state) },
+            Builtin(ref s) => { 10u8.hash(state); s.hash(state) },
+            Error(s)       => { 11u8.hash(state); s.hash(state) },
+            Char(c)        => { 12u8.hash(state); c.hash(state) },
+            Regex(ref r)   => { 13u8.hash(state); r.as_str().hash(state) },
+            Atom(ref a)    => { 14u8.hash(state); (&**a as *const Mutex<atom::State>).hash(state) },
+            Stream(ref s)  => { 15u8.hash(state); (&**s as *const Mutex<io::Handle>).hash(state) },
+            Future(ref t)  => { 16u8.hash(state); (&**t as *const future::Task).hash(state) },
+            Chan(ref c)    => { 17u8.hash(state); (&**c as *const chan::Channel).hash(state) },
+            Ref(ref r)     => { 18u8.hash(state); (&**r as *const stm::Ref).hash(state) },
+        }
+    }
+}
+
+/// Numbers compare by value, strings, symbols and keywords lexically, and
+/// vectors by length and then element by element. `nil` sorts first.
+impl PartialOrd for Node {
+    fn partial_cmp(&self, other: &Node) -> Option<Ordering> {
+        if number::is_number(self) && number::is_number(other) {
+            return number::compare(self, other);
+        }
+        match (self, other) {
+            (&Nil, &Nil)                 => Some(Ordering::Equal),
+            (&Nil, _)                    => Some(Ordering::Less),
+            (_, &Nil)                    => Some(Ordering::Greater),
+            (Str(s1), Str(s2))           => Some(s1.cmp(s2)),
+            (&Char(c1), &Char(c2))       => Some(c1.cmp(&c2)),
+            (Symbol(s1), Symbol(s2))     => Some(s1.cmp(s2)),
+            (Keyword(s1), Keyword(s2))   => Some(s1.cmp(s2)),
+            (&Bool(b1), &Bool(b2))       => Some(b1.cmp(&b2)),
+            (Vector(v1), Vector(v2))     => {
+                if v1.len() != v2.len() {
+                    return Some(v1.len().cmp(&v2.len()));
+                }
+                for (n1, n2) in v1.iter().zip(v2.iter()) {
+                    match n1.partial_cmp(n2) {
+                        Some(Ordering::Equal) => (),
+                        other                 => return other,
+                    }
+                }
+                Some(Ordering::Equal)
+            },
+            _                            => None,
+        }
+    }
+}
+
+/// Integer results that overflow an `i64` are promoted to `Big`.
 impl ops::Add for Node {
     type Output = Node;
     fn add(self, other: Node) -> Node {
-        match (self, other) {
-            (Int(n1), Int(n2)) => Int(n1 + n2),
-            (Int(n1), Float(n2)) => Float(n1 as f64 + n2),
-            (Float(n1), Float(n2)) => Float(n1 + n2),
-            (Float(n1), Int(n2)) => Float(n1 + n2 as f64),
-            _ => Error("Can only add numbers"),
+        match number::coerce(self, other) {
+            Some(Pair::Ints(n1, n2)) => match n1.checked_add(n2) {
+                Some(n) => Int(n),
+                None    => Big(BigInt::from(n1) + n2),
+            },
+            Some(Pair::Bigs(n1, n2)) => Big(n1 + n2),
+            Some(Pair::Ratios(n1, n2)) => number::ratio(n1 + n2),
+            Some(Pair::Decimals(n1, n2)) => Decimal(n1 + n2),
+            Some(Pair::Floats(n1, n2)) => Float(n1 + n2),
+            None => Error("Can only add numbers"),
         }
     }
 }
@@ -75,12 +320,16 @@ impl ops::Add for Node {
 impl ops::Sub for Node {
     type Output = Node;
     fn sub(self, other: Node) -> Node {
-        match (self, other) {
-            (Int(n1), Int(n2)) => Int(n1 - n2),
-            (Int(n1), Float(n2)) => Float(n1 as f64 - n2),
-            (Float(n1), Float(n2)) => Float(n1 - n2),
-            (Float(n1), Int(n2)) => Float(n1 - n2 as f64),
-            _ => Error("Can only subtract numbers"),
+        match number::coerce(self, other) {
+            Some(Pair::Ints(n1, n2)) => match n1.checked_sub(n2) {
+                Some(n) => Int(n),
+                None    => Big(BigInt::from(n1) - n2),
+            },
+            Some(Pair::Bigs(n1, n2)) => Big(n1 - n2),
+            Some(Pair::Ratios(n1, n2)) => number::ratio(n1 - n2),
+            Some(Pair::Decimals(n1, n2)) => Decimal(n1 - n2),
+            Some(Pair::Floats(n1, n2)) => Float(n1 - n2),
+            None => Error("Can only subtract numbers"),
         }
     }
 }
@@ -88,33 +337,128 @@ impl ops::Sub for Node {
 impl ops::Mul for Node {
     type Output = Node;
     fn mul(self, other: Node) -> Node {
-        match (self, other) {
-            (Int(n1), Int(n2)) => Int(n1 * n2),
-            (Int(n1), Float(n2)) => Float(n1 as f64 * n2),
-            (Float(n1), Float(n2)) => Float(n1 * n2),
-            (Float(n1), Int(n2)) => Float(n1 * n2 as f64),
-            _ => Error("Can only multiply numbers"),
+        match number::coerce(self, other) {
+            Some(Pair::Ints(n1, n2)) => match n1.checked_mul(n2) {
+                Some(n) => Int(n),
+                None    => Big(BigInt::from(n1) * n2),
+            },
+            Some(Pair::Bigs(n1, n2)) => Big(n1 * n2),
+            Some(Pair::Ratios(n1, n2)) => number::ratio(n1 * n2),
+            Some(Pair::Decimals(n1, n2)) => Decimal(n1 * n2),
+            Some(Pair::Floats(n1, n2)) => Float(n1 * n2),
+            None => Error("Can only multiply numbers"),
         }
     }
 }
 
+/// Division is exact: integers that don't divide evenly give a `Ratio`.
 impl ops::Div for Node {
     type Output = Node;
     fn div(self, other: Node) -> Node {
-        match (self, other) {
-            (Int(n1), Int(n2)) => {
-                let n1 = n1 as f64;
-                let n2 = n2 as f64;
-                if n1 % n2 == 0.0 {
-                    Int(n1 as i64 / n2 as i64)
-                } else {
-                    Float(n1 / n2)
-                }
-            },
-            (Int(n1), Float(n2)) => Float(n1 as f64 / n2),
-            (Float(n1), Float(n2)) => Float(n1 / n2),
-            (Float(n1), Int(n2)) => Float(n1 / n2 as f64),
-            _ => Error("Can only divide numbers"),
-        }
+        number::divide(self, other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use super::{Node, hash_of};
+    use super::Node::*;
+    use parser::{tokenize, parse};
+
+    fn read(src: &str) -> Node {
+        parse(&mut tokenize(src))
+    }
+
+    #[test]
+    fn adds_with_promotion() {
+        assert_eq!(Int(1) + Int(2), Int(3));
+        assert_eq!(format!("{}", Int(i64::MAX) + Int(1)), "9223372036854775808N");
+        assert_eq!(format!("{}", read("1/2") + read("1/2")), "1");
+        assert_eq!(Int(1) + Float(0.5), Float(1.5));
+        assert_eq!(format!("{}", read("1.5M") + Int(1)), "2.5M");
+        assert_eq!(Int(1) + Str("a".to_owned()), Error("Can only add numbers"));
+    }
+
+    #[test]
+    fn subtracts_with_promotion() {
+        assert_eq!(Int(5) - Int(7), Int(-2));
+        assert_eq!(format!("{}", Int(i64::MIN) - Int(1)), "-9223372036854775809N");
+        assert_eq!(format!("{}", read("1/2") - read("1/3")), "1/6");
+        assert_eq!(Nil - Int(1), Error("Can only subtract numbers"));
+    }
+
+    #[test]
+    fn multiplies_with_promotion() {
+        assert_eq!(Int(6) * Int(7), Int(42));
+        assert_eq!(format!("{}", Int(i64::MAX) * Int(2)), "18446744073709551614N");
+        assert_eq!(Float(1.5) * Int(2), Float(3.0));
+        assert_eq!(Bool(true) * Int(2), Error("Can only multiply numbers"));
+    }
+
+    #[test]
+    fn divides_exactly() {
+        assert_eq!(Int(6) / Int(3), Int(2));
+        assert_eq!(format!("{}", Int(1) / Int(3)), "1/3");
+        assert_eq!(Float(1.0) / Int(4), Float(0.25));
+        assert_eq!(Int(1) / Int(0), Error("Divide by zero"));
+    }
+
+    #[test]
+    fn compares_for_equality() {
+        assert_eq!(Int(1), Float(1.0));
+        assert_eq!(read("1/2"), Float(0.5));
+        assert!(read("1/3") != Float(1.0 / 3.0));
+        assert!(read("9007199254740993N") != Float(9007199254740992.0));
+        assert_eq!(read("[1 2]"), read("(1 2)"));
+        assert_eq!(read("{:a 1 :b 2}"), read("{:b 2 :a 1}"));
+        assert_eq!(read("#{1 2}"), read("#{2 1}"));
+        assert_eq!(read("{[1] {:a 1.0} 2 #{3}}"), read("{2 #{3.0} (1) {:a 1}}"));
+        assert!(read("{:a 1 :b 2}") != read("{:a 1 :c 2}"));
+        assert!(read("{:a 1 :b 2}") != read("{:a 1 :b 3}"));
+        assert!(read("#{1 2}") != read("#{1 3}"));
+        assert_eq!(read("#\"a+\""), read("#\"a+\""));
+        assert!(Str("1".to_owned()) != Int(1));
+        assert!(read("[1 2]") != read("[1 2 3]"));
+        assert!(Keyword("a".to_owned()) != Symbol("a".to_owned()));
+    }
+
+    #[test]
+    fn hashes_consistently_with_equality() {
+        assert_eq!(hash_of(&Int(1)), hash_of(&Float(1.0)));
+        assert_eq!(hash_of(&read("1/2")), hash_of(&Float(0.5)));
+        assert_eq!(hash_of(&read("9007199254740992N")), hash_of(&Float(9007199254740992.0)));
+        assert!(hash_of(&read("9007199254740993N")) != hash_of(&read("9007199254740992N")));
+        assert!(hash_of(&read("36893488147419103232N")) != hash_of(&read("36893488147419103233N")));
+        assert_eq!(hash_of(&read("[1 2]")), hash_of(&read("(1 2)")));
+        assert_eq!(hash_of(&read("{:a 1 :b 2}")), hash_of(&read("{:b 2 :a 1}")));
+        assert!(hash_of(&Str("a".to_owned())) != hash_of(&Keyword("a".to_owned())));
+    }
+
+    #[test]
+    fn orders_values() {
+        assert_eq!(Int(1).partial_cmp(&Float(1.5)), Some(Ordering::Less));
+        assert_eq!(Str("b".to_owned()).partial_cmp(&Str("a".to_owned())), Some(Ordering::Greater));
+        assert_eq!(Nil.partial_cmp(&Int(0)), Some(Ordering::Less));
+        assert_eq!(read("[1 2]").partial_cmp(&read("[1 3]")), Some(Ordering::Less));
+        assert_eq!(read("[9]").partial_cmp(&read("[1 1]")), Some(Ordering::Less));
+        assert_eq!(Int(1).partial_cmp(&Str("a".to_owned())), None);
+    }
+
+    #[test]
+    fn displays_values() {
+        assert_eq!(format!("{}", read("[1 \"a\\n\" \\b :c nil]")), "[1 \"a\\n\" \\b :c nil]");
+        assert_eq!(format!("{}", read("{:a '(1)}")), "{:a '(1)}");
+        assert_eq!(format!("{}", read("#\"a\\\"b\"")), "#\"a\\\"b\"");
+    }
+
+    #[test]
+    fn displays_floats_as_floats() {
+        assert_eq!(format!("{}", read("[1.0 -2.0 0.5 1e3]")), "[1.0 -2.0 0.5 1000.0]");
+        assert_eq!(format!("{}", Float(f64::INFINITY)), "##Inf");
+        assert_eq!(format!("{}", Float(f64::NEG_INFINITY)), "##-Inf");
+        assert_eq!(format!("{}", Float(f64::NAN)), "##NaN");
+        assert_eq!(read("[##Inf ##-Inf]"), Vector(vec![Float(f64::INFINITY), Float(f64::NEG_INFINITY)]));
+        assert_eq!(format!("{}", read("##NaN")), "##NaN");
     }
 }
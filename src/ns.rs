@@ -0,0 +1,631 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::mem;
+use std::path::PathBuf;
+use node::Node;
+use node::Node::{Str, Symbol, Keyword, List, Vector, Builtin, Nil, Error};
+use eval::{Env, eval, eval_args, eval_all, is_builtin};
+use io;
+use host::Capabilities;
+use limits::{EvalLimits, Usage};
+
+/// Where lib.clj lives. Its definitions can be used from every namespace
+/// without being required.
+pub const CORE: &str = "brojure.core";
+
+/// The namespace the REPL and files start out in.
+pub const USER: &str = "user";
+
+/// String functions, which can also be required as `brojure.string`.
+const STRING_FNS: [&str; 12] = ["split", "split-lines", "join", "trim", "upper-case", "lower-case",
+                                "starts-with?", "ends-with?", "includes?", "replace", "index-of", "reverse"];
+
+#[derive(Default)]
+pub struct Namespace {
+    vars: HashMap<String, Node>,
+    /// Other namespaces by the names given with `:as`.
+    aliases: HashMap<String, String>,
+    /// Names brought in with `:refer`, and the namespace they come from.
+    refers: HashMap<String, String>,
+    /// Vars defined `^:dynamic`, which `binding` can give values per thread.
+    dynamic: HashSet<String>,
+}
+
+/// Every namespace, shared by all environments.
+#[derive(Default)]
+pub struct Registry {
+    namespaces: HashMap<String, Namespace>,
+    /// Namespaces that have been loaded, or declared with `ns`, so requiring
+    /// them again does nothing.
+    loaded: HashSet<String>,
+    /// Namespaces in the middle of being loaded, outermost first.
+    loading: Vec<String>,
+    /// Directories to look for namespaces in, `load_path()` if not set.
+    path: Option<Vec<PathBuf>>,
+    capabilities: Capabilities,
+    limits: EvalLimits,
+    usage: Usage,
+}
+
+/// `a/b` names `b` in the namespace or alias `a`, while `/` alone is just
+/// division.
+pub fn split_qualified(name: &str) -> Option<(&str, &str)> {
+    match name.find('/') {
+        Some(i) if i > 0 && i < name.len() - 1 => Some((&name[..i], &name[i + 1..])),
+        _ => None,
+    }
+}
+
+/// Namespaces implemented in Rust, which never need loading.
+fn is_builtin_namespace(ns: &str) -> bool {
+    ns == CORE || ns == "brojure.string"
+}
+
+impl Registry {
+    fn namespace(&mut self, ns: &str) -> &mut Namespace {
+        self.namespaces.entry(ns.to_owned()).or_default()
+    }
+
+    pub fn define(&mut self, ns: &str, name: String, value: Node) {
+        self.namespace(ns).vars.insert(name, value);
+    }
+
+    /// A var in a namespace, including the builtins of the builtin ones.
+    fn var(&self, ns: &str, name: &str) -> Option<Node> {
+        if let Some(n) = self.namespaces.get(ns).and_then(|n| n.vars.get(name)) {
+            return Some(n.clone());
+        }
+        if ns == CORE {
+            if let Some(s) = io::standard_stream(name) {
+                return Some(s);
+            }
+        }
+        let builtin = match ns {
+            CORE => is_builtin(name),
+            "brojure.string" => STRING_FNS.contains(&name),
+            _ => false,
+        };
+        if builtin { Some(Builtin(name.to_owned())) } else { None }
+    }
+
+    /// Looks `name` up as seen from `ns`: qualified names in the namespace
+    /// or alias they name, anything else in `ns` itself, then in what it
+    /// refers to, then in `brojure.core`.
+    pub fn resolve(&self, ns: &str, name: &str) -> Option<Node> {
+        if let Some(value) = self.bound(ns, name) {
+            return Some(value);
+        }
+        let current = self.namespaces.get(ns);
+        if let Some((prefix, name)) = split_qualified(name) {
+            let target = current.and_then(|n| n.aliases.get(prefix)).map_or(prefix, String::as_str);
+            return self.var(target, name);
+        }
+        if let Some(current) = current {
+            if let Some(n) = current.vars.get(name) {
+                return Some(n.clone());
+            }
+            if let Some(from) = current.refers.get(name) {
+                return self.var(from, name);
+            }
+        }
+        self.core(name)
+    }
+
+    /// A var defined in `brojure.core`, leaving out the builtins.
+    fn core(&self, name: &str) -> Option<Node> {
+        self.namespaces.get(CORE).and_then(|n| n.vars.get(name)).cloned().or_else(|| io::standard_stream(name))
+    }
+
+    /// The namespace and unqualified name of the var `resolve` would find.
+    fn home(&self, ns: &str, name: &str) -> Option<(String, String)> {
+        let current = self.namespaces.get(ns);
+        if let Some((prefix, name)) = split_qualified(name) {
+            let target = current.and_then(|n| n.aliases.get(prefix)).map_or(prefix, String::as_str);
+            return self.var(target, name).map(|_| (target.to_owned(), name.to_owned()));
+        }
+        if let Some(current) = current {
+            if current.vars.contains_key(name) {
+                return Some((ns.to_owned(), name.to_owned()));
+            }
+            if let Some(from) = current.refers.get(name) {
+                return Some((from.clone(), name.to_owned()));
+            }
+        }
+        self.core(name).map(|_| (CORE.to_owned(), name.to_owned()))
+    }
+
+    /// Marks a var as `^:dynamic` or not, as it was last defined.
+    pub fn set_dynamic(&mut self, ns: &str, name: &str, dynamic: bool) {
+        let vars = &mut self.namespace(ns).dynamic;
+        if dynamic {
+            vars.insert(name.to_owned());
+        } else {
+            vars.remove(name);
+        }
+    }
+
+    /// Whether a var can be bound with `binding`, as the standard streams
+    /// always can.
+    fn is_dynamic(&self, ns: &str, name: &str) -> bool {
+        self.namespaces.get(ns).is_some_and(|n| n.dynamic.contains(name))
+            || (ns == CORE && io::standard_stream(name).is_some())
+    }
+
+    /// Tells registries apart in the bindings of a thread using several.
+    fn id(&self) -> usize {
+        self as *const Registry as usize
+    }
+
+    /// Identifies the var `resolve` would find, for `binding`.
+    fn key(&self, ns: &str, name: &str) -> Option<Key> {
+        self.home(ns, name).map(|(ns, name)| (self.id(), ns, name))
+    }
+
+    /// The value `binding` has given the var on this thread, if any.
+    fn bound(&self, ns: &str, name: &str) -> Option<Node> {
+        if BINDINGS.with(|b| b.borrow().is_empty()) {
+            return None;
+        }
+        let key = self.key(ns, name)?;
+        BINDINGS.with(|b| {
+            b.borrow().iter().rev().flatten().find(|(k, _)| *k == key).map(|(_, value)| value.clone())
+        })
+    }
+
+    /// Sets a var, or removes it for `None`, giving back what it was.
+    fn rebind(&mut self, ns: &str, name: &str, value: Option<Node>) -> Option<Node> {
+        let vars = &mut self.namespace(ns).vars;
+        match value {
+            Some(value) => vars.insert(name.to_owned(), value),
+            None => vars.remove(name),
+        }
+    }
+
+    pub fn set_load_path(&mut self, path: Vec<PathBuf>) {
+        self.path = Some(path);
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    pub fn limits(&self) -> &EvalLimits {
+        &self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: EvalLimits) {
+        self.limits = limits;
+    }
+
+    pub fn usage(&self) -> &Usage {
+        &self.usage
+    }
+
+    /// The file `a.b-c` is loaded from, `a/b_c.clj` under the first
+    /// directory on the load path that has it.
+    fn find(&self, ns: &str) -> Option<PathBuf> {
+        let file = format!("{}.clj", ns.replace('.', "/").replace('-', "_"));
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => load_path(),
+        };
+        path.into_iter().map(|dir| dir.join(&file)).find(|f| f.is_file())
+    }
+}
+
+/// The directories in `BROJURE_PATH`, or the current one if it isn't set.
+pub fn load_path() -> Vec<PathBuf> {
+    match env::var_os("BROJURE_PATH") {
+        Some(p) => env::split_paths(&p).collect(),
+        None => vec![PathBuf::from(".")],
+    }
+}
+
+/// Evaluates source the way a file is loaded, going back to the namespace
+/// `env` was in afterwards.
+pub fn load(src: &str, env: &mut Env) -> Node {
+    let ns = env.ns().to_owned();
+    let result = eval_all(src, env);
+    env.set_ns(&ns);
+    result
+}
+
+/// `(load-file "path")` evaluates a file and returns its last value.
+pub fn load_file(args: &mut [Node], env: &mut Env) -> Node {
+    if let Err(e) = io::allowed(env) {
+        return e;
+    }
+    let path = match eval_args(args, env).pop() {
+        Some(Str(s)) => s,
+        _ => return Error("Expected a string as argument to load-file"),
+    };
+    match io::read_file(&path) {
+        Ok(src) => load(&src, &mut env.top_level()),
+        Err(e) => e,
+    }
+}
+
+/// Loads the file for `ns` unless it is already loaded, failing if it is
+/// still being loaded further up, since it would only ever be half there.
+fn load_namespace(ns: &str, env: &mut Env) -> Node {
+    let registry = env.registry();
+    if registry.read().unwrap().loading.iter().any(|n| n == ns) {
+        return Error("Circular dependency between namespaces");
+    }
+    if is_builtin_namespace(ns) || registry.read().unwrap().loaded.contains(ns) {
+        return Nil;
+    }
+    if let Err(e) = io::allowed(env) {
+        return e;
+    }
+    let file = match registry.read().unwrap().find(ns) {
+        Some(file) => file,
+        None => return Error("Couldn't find namespace on the load path"),
+    };
+    let src = match io::read_file(&file.to_string_lossy()) {
+        Ok(src) => src,
+        Err(e) => return e,
+    };
+    registry.write().unwrap().loading.push(ns.to_owned());
+    let result = load(&src, &mut env.top_level());
+    let mut registry = registry.write().unwrap();
+    registry.loading.pop();
+    // The file's `ns` form marked it loaded, which only holds if the rest
+    // of the file loaded too.
+    if let Error(_) = result {
+        registry.loaded.remove(ns);
+        return result;
+    }
+    registry.loaded.insert(ns.to_owned());
+    Nil
+}
+
+/// `a.b` or `[a.b :as ab :refer [f g]]`, with `:refer :all` for every var.
+fn require_spec(spec: &Node, env: &mut Env) -> Node {
+    let (ns, options) = match *spec {
+        Symbol(ref s) => (s.clone(), &[][..]),
+        Vector(ref v) => match v.first() {
+            Some(Symbol(s)) => (s.clone(), &v[1..]),
+            _ => return Error("Expected a namespace at the start of a require vector"),
+        },
+        _ => return Error("Expected a symbol or vector to require"),
+    };
+    if let e @ Error(_) = load_namespace(&ns, env) {
+        return e;
+    }
+    if options.len() % 2 != 0 {
+        return Error("Expected options to require in pairs");
+    }
+    let registry = env.registry();
+    let mut registry = registry.write().unwrap();
+    let refers: Vec<String> = match options.chunks(2).find(|o| o[0] == Keyword("refer".to_owned())) {
+        Some([_, Keyword(k)]) if k == "all" => match registry.namespaces.get(&ns) {
+            Some(n) => n.vars.keys().cloned().collect(),
+            None => Vec::new(),
+        },
+        Some([_, Vector(names)]) => {
+            let mut refers = Vec::new();
+            for name in names {
+                match *name {
+                    Symbol(ref s) if registry.var(&ns, s).is_some() => refers.push(s.clone()),
+                    Symbol(_) => return Error("Couldn't refer to a name the namespace doesn't define"),
+                    _ => return Error("Expected symbols to refer"),
+                }
+            }
+            refers
+        },
+        Some(_) => return Error("Expected a vector or :all after :refer"),
+        None => Vec::new(),
+    };
+    let current = registry.namespace(env.ns());
+    for option in options.chunks(2) {
+        match (&option[0], &option[1]) {
+            (Keyword(k), Symbol(alias)) if k == "as" => {
+                current.aliases.insert(alias.clone(), ns.clone());
+            },
+            (Keyword(k), _) if k == "refer" => (),
+            _ => return Error("Unsupported option to require"),
+        }
+    }
+    for name in refers {
+        current.refers.insert(name, ns.clone());
+    }
+    Nil
+}
+
+/// `(require 'a.b '[c.d :as cd])` loads namespaces and makes their vars
+/// available in the current one.
+pub fn require(args: &mut [Node], env: &mut Env) -> Node {
+    for spec in eval_args(args, env) {
+        if let e @ Error(_) = require_spec(&spec, env) {
+            return e;
+        }
+    }
+    Nil
+}
+
+/// `(in-ns 'a.b)` switches to a namespace, creating it if need be.
+pub fn in_ns(args: &mut [Node], env: &mut Env) -> Node {
+    match eval_args(args, env).pop() {
+        Some(Symbol(s)) => {
+            env.set_ns(&s);
+            Nil
+        },
+        _ => Error("Expected a symbol as argument to in-ns"),
+    }
+}
+
+/// `(ns a.b "doc" (:require ...))` switches to a namespace and requires
+/// what it depends on.
+pub fn ns(args: &mut [Node], env: &mut Env) -> Node {
+    let name = match args.first() {
+        Some(Symbol(s)) => s.clone(),
+        _ => return Error("Expected a symbol as first argument to ns"),
+    };
+    env.set_ns(&name);
+    env.registry().write().unwrap().loaded.insert(name);
+    for clause in &args[1..] {
+        match *clause {
+            List(ref items) if items.first() == Some(&Keyword("require".to_owned())) => {
+                for spec in &items[1..] {
+                    if let e @ Error(_) = require_spec(spec, env) {
+                        return e;
+                    }
+                }
+            },
+            Str(_) => (),
+            _ => return Error("Unsupported clause in ns"),
+        }
+    }
+    Nil
+}
+
+/// A var's namespace and name.
+type Var = (String, String);
+/// A var as `binding` sees it: the registry, namespace and name.
+type Key = (usize, String, String);
+type Frame = Vec<(Key, Node)>;
+
+thread_local! {
+    /// The values `binding` gives vars on this thread, innermost last.
+    static BINDINGS: RefCell<Vec<Frame>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The values `binding` has given vars on a thread, to carry over to the
+/// futures and threads it starts.
+pub struct Bindings(Vec<Frame>);
+
+/// This thread's bindings.
+pub fn bindings() -> Bindings {
+    Bindings(BINDINGS.with(|b| b.borrow().clone()))
+}
+
+impl Bindings {
+    /// Runs `f` with these bindings in place of this thread's own.
+    pub fn with<T, F: FnOnce() -> T>(self, f: F) -> T {
+        let outer = BINDINGS.with(|b| mem::replace(&mut *b.borrow_mut(), self.0));
+        let result = f();
+        BINDINGS.with(|b| *b.borrow_mut() = outer);
+        result
+    }
+}
+
+/// The namespace and name of each var in a `binding` or `with-redefs`
+/// vector, along with its new value.
+fn bind_pairs(bindings: Vec<Node>, dynamic_only: bool, env: &mut Env) -> Result<Vec<(Var, Node)>, Node> {
+    if !bindings.len().is_multiple_of(2) {
+        return Err(Error("Expected binding vector to contain an even number of forms"));
+    }
+    let registry = env.registry();
+    let mut values = Vec::new();
+    for pair in bindings.chunks(2) {
+        let home = match pair[0] {
+            // Builtins can be redefined like vars of `brojure.core`.
+            Symbol(ref s) => registry.read().unwrap().home(env.ns(), s)
+                .or_else(|| if is_builtin(s) { Some((CORE.to_owned(), s.clone())) } else { None }),
+            _ => return Err(Error("Expected odd items in binding vector to be symbols")),
+        };
+        let home = match home {
+            Some(home) => home,
+            None => return Err(Error("Can't bind a var that isn't defined")),
+        };
+        if dynamic_only && !registry.read().unwrap().is_dynamic(&home.0, &home.1) {
+            return Err(Error("Can't bind a var that isn't dynamic"));
+        }
+        match eval(&mut pair[1].clone(), env) {
+            e @ Error(_) => return Err(e),
+            value => values.push((home, value)),
+        }
+    }
+    Ok(values)
+}
+
+fn eval_body(args: &mut [Node], env: &mut Env) -> Node {
+    let mut result = Nil;
+    for form in args.iter_mut() {
+        result = eval(form, env);
+    }
+    result
+}
+
+/// `(binding [var value ...] body...)` gives `^:dynamic` vars new values on
+/// this thread while the body is evaluated, and the futures and threads it
+/// starts, then puts the old ones back, even if the body evaluated to an
+/// error.
+pub fn binding(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let values = match args.remove(0) {
+        Vector(v) => bind_pairs(v, true, env),
+        _ => return Error("Expected vector as first argument to binding"),
+    };
+    let values = match values {
+        Ok(values) => values,
+        Err(e) => return e,
+    };
+    let id = env.registry().read().unwrap().id();
+    let frame = values.into_iter().map(|((ns, name), value)| ((id, ns, name), value)).collect();
+    BINDINGS.with(|b| b.borrow_mut().push(frame));
+    let result = eval_body(args, env);
+    BINDINGS.with(|b| b.borrow_mut().pop());
+    result
+}
+
+/// `(set! var value)` changes the value the innermost `binding` of a var on
+/// this thread gave it.
+pub fn set(args: &mut [Node], env: &mut Env) -> Node {
+    let key = match args[0] {
+        Symbol(ref s) => env.registry().read().unwrap().key(env.ns(), s),
+        _ => return Error("Expected a symbol as first argument to set!"),
+    };
+    let key = match key {
+        Some(key) => key,
+        None => return Error("Can't set a var that isn't defined"),
+    };
+    let value = match eval(&mut args[1], env) {
+        e @ Error(_) => return e,
+        value => value,
+    };
+    BINDINGS.with(|b| {
+        match b.borrow_mut().iter_mut().rev().flatten().find(|(k, _)| *k == key) {
+            Some(bound) => {
+                bound.1 = value.clone();
+                value
+            },
+            None => Error("Can't set a var that isn't bound with binding"),
+        }
+    })
+}
+
+/// `(with-redefs [var value ...] body...)` gives vars new values everywhere
+/// while the body is evaluated, as tests do to stub out functions, then
+/// puts the old ones back, even if the body evaluated to an error.
+pub fn with_redefs(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let values = match args.remove(0) {
+        Vector(v) => bind_pairs(v, false, env),
+        _ => return Error("Expected vector as first argument to with-redefs"),
+    };
+    let values = match values {
+        Ok(values) => values,
+        Err(e) => return e,
+    };
+    let registry = env.registry();
+    let old: Vec<Option<Node>> = values.iter()
+        .map(|((ns, name), value)| registry.write().unwrap().rebind(ns, name, Some(value.clone())))
+        .collect();
+    let result = eval_body(args, env);
+    for (((ns, name), _), old) in values.iter().zip(old).rev() {
+        registry.write().unwrap().rebind(ns, name, old);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::thread;
+    use eval::{Env, eval_all};
+    use super::load;
+
+    /// A directory of namespaces, one `(name, source)` per file.
+    fn project(name: &str, files: &[(&str, &str)]) -> Env {
+        let dir = env::temp_dir().join(format!("brojure-ns-{}-{}", name, ::std::process::id()));
+        for &(file, src) in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, src).unwrap();
+        }
+        let env = Env::new();
+        env.registry().write().unwrap().set_load_path(vec![PathBuf::from("/nonexistent"), dir]);
+        env
+    }
+
+    fn run(env: &mut Env, src: &str) -> String {
+        format!("{}", eval_all(src, env))
+    }
+
+    #[test]
+    fn namespaces_keep_their_own_vars() {
+        let mut env = Env::new();
+        assert_eq!(run(&mut env, "(def x 1) (in-ns 'other) (def x 2) x"), "2");
+        assert_eq!(run(&mut env, "[user/x other/x]"), "[1 2]");
+        assert_eq!(run(&mut env, "(in-ns 'user) x"), "1");
+        assert_eq!(run(&mut env, "(defn f [] x) (in-ns 'other) (user/f)"), "1");
+    }
+
+    #[test]
+    fn bindings_belong_to_a_thread_and_a_namespace() {
+        let mut env = Env::new();
+        run(&mut env, "(def ^:dynamic *x* 1) (in-ns 'other) (def ^:dynamic *x* 2) (in-ns 'user)");
+        assert_eq!(run(&mut env, "(binding [*x* 3] [*x* other/*x*])"), "[3 2]");
+        run(&mut env, "(def bound (promise)) (def seen (promise))");
+        let mut other = env.clone();
+        let handle = thread::spawn(move || {
+            other.enter();
+            run(&mut other, "(binding [*x* 4] (deliver bound true) @seen *x*)")
+        });
+        assert_eq!(run(&mut env, "@bound (let [x *x*] (deliver seen true) x)"), "1");
+        assert_eq!(handle.join().unwrap(), "4");
+        assert_eq!(run(&mut env, "[*x* (binding [*x* 5] (set! *x* 6)) *x*]"), "[1 6 1]");
+    }
+
+    #[test]
+    fn core_is_visible_everywhere() {
+        let mut env = Env::new();
+        load("(ns brojure.core) (defn twice [x] (* 2 x))", &mut env);
+        assert_eq!(env.ns(), "user");
+        assert_eq!(run(&mut env, "(ns a.b) [(twice 2) (brojure.core/twice 3) (brojure.core/count [1])]"), "[4 6 1]");
+        assert_eq!(run(&mut env, "(require '[brojure.string :as str]) (str/join \"-\" [1 2])"), "\"1-2\"");
+    }
+
+    #[test]
+    fn requires_from_the_load_path() {
+        let mut env = project("require", &[
+            ("app/util.clj", "(ns app.util) (defn shout [s] (upper-case s)) (def loads 1)"),
+            ("app/core_test.clj", "(ns app.core-test (:require [app.util :as u :refer [shout]])) (def greeting (shout \"hi\"))"),
+        ]);
+        assert_eq!(run(&mut env, "(require 'app.core-test) app.core-test/greeting"), "\"HI\"");
+        assert_eq!(run(&mut env, "(require '[app.util :as util :refer :all]) [(util/shout \"a\") loads]"), "[\"A\" 1]");
+        assert_eq!(run(&mut env, "shout"), "#<fn shout>");
+        assert_eq!(run(&mut env, "(require 'app.missing)"), "Error: Couldn't find namespace on the load path");
+        assert_eq!(run(&mut env, "(require '[app.util :refer [nope]])"),
+                   "Error: Couldn't refer to a name the namespace doesn't define");
+    }
+
+    #[test]
+    fn failed_requires_are_tried_again() {
+        let mut env = project("failing", &[
+            ("broken.clj", "(ns broken) (def x 1) (/ 1 0)"),
+            ("uses_broken.clj", "(ns uses-broken (:require broken))"),
+        ]);
+        assert_eq!(run(&mut env, "(require 'broken)"), "Error: Divide by zero");
+        assert_eq!(run(&mut env, "(require 'broken)"), "Error: Divide by zero");
+        assert_eq!(run(&mut env, "(require 'uses-broken)"), "Error: Divide by zero");
+        assert_eq!(run(&mut env, "(require 'uses-broken)"), "Error: Divide by zero");
+        assert_eq!(env.ns(), "user");
+    }
+
+    #[test]
+    fn detects_circular_dependencies() {
+        let mut env = project("cycle", &[
+            ("a.clj", "(ns a (:require b))"),
+            ("b.clj", "(ns b (:require a))"),
+        ]);
+        assert_eq!(run(&mut env, "(require 'a)"), "Error: Circular dependency between namespaces");
+        assert_eq!(env.ns(), "user");
+    }
+
+    #[test]
+    fn loads_files() {
+        let env = project("load", &[("script.clj", "(ns script) (def x 40) (+ x 2)")]);
+        let path = env::temp_dir().join(format!("brojure-ns-load-{}/script.clj", ::std::process::id()));
+        let mut env = env;
+        assert_eq!(run(&mut env, &format!("(load-file {:?})", path.display().to_string())), "42");
+        assert_eq!(run(&mut env, "[script/x (load-file \"/nonexistent.clj\")]"), "[40 Error: File not found]");
+    }
+}
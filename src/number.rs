@@ -0,0 +1,336 @@
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_integer::Integer;
+use num_traits::{Zero, Signed, ToPrimitive};
+use std::cmp::Ordering;
+use bigdecimal::BigDecimal;
+use node::Node;
+use node::Node::{Int, Float, Big, Ratio, Decimal, Error};
+
+/// Two numbers converted to the wider of their types, in the order
+/// `Int < Big < Ratio < Decimal < Float`.
+pub enum Pair {
+    Ints(i64, i64),
+    Bigs(BigInt, BigInt),
+    Ratios(BigRational, BigRational),
+    Decimals(BigDecimal, BigDecimal),
+    Floats(f64, f64),
+}
+
+fn rank(n: &Node) -> Option<u8> {
+    match *n {
+        Int(_)     => Some(0),
+        Big(_)     => Some(1),
+        Ratio(_)   => Some(2),
+        Decimal(_) => Some(3),
+        Float(_)   => Some(4),
+        _          => None,
+    }
+}
+
+pub fn is_number(n: &Node) -> bool {
+    rank(n).is_some()
+}
+
+fn to_big(n: Node) -> BigInt {
+    match n {
+        Int(i) => BigInt::from(i),
+        Big(b) => b,
+        _      => unreachable!(),
+    }
+}
+
+fn to_ratio(n: Node) -> BigRational {
+    match n {
+        Ratio(r) => r,
+        n        => BigRational::from_integer(to_big(n)),
+    }
+}
+
+fn to_decimal(n: Node) -> BigDecimal {
+    match n {
+        Decimal(d) => d,
+        Ratio(r)   => BigDecimal::from(r.numer().clone()) / BigDecimal::from(r.denom().clone()),
+        n          => BigDecimal::from(to_big(n)),
+    }
+}
+
+pub fn to_f64(n: &Node) -> f64 {
+    match *n {
+        Int(i)         => i as f64,
+        Big(ref b)     => b.to_f64().unwrap_or(f64::NAN),
+        Ratio(ref r)   => r.to_f64().unwrap_or(f64::NAN),
+        Decimal(ref d) => d.to_f64().unwrap_or(f64::NAN),
+        Float(f)       => f,
+        _              => f64::NAN,
+    }
+}
+
+/// The exact value of a number, which every float has too except the
+/// infinities and NaN.
+pub fn to_exact(n: &Node) -> Option<BigRational> {
+    match *n {
+        Int(i)         => Some(BigRational::from_integer(BigInt::from(i))),
+        Big(ref b)     => Some(BigRational::from_integer(b.clone())),
+        Ratio(ref r)   => Some(r.clone()),
+        Decimal(ref d) => {
+            let (digits, scale) = d.as_bigint_and_exponent();
+            let ten = BigInt::from(10);
+            Some(if scale >= 0 {
+                BigRational::new(digits, ten.pow(scale as u32))
+            } else {
+                BigRational::from_integer(digits * ten.pow(-scale as u32))
+            })
+        },
+        Float(f)       => BigRational::from_float(f),
+        _              => None,
+    }
+}
+
+/// Numeric ordering across every pairing of number types; `None` for NaN.
+/// Numbers of different types compare by their exact values, so `1/2` and
+/// `0.5` are equal but `9007199254740993` and `9007199254740992.0` aren't.
+pub fn compare(a: &Node, b: &Node) -> Option<Ordering> {
+    match (a, b) {
+        (&Int(n1), &Int(n2))     => Some(n1.cmp(&n2)),
+        (&Float(n1), &Float(n2)) => n1.partial_cmp(&n2),
+        _ => match (to_exact(a), to_exact(b)) {
+            (Some(n1), Some(n2)) => Some(n1.cmp(&n2)),
+            _                    => to_f64(a).partial_cmp(&to_f64(b)),
+        },
+    }
+}
+
+pub fn coerce(a: Node, b: Node) -> Option<Pair> {
+    let r = match (rank(&a), rank(&b)) {
+        (Some(ra), Some(rb)) => ra.max(rb),
+        _ => return None,
+    };
+    Some(match r {
+        0 => match (a, b) {
+            (Int(a), Int(b)) => Pair::Ints(a, b),
+            _ => unreachable!(),
+        },
+        1 => Pair::Bigs(to_big(a), to_big(b)),
+        2 => Pair::Ratios(to_ratio(a), to_ratio(b)),
+        3 => Pair::Decimals(to_decimal(a), to_decimal(b)),
+        _ => Pair::Floats(to_f64(&a), to_f64(&b)),
+    })
+}
+
+/// Ratios with a denominator of one become integers again.
+pub fn ratio(r: BigRational) -> Node {
+    if r.is_integer() {
+        integer(r.to_integer())
+    } else {
+        Ratio(r)
+    }
+}
+
+/// Demotes a big integer to `Int` when it fits.
+pub fn integer(b: BigInt) -> Node {
+    match b.to_i64() {
+        Some(i) => Int(i),
+        None    => Big(b),
+    }
+}
+
+pub fn parse_ratio(token: &str) -> Option<Node> {
+    let mut parts = token.splitn(2, '/');
+    let numer = parts.next()?.parse::<BigInt>().ok()?;
+    let denom = parts.next()?.parse::<BigInt>().ok()?;
+    if denom.is_zero() {
+        return None;
+    }
+    Some(ratio(BigRational::new(numer, denom)))
+}
+
+pub fn divide(a: Node, b: Node) -> Node {
+    match coerce(a, b) {
+        Some(Pair::Ints(n1, n2)) => {
+            if n2 == 0 {
+                Error("Divide by zero")
+            } else if n1.wrapping_rem(n2) == 0 {
+                match n1.checked_div(n2) {
+                    Some(n) => Int(n),
+                    None    => Big(BigInt::from(n1) / BigInt::from(n2)),
+                }
+            } else {
+                ratio(BigRational::new(BigInt::from(n1), BigInt::from(n2)))
+            }
+        },
+        Some(Pair::Bigs(n1, n2)) => {
+            if n2.is_zero() {
+                Error("Divide by zero")
+            } else {
+                ratio(BigRational::new(n1, n2))
+            }
+        },
+        Some(Pair::Ratios(n1, n2)) => {
+            if n2.is_zero() {
+                Error("Divide by zero")
+            } else {
+                ratio(n1 / n2)
+            }
+        },
+        Some(Pair::Decimals(n1, n2)) => {
+            if n2.is_zero() {
+                Error("Divide by zero")
+            } else {
+                Decimal(n1 / n2)
+            }
+        },
+        Some(Pair::Floats(n1, n2)) => Float(n1 / n2),
+        None => Error("Can only divide numbers"),
+    }
+}
+
+/// Integer division truncated towards zero.
+pub fn quot(a: Node, b: Node) -> Node {
+    match coerce(a, b) {
+        Some(Pair::Ints(_, 0)) => Error("Divide by zero"),
+        Some(Pair::Ints(n1, n2)) => match n1.checked_div(n2) {
+            Some(n) => Int(n),
+            None    => Big(BigInt::from(n1) / BigInt::from(n2)),
+        },
+        Some(Pair::Bigs(n1, n2)) => {
+            if n2.is_zero() { Error("Divide by zero") } else { Big(n1 / n2) }
+        },
+        Some(Pair::Ratios(n1, n2)) => {
+            if n2.is_zero() { Error("Divide by zero") } else { integer((n1 / n2).trunc().to_integer()) }
+        },
+        Some(Pair::Decimals(n1, n2)) => {
+            if n2.is_zero() { Error("Divide by zero") } else { Decimal((n1 / n2).with_scale(0)) }
+        },
+        Some(Pair::Floats(n1, n2)) => Float((n1 / n2).trunc()),
+        None => Error("Expected numbers as arguments to quot"),
+    }
+}
+
+/// Remainder of truncating division; takes the sign of the dividend.
+pub fn rem(a: Node, b: Node) -> Node {
+    match coerce(a, b) {
+        Some(Pair::Ints(_, 0)) => Error("Divide by zero"),
+        Some(Pair::Ints(n1, n2)) => Int(n1.wrapping_rem(n2)),
+        Some(Pair::Bigs(n1, n2)) => {
+            if n2.is_zero() { Error("Divide by zero") } else { Big(n1 % n2) }
+        },
+        Some(Pair::Ratios(n1, n2)) => {
+            if n2.is_zero() { Error("Divide by zero") } else { ratio(n1 % n2) }
+        },
+        Some(Pair::Decimals(n1, n2)) => {
+            if n2.is_zero() { Error("Divide by zero") } else { Decimal(n1 % n2) }
+        },
+        Some(Pair::Floats(n1, n2)) => Float(n1 % n2),
+        None => Error("Expected numbers as arguments to rem"),
+    }
+}
+
+/// Modulus of flooring division; takes the sign of the divisor.
+pub fn modulo(a: Node, b: Node) -> Node {
+    match coerce(a, b) {
+        Some(Pair::Ints(_, 0)) => Error("Divide by zero"),
+        Some(Pair::Ints(n1, n2)) => {
+            let m = n1.wrapping_rem(n2);
+            Int(if m != 0 && (m < 0) != (n2 < 0) { m + n2 } else { m })
+        },
+        Some(Pair::Bigs(n1, n2)) => {
+            if n2.is_zero() { Error("Divide by zero") } else { Big(n1.mod_floor(&n2)) }
+        },
+        Some(Pair::Ratios(n1, n2)) => {
+            if n2.is_zero() {
+                Error("Divide by zero")
+            } else {
+                let m = &n1 % &n2;
+                if !m.is_zero() && m.is_negative() != n2.is_negative() { ratio(m + n2) } else { ratio(m) }
+            }
+        },
+        Some(Pair::Decimals(n1, n2)) => {
+            if n2.is_zero() {
+                Error("Divide by zero")
+            } else {
+                let m = &n1 % &n2;
+                if !m.is_zero() && m.is_negative() != n2.is_negative() { Decimal(m + n2) } else { Decimal(m) }
+            }
+        },
+        Some(Pair::Floats(n1, n2)) => {
+            let m = n1 % n2;
+            Float(if m != 0.0 && (m < 0.0) != (n2 < 0.0) { m + n2 } else { m })
+        },
+        None => Error("Expected numbers as arguments to mod"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use num_bigint::BigInt;
+    use num_rational::BigRational;
+    use node::Node;
+    use node::Node::{Int, Float, Big, Decimal, Error};
+    use super::{compare, divide, integer, modulo, parse_ratio, quot, ratio, rem};
+
+    fn big(s: &str) -> Node {
+        Big(s.parse().unwrap())
+    }
+
+    fn decimal(s: &str) -> Node {
+        Decimal(s.parse().unwrap())
+    }
+
+    /// Printed, which tells the number types apart where `=` doesn't.
+    fn show(n: Node) -> String {
+        format!("{}", n)
+    }
+
+    #[test]
+    fn promotes_on_overflow() {
+        assert_eq!(show(Int(i64::MAX) + Int(1)), "9223372036854775808N");
+        assert_eq!(show(Int(i64::MIN) - Int(1)), "-9223372036854775809N");
+        assert_eq!(show(Int(i64::MAX) * Int(2)), "18446744073709551614N");
+        assert_eq!(show(divide(Int(i64::MIN), Int(-1))), "9223372036854775808N");
+        assert_eq!(show(quot(Int(i64::MIN), Int(-1))), "9223372036854775808N");
+        assert_eq!(show(rem(Int(i64::MIN), Int(-1))), "0");
+        assert_eq!(show(integer(BigInt::from(7))), "7");
+        assert_eq!(show(big("1") + Int(1)), "2N");
+        assert_eq!(show(Int(1) + Float(0.5)), "1.5");
+        assert_eq!(show(Int(1) + decimal("0.5")), "1.5M");
+    }
+
+    #[test]
+    fn normalises_ratios() {
+        assert_eq!(parse_ratio("2/4").map(show), Some("1/2".to_owned()));
+        assert_eq!(parse_ratio("4/2").map(show), Some("2".to_owned()));
+        assert_eq!(parse_ratio("3/-6").map(show), Some("-1/2".to_owned()));
+        assert_eq!(parse_ratio("1/0"), None);
+        assert_eq!(show(ratio(BigRational::from_integer(BigInt::from(3)))), "3");
+        assert_eq!(show(divide(Int(6), Int(4))), "3/2");
+        assert_eq!(show(divide(parse_ratio("1/2").unwrap(), parse_ratio("1/4").unwrap())), "2");
+        assert_eq!(divide(Int(1), Int(0)), Error("Divide by zero"));
+        assert_eq!(divide(Float(1.0), Float(0.0)), Float(f64::INFINITY));
+    }
+
+    #[test]
+    fn quot_rem_and_mod_take_their_signs_like_clojure() {
+        for &(a, b, q, r, m) in &[(7, 2, 3, 1, 1), (-7, 2, -3, -1, 1), (7, -2, -3, 1, -1), (-7, -2, 3, -1, -1)] {
+            assert_eq!(quot(Int(a), Int(b)), Int(q), "(quot {} {})", a, b);
+            assert_eq!(rem(Int(a), Int(b)), Int(r), "(rem {} {})", a, b);
+            assert_eq!(modulo(Int(a), Int(b)), Int(m), "(mod {} {})", a, b);
+            assert_eq!(modulo(Float(a as f64), Float(b as f64)), Float(m as f64), "(mod {}.0 {}.0)", a, b);
+        }
+        assert_eq!(modulo(parse_ratio("-7/2").unwrap(), Int(2)), parse_ratio("1/2").unwrap());
+        assert_eq!(modulo(decimal("-7.5"), Int(2)), decimal("0.5"));
+        assert_eq!(quot(Float(-7.5), Int(2)), Float(-3.0));
+        assert_eq!(modulo(Int(1), Int(0)), Error("Divide by zero"));
+    }
+
+    #[test]
+    fn compares_different_types_by_exact_value() {
+        assert_eq!(compare(&parse_ratio("1/2").unwrap(), &Float(0.5)), Some(Ordering::Equal));
+        assert_eq!(compare(&decimal("0.5"), &parse_ratio("1/2").unwrap()), Some(Ordering::Equal));
+        assert_eq!(compare(&parse_ratio("1/3").unwrap(), &Float(1.0 / 3.0)), Some(Ordering::Greater));
+        assert_eq!(compare(&big("9007199254740993"), &Float(9007199254740992.0)), Some(Ordering::Greater));
+        assert_eq!(compare(&big("9007199254740993"), &Float(f64::INFINITY)), Some(Ordering::Less));
+        assert_eq!(compare(&Int(1), &Float(f64::NAN)), None);
+    }
+}
@@ -1,115 +1,308 @@
+use std::iter::Peekable;
+use std::str::Chars;
+use num_bigint::BigInt;
+use bigdecimal::BigDecimal;
 use node::Node;
-use node::Node::{Int, Float, Str, Bool, Symbol, List, Vector, Nil, Error};
+use number;
+use re;
+use node::Node::{Int, Big, Decimal, Float, Str, Char, Bool, Symbol, Keyword, List, Vector, Map, Set, Nil, Error};
 
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || c == ',' || "()[]{}\";".contains(c)
+}
+
+/// Splits source into tokens. String literals are kept whole, including
+/// their quotes and escapes, and `;` comments are dropped.
 pub fn tokenize(s: &str) -> Vec<String> {
-    s
-        .replace("(", " ( ")
-        .replace(")", " ) ")
-        .replace("[", " [ ")
-        .replace("]", " ] ")
-        .split_whitespace()
-        .map(|s| s.to_owned())
-        .collect()
+    lex(s, false).into_iter().map(|(t, _)| t).collect()
 }
 
-pub fn parse(tokens: &mut Vec<String>) -> Node {
-    if tokens.len() == 0 {
-        Error("Unexpected EOF")
-    } else {
-        let mut stack = Vec::new();
+/// Like `tokenize`, with the position each token starts at.
+pub fn tokenize_with_positions(s: &str) -> Vec<(String, Pos)> {
+    lex(s, false)
+}
 
-        while tokens.len() > 0 {
-            let t = tokens.remove(0);
+/// Like `tokenize`, but keeps each comment as a token starting with `;`,
+/// and line breaks and commas as tokens of their own, for tools that work
+/// on source.
+pub fn tokenize_with_trivia(s: &str) -> Vec<String> {
+    lex(s, true).into_iter().map(|(t, _)| t).collect()
+}
 
-            match t.as_ref() {
-                "(" => {
-                    stack.push(Vec::new());
-                },
-                ")" => {
-                    match stack.pop() {
-                        Some(top) => {
-                            if stack.len() > 0 {
-                                match stack.last_mut() {
-                                    Some(prev) => prev.push(List(top)),
-                                    None       => return Error("Couldn't parse"),
-                                }
-                            } else {
-                                stack.push(top);
-                            }
-                        },
-                        None => return Error("Couldn't parse"),
+/// A line and column in source, both counting from one.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Pos {
+    pub line: usize,
+    pub col: usize,
+}
+
+/// Characters of source along with the position of the next one.
+struct Source<'a> {
+    chars: Peekable<Chars<'a>>,
+    pos: Pos,
+}
+
+impl<'a> Iterator for Source<'a> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.pos = Pos { line: self.pos.line + 1, col: 1 };
+        } else {
+            self.pos.col += 1;
+        }
+        Some(c)
+    }
+}
+
+impl<'a> Source<'a> {
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek()
+    }
+}
+
+fn lex(s: &str, trivia: bool) -> Vec<(String, Pos)> {
+    let mut tokens = Vec::new();
+    let mut chars = Source { chars: s.chars().peekable(), pos: Pos { line: 1, col: 1 } };
+
+    loop {
+        let pos = chars.pos;
+        let c = match chars.next() {
+            Some(c) => c,
+            None    => break,
+        };
+        match c {
+            '\n' | ',' if trivia => tokens.push((c.to_string(), pos)),
+            c if c.is_whitespace() || c == ',' => (),
+            ';' => {
+                let mut comment = String::from(";");
+                while let Some(&c) = chars.peek() {
+                    if c == '\n' {
+                        break;
                     }
-                },
-                "[" => {
-                    stack.push(vec![Bool(true)]);
-                },
-                "]" => {
-                    match stack.pop() {
-                        Some(mut top) => {
-                            if stack.len() > 0 {
-                                match stack.last_mut() {
-                                    Some(prev) => {
-                                        top.remove(0);
-                                        prev.push(Vector(top))
-                                    },
-                                    None       => return Error("Couldn't parse"),
-                                }
-                            } else {
-                                stack.push(top);
-                            }
-                        },
-                        None => return Error("Couldn't parse"),
+                    comment.push(c);
+                    chars.next();
+                }
+                if trivia {
+                    tokens.push((comment.trim_end().to_owned(), pos));
+                }
+            },
+            '(' | ')' | '[' | ']' | '{' | '}' | '\'' | '@' | '^' => tokens.push((c.to_string(), pos)),
+            '#' if chars.peek() == Some(&'{') => {
+                chars.next();
+                tokens.push(("#{".to_owned(), pos));
+            },
+            '#' if chars.peek() == Some(&'"') => {
+                chars.next();
+                tokens.push((read_string(&mut chars, "#\""), pos));
+            },
+            '"' => tokens.push((read_string(&mut chars, "\""), pos)),
+            '\\' => {
+                let mut token = String::from("\\");
+                if let Some(c) = chars.next() {
+                    token.push(c);
+                }
+                while let Some(&c) = chars.peek() {
+                    if is_delimiter(c) {
+                        break;
                     }
-                },
-                _ => {
-                    if stack.len() > 0 {
-                        match stack.last_mut() {
-                            Some(prev) => prev.push(atom(&t)),
-                            None       => return Error("Couldn't parse"),
-                        }
-                    } else {
-                        return atom(&t);
+                    token.push(c);
+                    chars.next();
+                }
+                tokens.push((token, pos));
+            },
+            _ => {
+                let mut token = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if is_delimiter(c) {
+                        break;
                     }
-                },
+                    token.push(c);
+                    chars.next();
+                }
+                tokens.push((token, pos));
+            },
+        }
+    }
+    tokens
+}
 
-            }
+/// Reads up to and including the closing quote, keeping escapes as they are.
+fn read_string<I: Iterator<Item = char>>(chars: &mut I, open: &str) -> String {
+    let mut token = String::from(open);
+    while let Some(c) = chars.next() {
+        token.push(c);
+        match c {
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    token.push(escaped);
+                }
+            },
+            '"' => break,
+            _ => (),
         }
-        if stack.len() == 1 {
-            let mut top = stack.pop().unwrap();
-            match top[0] {
-                Bool(_)   => {
-                    top.remove(0);
-                    Vector(top)
+    }
+    token
+}
+
+/// Parses the first form in `tokens`, leaving any following forms in place.
+pub fn parse(tokens: &mut Vec<String>) -> Node {
+    let mut spanned = tokens.drain(..).map(|t| (t, Pos::default())).collect();
+    let (node, _) = parse_spanned(&mut spanned);
+    tokens.extend(spanned.into_iter().map(|(t, _)| t));
+    node
+}
+
+/// Where a form starts, along with the spans of the forms inside it in
+/// source order, so a map's spans alternate between keys and values.
+#[derive(Debug, Clone, Default)]
+pub struct Span {
+    pub pos: Pos,
+    pub children: Vec<Span>,
+}
+
+/// Like `parse`, along with the span of the form.
+pub fn parse_spanned(tokens: &mut Vec<(String, Pos)>) -> (Node, Span) {
+    if tokens.is_empty() {
+        return (Error("Unexpected EOF"), Span::default());
+    }
+    let mut stack: Vec<(String, Vec<Node>, Span)> = Vec::new();
+
+    while !tokens.is_empty() {
+        let (t, pos) = tokens.remove(0);
+
+        let (mut node, mut span) = match t.as_ref() {
+            "(" | "[" | "{" | "#{" | "'" | "@" | "^" => {
+                stack.push((t, Vec::new(), Span { pos, children: Vec::new() }));
+                continue;
+            },
+            ")" | "]" | "}" => {
+                let (open, items, span) = match stack.pop() {
+                    Some(top) => top,
+                    None      => return (Error("Couldn't parse"), Span { pos, children: Vec::new() }),
+                };
+                let node = match (open.as_ref(), t.as_ref()) {
+                    ("(", ")")  => List(items),
+                    ("[", "]")  => Vector(items),
+                    ("{", "}")  => {
+                        if items.len() % 2 != 0 {
+                            return (Error("Map literal must contain an even number of forms"), span);
+                        }
+                        let mut items = items.into_iter();
+                        let mut m = Vec::new();
+                        while let (Some(k), Some(v)) = (items.next(), items.next()) {
+                            m.push((k, v));
+                        }
+                        Map(m)
+                    },
+                    ("#{", "}") => Set(items),
+                    _           => return (Error("Couldn't parse"), span),
+                };
+                (node, span)
+            },
+            _ => (atom(&t), Span { pos, children: Vec::new() }),
+        };
+        // `'form` reads as `(quote form)`, `@form` as `(deref form)` and
+        // `^meta form` as `(^ meta form)` once the form is complete.
+        loop {
+            match stack.last() {
+                Some(top) if reader_macro(&top.0).is_some() => {
+                    let (open, _, prefix) = stack.pop().unwrap();
+                    let symbol = Span { pos: prefix.pos, children: Vec::new() };
+                    node = List(vec![Symbol(reader_macro(&open).unwrap().to_owned()), node]);
+                    span = Span { pos: prefix.pos, children: vec![symbol, span] };
+                },
+                Some(top) if top.0 == "^" && !top.1.is_empty() => {
+                    let (_, mut meta, mut prefix) = stack.pop().unwrap();
+                    let symbol = Span { pos: prefix.pos, children: Vec::new() };
+                    node = List(vec![Symbol("^".to_owned()), meta.remove(0), node]);
+                    prefix.children.insert(0, symbol);
+                    prefix.children.push(span);
+                    span = prefix;
                 },
-                _         => List(top),
+                _ => break,
             }
-        } else {
-            return Error("Couldn't parse")
+        }
+        match stack.last_mut() {
+            Some(prev) => {
+                prev.1.push(node);
+                prev.2.children.push(span);
+            },
+            None => return (node, span),
         }
     }
+    (Error("Couldn't parse"), Span::default())
+}
+
+/// The form a prefix token wraps what follows it in.
+fn reader_macro(token: &str) -> Option<&'static str> {
+    match token {
+        "'" => Some("quote"),
+        "@" => Some("deref"),
+        _ => None,
+    }
+}
+
+/// The form `^meta form` was read from, leaving out the metadata.
+pub fn without_meta(n: &Node) -> &Node {
+    match *n {
+        List(ref v) if v.len() == 3 && v[0] == Symbol("^".to_owned()) => without_meta(&v[2]),
+        _ => n,
+    }
+}
+
+/// Whether `^meta form` marks `form` as `^:dynamic`.
+pub fn is_dynamic(n: &Node) -> bool {
+    match *n {
+        List(ref v) if v.len() == 3 && v[0] == Symbol("^".to_owned()) => {
+            let dynamic = Keyword("dynamic".to_owned());
+            let marked = match v[1] {
+                Keyword(_) => v[1] == dynamic,
+                Map(ref m) => m.iter().any(|(k, v)| *k == dynamic && *v == Bool(true)),
+                _ => false,
+            };
+            marked || is_dynamic(&v[2])
+        },
+        _ => false,
+    }
 }
 
 fn atom(token: &str) -> Node {
+    if token.starts_with('"') {
+        return string(token);
+    }
+    if token.starts_with("#\"") {
+        return regex(token);
+    }
+    if token.starts_with('\\') {
+        return character(token);
+    }
     match token.parse::<i64>() {
         Ok(num) => Int(num),
         Err(_) => {
             match token.parse::<f64>() {
-                Ok(num) => Float(num),
+                Ok(num) => {
+                    if token.chars().all(|c| c.is_ascii_digit() || c == '-' || c == '+') {
+                        big(token)
+                    } else {
+                        Float(num)
+                    }
+                },
                 Err(_) => {
                     match token {
                         "true"  => Bool(true),
                         "false" => Bool(false),
                         "nil"   => Nil,
+                        "##Inf"  => Float(f64::INFINITY),
+                        "##-Inf" => Float(f64::NEG_INFINITY),
+                        "##NaN"  => Float(f64::NAN),
                         _       => {
-                            let txt = token.clone();
-                            let first = txt.chars().nth(0).unwrap();
-                            let last = txt.chars().last().unwrap();
-                            if first == '"' && last == '"' {
-                                let txt: String = txt.chars()
-                                    .filter(|c| *c != '"')
-                                    .collect();
-
-                                Str(String::from(txt))
+                            if let Some(n) = number_literal(token) {
+                                return n;
+                            }
+                            if token.starts_with(':') && token.len() > 1 {
+                                Keyword(token[1..].to_owned())
                             } else {
                                 Symbol(token.to_owned())
                             }
@@ -120,3 +313,180 @@ fn atom(token: &str) -> Node {
         },
     }
 }
+
+fn string(token: &str) -> Node {
+    if token.len() < 2 || !token.ends_with('"') {
+        return Error("Unterminated string");
+    }
+    let mut s = String::new();
+    let mut chars = token[1..token.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            s.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n')  => s.push('\n'),
+            Some('t')  => s.push('\t'),
+            Some('r')  => s.push('\r'),
+            Some('"')  => s.push('"'),
+            Some('\\') => s.push('\\'),
+            Some('u')  => {
+                let hex: String = chars.by_ref().take(4).collect();
+                match u32::from_str_radix(&hex, 16).ok().and_then(::std::char::from_u32) {
+                    Some(c) => s.push(c),
+                    None    => return Error("Invalid unicode escape in string"),
+                }
+            },
+            _ => return Error("Unsupported escape character in string"),
+        }
+    }
+    Str(s)
+}
+
+/// Regex literals take their contents verbatim, apart from escaped quotes.
+fn regex(token: &str) -> Node {
+    if token.len() < 3 || !token.ends_with('"') {
+        return Error("Unterminated regular expression");
+    }
+    re::compile(&token[2..token.len() - 1].replace("\\\"", "\""))
+}
+
+/// `\a`, `\newline`, `\u03bb` and the other named characters.
+fn character(token: &str) -> Node {
+    let name = &token[1..];
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => return Char(c),
+        (None, _) => return Error("Expected a character after \\"),
+        _ => (),
+    }
+    match name {
+        "newline"   => Char('\n'),
+        "space"     => Char(' '),
+        "tab"       => Char('\t'),
+        "return"    => Char('\r'),
+        "backspace" => Char('\u{8}'),
+        "formfeed"  => Char('\u{c}'),
+        _ if name.starts_with('u') && name.len() == 5 => {
+            match u32::from_str_radix(&name[1..], 16).ok().and_then(::std::char::from_u32) {
+                Some(c) => Char(c),
+                None    => Error("Invalid unicode character"),
+            }
+        },
+        _ => Error("Unsupported character"),
+    }
+}
+
+fn big(token: &str) -> Node {
+    match token.parse::<BigInt>() {
+        Ok(n)  => Big(n),
+        Err(_) => Error("Invalid number"),
+    }
+}
+
+/// `42N` big integers, `1.5M` decimals and `1/3` ratios.
+fn number_literal(token: &str) -> Option<Node> {
+    let starts_numeric = match token.chars().next() {
+        Some('-') | Some('+') => token.chars().nth(1).is_some_and(|c| c.is_ascii_digit()),
+        Some(c) => c.is_ascii_digit(),
+        None => false,
+    };
+    if !starts_numeric {
+        return None;
+    }
+    if let Some(digits) = token.strip_suffix('N') {
+        return Some(big(digits));
+    }
+    if let Some(digits) = token.strip_suffix('M') {
+        return Some(match digits.parse::<BigDecimal>() {
+            Ok(d)  => Decimal(d),
+            Err(_) => Error("Invalid number"),
+        });
+    }
+    if token.contains('/') {
+        return Some(number::parse_ratio(token).unwrap_or(Error("Invalid number")));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{tokenize, tokenize_with_trivia, tokenize_with_positions, parse, parse_spanned, without_meta, is_dynamic, Pos};
+    use node::Node::{Int, Str, Char, Symbol, Keyword, List, Vector, Map, Set, Nil, Error};
+
+    fn read(src: &str) -> ::node::Node {
+        parse(&mut tokenize(src))
+    }
+
+    #[test]
+    fn tokenizes_brackets_strings_and_comments() {
+        assert_eq!(tokenize("(+ 1 [2, 3]) ; done\n#{:a} \"a b\\\"\" #\"\\d\" \\space 'x"),
+                   vec!["(", "+", "1", "[", "2", "3", "]", ")", "#{", ":a", "}", "\"a b\\\"\"", "#\"\\d\"",
+                        "\\space", "'", "x"]);
+    }
+
+    #[test]
+    fn keeps_trivia_and_positions() {
+        assert_eq!(tokenize_with_trivia("a, b ; c\nd"), vec!["a", ",", "b", "; c", "\n", "d"]);
+        let positions: Vec<Pos> = tokenize_with_positions("(a\n  b)").into_iter().map(|(_, p)| p).collect();
+        assert_eq!(positions, vec![Pos { line: 1, col: 1 }, Pos { line: 1, col: 2 }, Pos { line: 2, col: 3 },
+                                   Pos { line: 2, col: 4 }]);
+    }
+
+    #[test]
+    fn parses_atoms() {
+        assert_eq!(read("42"), Int(42));
+        assert_eq!(read("\"a\\tb\\u00e9\""), Str("a\tb\u{e9}".to_owned()));
+        assert_eq!(read("\\newline"), Char('\n'));
+        assert_eq!(read(":key"), Keyword("key".to_owned()));
+        assert_eq!(read("nil"), Nil);
+        assert_eq!(format!("{}", read("1/2")), "1/2");
+        assert_eq!(format!("{}", read("99999999999999999999")), "99999999999999999999N");
+        assert_eq!(format!("{}", read("1.5M")), "1.5M");
+        assert_eq!(read("\"open"), Error("Unterminated string"));
+        assert_eq!(read("\\bogus"), Error("Unsupported character"));
+    }
+
+    #[test]
+    fn parses_collections_and_quotes() {
+        assert_eq!(read("(f [1] {:a 1} #{2})"), List(vec![
+            Symbol("f".to_owned()),
+            Vector(vec![Int(1)]),
+            Map(vec![(Keyword("a".to_owned()), Int(1))]),
+            Set(vec![Int(2)]),
+        ]));
+        assert_eq!(read("'x"), List(vec![Symbol("quote".to_owned()), Symbol("x".to_owned())]));
+        assert_eq!(read("@x"), List(vec![Symbol("deref".to_owned()), Symbol("x".to_owned())]));
+        let meta = read("(def ^:dynamic 'x 1)");
+        assert_eq!(meta, List(vec![
+            Symbol("def".to_owned()),
+            List(vec![Symbol("^".to_owned()), Keyword("dynamic".to_owned()), read("'x")]),
+            Int(1),
+        ]));
+        if let List(ref v) = meta {
+            assert!(is_dynamic(&v[1]) && !is_dynamic(&read("^{:dynamic false} x")));
+            assert_eq!(*without_meta(&v[1]), read("'x"));
+        }
+        assert_eq!(read("{:a}"), Error("Map literal must contain an even number of forms"));
+        assert_eq!(read("(]"), Error("Couldn't parse"));
+        assert_eq!(read(""), Error("Unexpected EOF"));
+    }
+
+    #[test]
+    fn leaves_following_forms() {
+        let mut tokens = tokenize("1 (2) 3");
+        assert_eq!(parse(&mut tokens), Int(1));
+        assert_eq!(parse(&mut tokens), List(vec![Int(2)]));
+        assert_eq!(tokens, vec!["3"]);
+    }
+
+    #[test]
+    fn spans_point_at_each_form() {
+        let (_, span) = parse_spanned(&mut tokenize_with_positions("(a\n {:k 'v})"));
+        let map = &span.children[1];
+        assert_eq!(map.pos, Pos { line: 2, col: 2 });
+        assert_eq!(map.children[1].pos, Pos { line: 2, col: 6 });
+        assert_eq!(map.children[1].children[1].pos, Pos { line: 2, col: 7 });
+    }
+}
@@ -0,0 +1,452 @@
+use node::Node;
+use node::Node::{Int, Symbol, List, Vector, Map, Set, Cons, ChunkedSeq, LazySeq, Nil, Error};
+use eval::{Env, eval_args};
+use print::{self, Printer, walk_seq};
+
+/// A document in the style of Wadler's "A prettier printer": text joined
+/// by line breaks that a group lays out flat when it fits in the width.
+pub enum Doc {
+    Text(String),
+    /// A space when flat, otherwise a newline up to the current indentation.
+    Line,
+    Nest(usize, Box<Doc>),
+    /// Indents by the column the document starts at.
+    Align(Box<Doc>),
+    Concat(Vec<Doc>),
+    Group(Box<Doc>),
+}
+
+use self::Doc::{Text, Line, Nest, Align, Concat, Group};
+
+pub fn text<S: Into<String>>(s: S) -> Doc {
+    Text(s.into())
+}
+
+pub fn nest(i: usize, doc: Doc) -> Doc {
+    Nest(i, Box::new(doc))
+}
+
+pub fn align(doc: Doc) -> Doc {
+    Align(Box::new(doc))
+}
+
+pub fn group(doc: Doc) -> Doc {
+    Group(Box::new(doc))
+}
+
+/// `docs` separated by `sep`.
+pub fn join(docs: Vec<Doc>, sep: fn() -> Doc) -> Doc {
+    let mut joined = Vec::new();
+    for (i, doc) in docs.into_iter().enumerate() {
+        if i > 0 {
+            joined.push(sep());
+        }
+        joined.push(doc);
+    }
+    Concat(joined)
+}
+
+/// `docs` separated by lines that only break when the next doc doesn't fit,
+/// packing as many on each line as will go.
+pub fn fill(docs: Vec<Doc>) -> Doc {
+    let mut filled = Vec::new();
+    for (i, doc) in docs.into_iter().enumerate() {
+        if i == 0 {
+            filled.push(doc);
+        } else {
+            filled.push(group(Concat(vec![Line, doc])));
+        }
+    }
+    Concat(filled)
+}
+
+/// `docs` filling lines where one atom follows another, and otherwise
+/// separated by lines that break when the whole doesn't fit, so a run of
+/// numbers packs together while collections get lines of their own.
+pub fn fill_atoms(docs: Vec<(Doc, bool)>) -> Doc {
+    let mut filled = Vec::new();
+    let mut after_atom = false;
+    for (i, (doc, atom)) in docs.into_iter().enumerate() {
+        if i == 0 {
+            filled.push(doc);
+        } else if atom && after_atom {
+            filled.push(group(Concat(vec![Line, doc])));
+        } else {
+            filled.push(Line);
+            filled.push(doc);
+        }
+        after_atom = atom;
+    }
+    Concat(filled)
+}
+
+fn is_atom(n: &Node) -> bool {
+    !matches!(*n, List(_) | Vector(_) | Map(_) | Set(_) | Cons(..) | ChunkedSeq { .. } | LazySeq(_))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Flat,
+    Break,
+}
+
+/// Lays `doc` out in `width` columns.
+pub fn render(doc: &Doc, width: usize) -> String {
+    let mut out = String::new();
+    let mut col = 0;
+    let mut stack = vec![(0, Mode::Break, doc)];
+
+    while let Some((indent, mode, doc)) = stack.pop() {
+        match *doc {
+            Text(ref s) => {
+                out.push_str(s);
+                col = match s.rfind('\n') {
+                    Some(i) => s[i + 1..].chars().count(),
+                    None    => col + s.chars().count(),
+                };
+            },
+            Line if mode == Mode::Flat => {
+                out.push(' ');
+                col += 1;
+            },
+            Line => {
+                out.push('\n');
+                out.push_str(&" ".repeat(indent));
+                col = indent;
+            },
+            Nest(i, ref doc) => stack.push((indent + i, mode, doc)),
+            Align(ref doc) => stack.push((col, mode, doc)),
+            Concat(ref docs) => {
+                for doc in docs.iter().rev() {
+                    stack.push((indent, mode, doc));
+                }
+            },
+            Group(ref doc) => {
+                let flat = mode == Mode::Flat || fits(width as isize - col as isize, doc, &stack);
+                stack.push((indent, if flat { Mode::Flat } else { Mode::Break }, doc));
+            },
+        }
+    }
+    out
+}
+
+/// Whether `doc` laid out flat, and whatever follows it up to the next
+/// line break, fits in `remaining` columns.
+fn fits(mut remaining: isize, doc: &Doc, rest: &[(usize, Mode, &Doc)]) -> bool {
+    let mut stack = vec![(Mode::Flat, doc)];
+    let mut rest = rest.iter().rev();
+
+    while remaining >= 0 {
+        let (mode, doc) = match stack.pop() {
+            Some(next) => next,
+            None => match rest.next() {
+                Some(&(_, mode, doc)) => (mode, doc),
+                None => return true,
+            },
+        };
+        match *doc {
+            Text(ref s) => {
+                if let Some(i) = s.find('\n') {
+                    return remaining >= s[..i].chars().count() as isize;
+                }
+                remaining -= s.chars().count() as isize;
+            },
+            Line if mode == Mode::Flat => remaining -= 1,
+            Line => return true,
+            Nest(_, ref doc) | Align(ref doc) => stack.push((mode, doc)),
+            Concat(ref docs) => {
+                for doc in docs.iter().rev() {
+                    stack.push((mode, doc));
+                }
+            },
+            Group(ref doc) => stack.push((mode, doc)),
+        }
+    }
+    false
+}
+
+/// Builds documents for values, as data or as code, cut off at the
+/// printer's `*print-length*` and `*print-level*`.
+struct Layout {
+    printer: Printer,
+    code: bool,
+    open: Vec<usize>,
+    /// How many collections the value being laid out is nested in.
+    depth: usize,
+}
+
+impl Layout {
+    fn new(printer: Printer, code: bool) -> Layout {
+        Layout { printer, code, open: Vec::new(), depth: 0 }
+    }
+
+    fn doc(&mut self, n: &Node) -> Doc {
+        if !is_atom(n) && self.printer.level().is_some_and(|l| self.depth >= l) {
+            return text("#");
+        }
+        match *n {
+            List(ref v) if v.len() == 2 && v[0] == Symbol("quote".to_owned()) => {
+                Concat(vec![text("'"), self.doc(&v[1])])
+            },
+            List(ref v) if self.code => self.form(v),
+            List(ref v) => {
+                let items = self.items(v).into_iter().map(|(doc, n)| (doc, n.is_none_or(is_atom))).collect();
+                group(Concat(vec![text("("), align(fill_atoms(items)), text(")")]))
+            },
+            Vector(ref v) => self.coll("[", "]", v, true),
+            Set(ref v) => self.coll("#{", "}", v, true),
+            Map(ref m) => {
+                let mut entries = Vec::new();
+                for (i, (k, v)) in m.iter().enumerate() {
+                    if self.printer.length() == Some(i) {
+                        entries.push(text("..."));
+                        break;
+                    }
+                    entries.push(Concat(vec![self.child(k), text(" "), self.child(v)]));
+                }
+                group(Concat(vec![text("{"), align(join(entries, || Concat(vec![text(","), Line]))), text("}")]))
+            },
+            Cons(..) | ChunkedSeq { .. } | LazySeq(_) => {
+                let walk = walk_seq(n, self.printer.length(), &self.open);
+                let start = self.open.len();
+                let mut items = Vec::new();
+                for &(id, ref item) in &walk.items {
+                    self.open.push(id);
+                    items.push((self.child(item), is_atom(item)));
+                }
+                self.open.truncate(start);
+                if let Some(tail) = walk.tail {
+                    items.push((text(tail), true));
+                }
+                group(Concat(vec![text("("), align(fill_atoms(items)), text(")")]))
+            },
+            _ => text(self.printer.print(n)),
+        }
+    }
+
+    /// The document for an item of the collection being laid out.
+    fn child(&mut self, n: &Node) -> Doc {
+        self.depth += 1;
+        let doc = self.doc(n);
+        self.depth -= 1;
+        doc
+    }
+
+    /// Documents for `items` up to the print length, then `...` if there
+    /// are more, each with the item it came from.
+    fn items<'a>(&mut self, items: &'a [Node]) -> Vec<(Doc, Option<&'a Node>)> {
+        let mut docs = Vec::new();
+        for (i, n) in items.iter().enumerate() {
+            if self.printer.length() == Some(i) {
+                docs.push((text("..."), None));
+                break;
+            }
+            docs.push((self.child(n), Some(n)));
+        }
+        docs
+    }
+
+    /// Vectors and sets fill each line, lists in code break one item per line.
+    fn coll(&mut self, open: &str, close: &str, items: &[Node], filled: bool) -> Doc {
+        let docs = self.items(items).into_iter().map(|(doc, _)| doc).collect();
+        let items = if filled { fill(docs) } else { join(docs, || Line) };
+        group(Concat(vec![text(open), align(items), text(close)]))
+    }
+
+    /// Lays out a form the way it would be written: special forms keep their
+    /// leading arguments on the first line and indent the body by two, and
+    /// calls line their arguments up under the first one.
+    fn form(&mut self, v: &[Node]) -> Doc {
+        let head = match v.first() {
+            Some(Symbol(s)) => s.clone(),
+            Some(_) => return self.coll("(", ")", v, false),
+            None => return text("()"),
+        };
+        let header = match head.as_ref() {
+            "defn" | "defmacro" | "deftest" => 2,
+            "fn" | "def" | "if" | "when" | "when-not" | "let" | "for" | "loop" | "binding"
+            | "doseq" | "dotimes" | "testing" | "with-open" | "ns" => 1,
+            "do" | "lazy-seq" | "dosync" | "future" | "with-out-str" => 0,
+            "are" => 2,
+            _ => {
+                let args: Vec<Doc> = v[1..].iter().map(|n| self.child(n)).collect();
+                if args.is_empty() {
+                    return Concat(vec![text("("), text(head), text(")")]);
+                }
+                return group(Concat(vec![text("("), text(head), text(" "), align(join(args, || Line)), text(")")]));
+            },
+        };
+        let binds = matches!(head.as_ref(), "let" | "for" | "loop" | "binding" | "doseq" | "dotimes" | "with-open");
+        let mut first = vec![text("("), text(head)];
+        for (i, n) in v[1..].iter().take(header).enumerate() {
+            first.push(text(" "));
+            first.push(match *n {
+                Vector(ref b) if binds && i == 0 => self.bindings(b),
+                _ => self.child(n),
+            });
+        }
+        let body: Vec<Doc> = v[1..].iter().skip(header).map(|n| self.child(n)).collect();
+        if body.is_empty() {
+            first.push(text(")"));
+            return group(Concat(first));
+        }
+        first.push(nest(2, Concat(vec![Line, join(body, || Line)])));
+        first.push(text(")"));
+        group(Concat(first))
+    }
+
+    /// A binding vector with one name and value pair per line.
+    fn bindings(&mut self, b: &[Node]) -> Doc {
+        let pairs: Vec<Doc> = b.chunks(2)
+            .map(|pair| {
+                let docs: Vec<Doc> = pair.iter().map(|n| self.child(n)).collect();
+                join(docs, || text(" "))
+            })
+            .collect();
+        group(Concat(vec![text("["), align(join(pairs, || Line)), text("]")]))
+    }
+}
+
+/// Pretty prints data, breaking collections that don't fit in `width`
+/// columns across lines, within the print limits in `env`.
+pub fn pprint(n: &Node, width: usize, env: &Env) -> String {
+    let mut layout = Layout::new(Printer::with_env(true, env), false);
+    render(&layout.doc(n), width)
+}
+
+/// Pretty prints a form as code, with `defn`, `let`, `if` and friends laid
+/// out the way they are usually written.
+pub fn pprint_code(n: &Node, width: usize, env: &Env) -> String {
+    let mut layout = Layout::new(Printer::with_env(true, env), true);
+    render(&layout.doc(n), width)
+}
+
+/// The right margin when `*print-right-margin*` isn't set.
+const WIDTH: usize = 72;
+
+/// `pprint` and `pprint-code`, which print each argument on its own lines.
+pub fn print(args: &mut [Node], env: &mut Env, code: bool) -> Node {
+    let args = eval_args(args, env);
+    let width = match env.get("*print-right-margin*") {
+        Some(Int(n)) if n > 0 => n as usize,
+        _ => WIDTH,
+    };
+    for n in args {
+        let s = if code { pprint_code(&n, width, env) } else { pprint(&n, width, env) };
+        if let e @ Error(_) = print::out(&(s + "\n"), env) {
+            return e;
+        }
+    }
+    Nil
+}
+
+#[cfg(test)]
+mod tests {
+    use node::Node;
+    use eval::{Env, eval_all};
+    use parser::{tokenize, parse};
+    use super::{Doc, text, nest, align, group, join, fill, fill_atoms, render};
+    use super::Doc::{Line, Concat};
+
+    fn read(src: &str) -> Node {
+        parse(&mut tokenize(src))
+    }
+
+    fn pprint(n: &Node, width: usize) -> String {
+        super::pprint(n, width, &Env::new())
+    }
+
+    fn pprint_code(n: &Node, width: usize) -> String {
+        super::pprint_code(n, width, &Env::new())
+    }
+
+    fn words(n: usize) -> Vec<Doc> {
+        (0..n).map(|i| text(format!("w{}", i))).collect()
+    }
+
+    #[test]
+    fn groups_go_flat_only_when_they_fit() {
+        let doc = || group(join(vec![text("aaa"), text("bbb")], || Line));
+        assert_eq!(render(&doc(), 7), "aaa bbb");
+        assert_eq!(render(&doc(), 6), "aaa\nbbb");
+        // What follows the group up to the next line break has to fit too.
+        let followed = Concat(vec![doc(), text("!"), Line, text("next")]);
+        assert_eq!(render(&followed, 8), "aaa bbb!\nnext");
+        assert_eq!(render(&followed, 7), "aaa\nbbb!\nnext");
+    }
+
+    #[test]
+    fn nests_and_aligns() {
+        let nested = group(Concat(vec![text("(f"), nest(2, Concat(vec![Line, text("x"), Line, text("y")])), text(")")]));
+        assert_eq!(render(&nested, 80), "(f x y)");
+        assert_eq!(render(&nested, 6), "(f\n  x\n  y)");
+        let aligned = group(Concat(vec![text("(f "), align(join(vec![text("x"), text("y")], || Line)), text(")")]));
+        assert_eq!(render(&aligned, 6), "(f x\n   y)");
+    }
+
+    #[test]
+    fn fills_lines() {
+        assert_eq!(render(&fill(words(5)), 8), "w0 w1 w2\nw3 w4");
+        assert_eq!(render(&fill(words(5)), 7), "w0 w1\nw2 w3\nw4");
+        assert_eq!(render(&fill(words(1)), 1), "w0");
+    }
+
+    #[test]
+    fn fills_runs_of_atoms() {
+        let docs = |atoms: &[bool]| atoms.iter().enumerate().map(|(i, &a)| (text(format!("w{}", i)), a)).collect();
+        assert_eq!(render(&group(fill_atoms(docs(&[true, true, true, false, true]))), 80), "w0 w1 w2 w3 w4");
+        assert_eq!(render(&group(fill_atoms(docs(&[true, true, true, false, true]))), 8), "w0 w1 w2
+w3
+w4");
+        assert_eq!(pprint(&read("{:a [1 2 3 {:b (0 1 2 3 4 5 6 7 8 9)}]}"), 24),
+                   "{:a [1 2 3\n     {:b (0 1 2 3 4 5 6\n          7 8 9)}]}");
+        assert_eq!(pprint(&read("(1 2 [3 4] 5 6)"), 8), "(1 2\n [3 4]\n 5 6)");
+        let mut env = Env::new();
+        assert_eq!(format!("{}", eval_all("(with-out-str (pprint {:a [1 2 3 {:b (range 40)}]}))", &mut env)),
+                   "\"{:a [1 2 3\\n     {:b (0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23\\n          \
+                    24 25 26 27 28 29 30 31 32 33 34 35 36 37 38 39)}]}\\n\"");
+    }
+
+    #[test]
+    fn breaks_data_at_the_width() {
+        assert_eq!(pprint(&read("[1 2 3]"), 7), "[1 2 3]");
+        assert_eq!(pprint(&read("[1 2 3]"), 6), "[1 2\n 3]");
+        assert_eq!(pprint(&read("{:a 1 :b 2}"), 12), "{:a 1, :b 2}");
+        assert_eq!(pprint(&read("{:a 1 :b 2}"), 11), "{:a 1,\n :b 2}");
+        assert_eq!(pprint(&read("{:a [1 2] :b #{3}}"), 10), "{:a [1 2],\n :b #{3}}");
+        assert_eq!(pprint(&read("'(1 2)"), 5), "'(1\n  2)");
+        assert_eq!(pprint(&read("\"a b\""), 1), "\"a b\"");
+    }
+
+    #[test]
+    fn lays_code_out_as_written() {
+        let defn = read("(defn f [x] (if (pos? x) (inc x) (dec x)))");
+        assert_eq!(pprint_code(&defn, 80), "(defn f [x] (if (pos? x) (inc x) (dec x)))");
+        assert_eq!(pprint_code(&defn, 32), "(defn f [x]\n  (if (pos? x) (inc x) (dec x)))");
+        assert_eq!(pprint_code(&defn, 31), "(defn f [x]\n  (if (pos? x)\n    (inc x)\n    (dec x)))");
+        assert_eq!(pprint_code(&read("(let [a 1 b 2] (+ a b))"), 12), "(let [a 1\n      b 2]\n  (+ a b))");
+        assert_eq!(pprint_code(&read("(assoc m :a 1)"), 10), "(assoc m\n       :a\n       1)");
+    }
+
+    #[test]
+    fn prints_seqs_with_cycles_and_margins() {
+        let mut env = Env::new();
+        assert_eq!(format!("{}", eval_all("(def xs (lazy-seq (cons 1 xs))) (with-out-str (pprint xs))", &mut env)),
+                   "\"(1 ...)\\n\"");
+        assert_eq!(format!("{}", eval_all("(def *print-right-margin* 4) (with-out-str (pprint [1 2 3]))", &mut env)),
+                   "\"[1 2\\n 3]\\n\"");
+    }
+
+    #[test]
+    fn cuts_off_at_the_print_length_and_level() {
+        let mut env = Env::new();
+        assert_eq!(format!("{}", eval_all("(def *print-length* 3) (with-out-str (pprint (range)))", &mut env)),
+                   "\"(0 1 2 ...)\\n\"");
+        assert_eq!(format!("{}", eval_all("(with-out-str (pprint [[1 2 3 4] {:a 1 :b 2 :c 3 :d 4} #{1}]))", &mut env)),
+                   "\"[[1 2 3 ...] {:a 1, :b 2, :c 3, ...} #{1}]\\n\"");
+        assert_eq!(format!("{}", eval_all("(def *print-level* 1) (with-out-str (pprint [1 [2] {:a (range)}]))", &mut env)),
+                   "\"[1 # #]\\n\"");
+        assert_eq!(format!("{}", eval_all("(with-out-str (pprint-code '(f [x] (g (h x)))))", &mut env)),
+                   "\"(f # #)\\n\"");
+    }
+}
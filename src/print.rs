@@ -0,0 +1,485 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use num_traits::ToPrimitive;
+use node::Node;
+use io::{self, Handle};
+use node::Node::{Int, Big, Str, Char, Symbol, List, Vector, Map, Set, Lambda, Builtin, Atom, Stream, Future, Chan, Ref, Cons, ChunkedSeq, LazySeq, Nil, Error};
+use eval::{Env, eval_args, check_size, check_waiting};
+use seq::Seqable;
+use number;
+use string::to_str;
+
+/// Renders values, either readably (strings quoted, as `pr` does) or as
+/// plain text (as `print` does), cutting collections off after
+/// `*print-length*` items and `*print-level*` levels of nesting.
+pub struct Printer {
+    readably: bool,
+    length: Option<usize>,
+    level: Option<usize>,
+    /// Seq cells currently being printed, to stop on self-referencing seqs.
+    open: Vec<usize>,
+    /// Atoms, refs and futures currently being printed, to stop on ones
+    /// that hold themselves.
+    refs: Vec<usize>,
+}
+
+impl Printer {
+    pub fn new(readably: bool) -> Printer {
+        Printer { readably, length: None, level: None, open: Vec::new(), refs: Vec::new() }
+    }
+
+    /// A printer using the `*print-length*` and `*print-level*` limits in `env`.
+    pub fn with_env(readably: bool, env: &Env) -> Printer {
+        let limit = |name| match env.get(name) {
+            Some(Int(n)) if n >= 0 => Some(n as usize),
+            _ => None,
+        };
+        Printer { readably, length: limit("*print-length*"), level: limit("*print-level*"), open: Vec::new(), refs: Vec::new() }
+    }
+
+    /// How many items of a collection are printed before `...`.
+    pub fn length(&self) -> Option<usize> {
+        self.length
+    }
+
+    /// How deep collections nest before being printed as `#`.
+    pub fn level(&self) -> Option<usize> {
+        self.level
+    }
+
+    pub fn print(&mut self, n: &Node) -> String {
+        let mut out = String::new();
+        self.write(&mut out, n, 0);
+        out
+    }
+
+    fn write(&mut self, out: &mut String, n: &Node, depth: usize) {
+        let is_coll = matches!(*n, List(_) | Vector(_) | Map(_) | Set(_) | Cons(..) | ChunkedSeq { .. } | LazySeq(_));
+        if is_coll && self.level.is_some_and(|l| depth >= l) {
+            out.push('#');
+            return;
+        }
+        match *n {
+            Str(ref s) if !self.readably => out.push_str(s),
+            Char(c) if !self.readably => out.push(c),
+            List(ref v) if v.len() == 2 && v[0] == Symbol("quote".to_owned()) => {
+                out.push('\'');
+                self.write(out, &v[1], depth);
+            },
+            List(ref v)   => self.write_items(out, "(", ")", v.iter().cloned(), depth),
+            Vector(ref v) => self.write_items(out, "[", "]", v.iter().cloned(), depth),
+            Set(ref v)    => self.write_items(out, "#{", "}", v.iter().cloned(), depth),
+            Map(ref m)    => {
+                out.push('{');
+                for (i, (k, v)) in m.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    if self.length == Some(i) {
+                        out.push_str("...");
+                        break;
+                    }
+                    self.write(out, k, depth + 1);
+                    out.push(' ');
+                    self.write(out, v, depth + 1);
+                }
+                out.push('}');
+            },
+            Cons(..) | ChunkedSeq { .. } | LazySeq(_) => self.write_seq(out, n, depth),
+            Lambda { name: Some(ref name), .. } => out.push_str(&format!("#<fn {}>", name)),
+            Lambda { .. } => out.push_str("#<fn>"),
+            Builtin(ref name) => out.push_str(&format!("#<fn {}>", name)),
+            Stream(ref s) => out.push_str(match *s.lock().unwrap() {
+                Handle::Reader(_) | Handle::Stdin => "#<reader>",
+                Handle::Closed => "#<closed stream>",
+                _ => "#<writer>",
+            }),
+            Chan(_) => out.push_str("#<chan>"),
+            Ref(ref r) => self.write_ref(out, "#<ref ", Arc::as_ptr(r) as usize, || Some(r.value()), depth),
+            Future(ref t) => {
+                let kind = if t.promise { "#<promise " } else { "#<future " };
+                self.write_ref(out, kind, Arc::as_ptr(t) as usize, || t.value(), depth)
+            },
+            Atom(ref a) => {
+                let value = || Some(a.lock().unwrap().value.clone());
+                self.write_ref(out, "#<atom ", Arc::as_ptr(a) as usize, value, depth)
+            },
+            _ => out.push_str(&format!("{}", n)),
+        }
+    }
+
+    fn write_items<I: Iterator<Item = Node>>(&mut self, out: &mut String, open: &str, close: &str, items: I, depth: usize) {
+        out.push_str(open);
+        for (i, item) in items.enumerate() {
+            if i > 0 {
+                out.push(' ');
+            }
+            if self.length == Some(i) {
+                out.push_str("...");
+                break;
+            }
+            self.write(out, &item, depth + 1);
+        }
+        out.push_str(close);
+    }
+
+    /// Writes a reference cell around its value, or `...` in place of the
+    /// value when the cell is already being printed further out.
+    fn write_ref<F: FnOnce() -> Option<Node>>(&mut self, out: &mut String, kind: &str, id: usize, value: F, depth: usize) {
+        out.push_str(kind);
+        if self.refs.contains(&id) {
+            out.push_str("...");
+        } else {
+            match value() {
+                Some(value) => {
+                    self.refs.push(id);
+                    self.write(out, &value, depth + 1);
+                    self.refs.pop();
+                },
+                None => out.push_str("pending"),
+            }
+        }
+        out.push('>');
+    }
+
+    fn write_seq(&mut self, out: &mut String, n: &Node, depth: usize) {
+        let walk = walk_seq(n, self.length, &self.open);
+        let start = self.open.len();
+        out.push('(');
+        for (i, &(id, ref item)) in walk.items.iter().enumerate() {
+            if i > 0 {
+                out.push(' ');
+            }
+            self.open.push(id);
+            self.write(out, item, depth + 1);
+        }
+        self.open.truncate(start);
+        if let Some(ref tail) = walk.tail {
+            if !walk.items.is_empty() {
+                out.push(' ');
+            }
+            out.push_str(tail);
+        }
+        out.push(')');
+    }
+}
+
+/// The elements of a seq realized one cell at a time, so infinite seqs can
+/// be cut off after `length` items. Each element comes with the identity of
+/// its cell, to be pushed on `open` while it is printed.
+pub struct SeqWalk {
+    pub items: Vec<(usize, Node)>,
+    /// `...` when the seq was cut short, or the error that ended it.
+    pub tail: Option<String>,
+    /// Holding on to the cells keeps their addresses from being reused.
+    cells: Vec<Node>,
+}
+
+/// Walks `n`, stopping with `...` when a cell comes round again, either in
+/// this seq or in one of the seqs in `open` that are being printed around it,
+/// or with the error for going over a limit of the evaluation in progress.
+pub fn walk_seq(n: &Node, length: Option<usize>, open: &[usize]) -> SeqWalk {
+    let mut walk = SeqWalk { items: Vec::new(), tail: None, cells: Vec::new() };
+    let mut seen = HashSet::new();
+    let mut s = n.seq();
+    while s != Nil {
+        if let Error(_) = s {
+            walk.tail = Some(format!("{}", s));
+            break;
+        }
+        if let Err(e) = check_size(walk.items.len()) {
+            walk.tail = Some(format!("{}", e));
+            break;
+        }
+        let id = cell(&s);
+        if length == Some(walk.items.len()) || !seen.insert(id) || open.contains(&id) {
+            walk.tail = Some("...".to_owned());
+            break;
+        }
+        walk.items.push((id, s.first()));
+        let next = s.next();
+        walk.cells.push(s);
+        s = next;
+    }
+    walk
+}
+
+/// Identifies a realized seq cell by the memory it shares with its copies.
+fn cell(s: &Node) -> usize {
+    match *s {
+        Cons(ref head, _) => Arc::as_ptr(head) as usize,
+        ChunkedSeq { ref items, offset, .. } => Arc::as_ptr(items) as usize + offset,
+        _ => 0,
+    }
+}
+
+/// Human readable output: strings and characters as their text.
+pub fn print_str(args: &[Node], env: &Env) -> String {
+    let mut printer = Printer::with_env(false, env);
+    args.iter().map(|n| printer.print(n)).collect::<Vec<String>>().join(" ")
+}
+
+/// Readable output that the reader can parse back.
+pub fn pr_str(args: &[Node], env: &Env) -> String {
+    let mut printer = Printer::with_env(true, env);
+    args.iter().map(|n| printer.print(n)).collect::<Vec<String>>().join(" ")
+}
+
+/// Writes `s` to `*out*` unless a limit was gone over while printing it,
+/// which leaves it cut short.
+pub fn out(s: &str, env: &Env) -> Node {
+    match check_waiting() {
+        Ok(()) => io::out(s, env),
+        Err(e) => e,
+    }
+}
+
+pub fn print(args: &mut [Node], env: &mut Env) -> Node {
+    out(&print_str(&eval_args(args, env), env), env)
+}
+
+pub fn println(args: &mut [Node], env: &mut Env) -> Node {
+    out(&(print_str(&eval_args(args, env), env) + "\n"), env)
+}
+
+pub fn pr(args: &mut [Node], env: &mut Env) -> Node {
+    out(&pr_str(&eval_args(args, env), env), env)
+}
+
+pub fn prn(args: &mut [Node], env: &mut Env) -> Node {
+    out(&(pr_str(&eval_args(args, env), env) + "\n"), env)
+}
+
+pub fn format(args: &mut [Node], env: &mut Env) -> Node {
+    let args = eval_args(args, env);
+    match args.split_first() {
+        Some((Str(fmt), rest)) => match format_str(fmt, rest) {
+            Ok(s)  => Str(s),
+            Err(e) => e,
+        },
+        _ => Error("Expected a format string as first argument to format"),
+    }
+}
+
+pub fn printf(args: &mut [Node], env: &mut Env) -> Node {
+    match format(args, env) {
+        Str(s) => out(&s, env),
+        e      => e,
+    }
+}
+
+/// A `%` directive: `%[flags][width][.precision]conversion`.
+struct Spec {
+    left: bool,
+    zero: bool,
+    plus: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+/// Expands `%s %d %f %x %X %o %e %c %b %n %%` directives in `fmt`.
+pub fn format_str(fmt: &str, args: &[Node]) -> Result<String, Node> {
+    let mut s = String::new();
+    let mut args = args.iter();
+    let mut chars = fmt.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            s.push(c);
+            continue;
+        }
+        let mut spec = Spec { left: false, zero: false, plus: false, width: 0, precision: None };
+        while let Some(&c) = chars.peek() {
+            match c {
+                '-' => spec.left = true,
+                '0' => spec.zero = true,
+                '+' => spec.plus = true,
+                _   => break,
+            }
+            chars.next();
+        }
+        while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+            spec.width = spec.width * 10 + d as usize;
+            chars.next();
+        }
+        if chars.peek() == Some(&'.') {
+            chars.next();
+            let mut precision = 0;
+            while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+                precision = precision * 10 + d as usize;
+                chars.next();
+            }
+            spec.precision = Some(precision);
+        }
+        let conversion = match chars.next() {
+            Some(c) => c,
+            None    => return Err(Error("Format string ends in the middle of a directive")),
+        };
+        match conversion {
+            '%' => { s.push('%'); continue; },
+            'n' => { s.push('\n'); continue; },
+            _   => (),
+        }
+        let arg = match args.next() {
+            Some(arg) => arg,
+            None      => return Err(Error("Not enough arguments for format string")),
+        };
+        let text = match conversion {
+            's' => {
+                let text = to_str(arg);
+                match spec.precision {
+                    Some(p) => text.chars().take(p).collect(),
+                    None    => text,
+                }
+            },
+            'b' => (*arg != Nil && *arg != Node::Bool(false)).to_string(),
+            'c' => match *arg {
+                Char(c) => c.to_string(),
+                Int(n)  => match n.to_u32().and_then(::std::char::from_u32) {
+                    Some(c) => c.to_string(),
+                    None    => return Err(Error("Invalid character code for %c in format")),
+                },
+                _ => return Err(Error("Expected a character for %c in format")),
+            },
+            'd' => match *arg {
+                Int(n)     => signed(n.to_string(), &spec),
+                Big(ref n) => signed(n.to_string(), &spec),
+                _ => return Err(Error("Expected an integer for %d in format")),
+            },
+            'x' | 'X' | 'o' => {
+                let text = match *arg {
+                    Int(n) if conversion == 'o' => format!("{:o}", n),
+                    Int(n)     => format!("{:x}", n),
+                    Big(ref n) => n.to_str_radix(if conversion == 'o' { 8 } else { 16 }),
+                    _ => return Err(Error("Expected an integer for %x or %o in format")),
+                };
+                if conversion == 'X' { text.to_uppercase() } else { text }
+            },
+            'f' | 'e' if number::is_number(arg) => {
+                let f = number::to_f64(arg);
+                let p = spec.precision.unwrap_or(6);
+                signed(if conversion == 'f' { format!("{:.*}", p, f) } else { format!("{:.*e}", p, f) }, &spec)
+            },
+            'f' | 'e' => return Err(Error("Expected a number for %f or %e in format")),
+            _ => return Err(Error("Unknown conversion in format string")),
+        };
+        s.push_str(&pad(text, &spec, matches!(conversion, 'd' | 'x' | 'X' | 'o' | 'f' | 'e')));
+    }
+    Ok(s)
+}
+
+fn signed(text: String, spec: &Spec) -> String {
+    if spec.plus && !text.starts_with('-') {
+        format!("+{}", text)
+    } else {
+        text
+    }
+}
+
+/// Pads to the directive's width, putting zeroes after any sign.
+fn pad(text: String, spec: &Spec, numeric: bool) -> String {
+    let len = text.chars().count();
+    if len >= spec.width {
+        return text;
+    }
+    let fill = spec.width - len;
+    if spec.left {
+        text + &" ".repeat(fill)
+    } else if spec.zero && numeric {
+        let sign = if text.starts_with('-') || text.starts_with('+') { 1 } else { 0 };
+        format!("{}{}{}", &text[..sign], "0".repeat(fill), &text[sign..])
+    } else {
+        " ".repeat(fill) + &text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use node::Node;
+    use node::Node::{Int, Big, Float, Str, Char, Nil, Error};
+    use eval::{Env, eval_all};
+    use parser::{tokenize, parse};
+    use super::{Printer, format_str};
+
+    fn read(src: &str) -> Node {
+        parse(&mut tokenize(src))
+    }
+
+    fn run(env: &mut Env, src: &str) -> String {
+        format!("{}", eval_all(src, env))
+    }
+
+    fn limited(length: Option<usize>, level: Option<usize>, src: &str) -> String {
+        Printer { readably: true, length, level, open: Vec::new(), refs: Vec::new() }.print(&read(src))
+    }
+
+    #[test]
+    fn prints_readably_or_plainly() {
+        let n = read("[\"a\\nb\" \\c (quote x) '(1 \"d\")]");
+        assert_eq!(Printer::new(true).print(&n), "[\"a\\nb\" \\c 'x '(1 \"d\")]");
+        assert_eq!(Printer::new(false).print(&n), "[a\nb c 'x '(1 d)]");
+        assert_eq!(Printer::new(true).print(&read("{:a #{1} :b {}}")), "{:a #{1}, :b {}}");
+    }
+
+    #[test]
+    fn cuts_collections_off_at_the_print_length() {
+        assert_eq!(limited(Some(2), None, "[1 2 3]"), "[1 2 ...]");
+        assert_eq!(limited(Some(2), None, "[1 2]"), "[1 2]");
+        assert_eq!(limited(Some(0), None, "(1)"), "(...)");
+        assert_eq!(limited(Some(1), None, "{:a 1 :b 2}"), "{:a 1, ...}");
+        assert_eq!(limited(Some(1), None, "#{1 2}"), "#{1 ...}");
+        assert_eq!(limited(Some(1), None, "[[1 2] [3]]"), "[[1 ...] ...]");
+        let mut env = Env::new();
+        assert_eq!(run(&mut env, "(def *print-length* 3) (pr-str (range) (map - [1 2 3 4]))"),
+                   "\"(0 1 2 ...) (-1 -2 -3 ...)\"");
+    }
+
+    #[test]
+    fn cuts_nesting_off_at_the_print_level() {
+        assert_eq!(limited(None, Some(0), "[1]"), "#");
+        assert_eq!(limited(None, Some(1), "[1 [2] {:a [3]}]"), "[1 # #]");
+        assert_eq!(limited(None, Some(2), "[1 [2 [3]] {:a [3]}]"), "[1 [2 #] {:a #}]");
+        assert_eq!(limited(Some(1), Some(1), "([1] 2)"), "(# ...)");
+        let mut env = Env::new();
+        assert_eq!(run(&mut env, "(def *print-level* 1) (pr-str (list 1 (range 2)) (atom [1]))"),
+                   "\"(1 #) #<atom #>\"");
+    }
+
+    #[test]
+    fn stops_at_seqs_that_come_round_again() {
+        let mut env = Env::new();
+        assert_eq!(run(&mut env, "(def xs (lazy-seq (cons 1 xs))) (pr-str xs)"), "\"(1 ...)\"");
+        assert_eq!(run(&mut env, "(def ys (lazy-seq (cons ys nil))) (pr-str ys)"), "\"((...))\"");
+        assert_eq!(run(&mut env, "(def zs (lazy-seq (cons 1 (lazy-seq (cons 2 zs))))) (pr-str [zs zs])"),
+                   "\"[(1 2 ...) (1 2 ...)]\"");
+        assert_eq!(run(&mut env, "(pr-str (map (fn [x] (/ 1 x)) [1 0]))"), "\"(1 Error: Divide by zero)\"");
+    }
+
+    #[test]
+    fn stops_at_reference_cells_that_hold_themselves() {
+        let mut env = Env::new();
+        assert_eq!(run(&mut env, "(def a (atom nil)) (reset! a a) (pr-str a)"), "\"#<atom #<atom ...>>\"");
+        assert_eq!(run(&mut env, "(reset! a [a {:b a}]) (pr-str a)"), "\"#<atom [#<atom ...> {:b #<atom ...>}]>\"");
+        assert_eq!(run(&mut env, "(def b (atom 1)) (pr-str [b b])"), "\"[#<atom 1> #<atom 1>]\"");
+        assert_eq!(run(&mut env, "(def r (ref nil)) (dosync (ref-set r [r])) (pr-str r)"), "\"#<ref [#<ref ...>]>\"");
+    }
+
+    #[test]
+    fn formats_directives() {
+        let format = |fmt: &str, args: &[Node]| format_str(fmt, args).unwrap_or_else(|e| format!("{}", e));
+        assert_eq!(format("%5d|%-5d|%05d|%+d", &[Int(42), Int(42), Int(-42), Int(7)]), "   42|42   |-0042|+7");
+        assert_eq!(format("%x %X %o %d", &[Int(255), Int(255), Int(8), Big("99999999999999999999".parse().unwrap())]),
+                   "ff FF 10 99999999999999999999");
+        assert_eq!(format("%.2f %.1e %8.3f", &[Float(1.23456), Int(1500), Float(-2.0)]), "1.23 1.5e3   -2.000");
+        assert_eq!(format("%s|%.2s|%c%c|%b %b", &[Nil, Str("abc".to_owned()), Char('x'), Int(955), Nil, Int(0)]),
+                   "|ab|xλ|false true");
+        assert_eq!(format("100%%%n", &[]), "100%\n");
+        assert_eq!(format("%d", &[]), "Error: Not enough arguments for format string");
+        assert_eq!(format("%", &[]), "Error: Format string ends in the middle of a directive");
+        assert_eq!(format("%q", &[Int(1)]), "Error: Unknown conversion in format string");
+        assert_eq!(format("%c", &[Int(-1)]), "Error: Invalid character code for %c in format");
+        assert_eq!(format("%f", &[Str("x".to_owned())]), "Error: Expected a number for %f or %e in format");
+        assert_eq!(format_str("%d", &[Int(1)]), Ok("1".to_owned()));
+        assert_eq!(format_str("%d", &[Str("1".to_owned())]), Err(Error("Expected an integer for %d in format")));
+    }
+}
@@ -0,0 +1,145 @@
+use regex::{Regex, Captures};
+use node::Node;
+use node::Node::{Str, Vector, Nil, Error};
+use eval::{Env, eval_args, apply};
+use seq::Seqable;
+use string::to_str;
+
+pub fn compile(pattern: &str) -> Node {
+    match Regex::new(pattern) {
+        Ok(re) => Node::Regex(re),
+        Err(_) => Error("Invalid regular expression"),
+    }
+}
+
+/// A match is a plain string, or a vector of the whole match followed by
+/// each group (`nil` when a group didn't participate) if the pattern has groups.
+fn match_node(caps: &Captures) -> Node {
+    if caps.len() == 1 {
+        return Str(caps[0].to_owned());
+    }
+    Vector(caps.iter()
+        .map(|m| m.map_or(Nil, |m| Str(m.as_str().to_owned())))
+        .collect())
+}
+
+fn regex_and_str(args: &mut [Node], env: &mut Env, err: &'static str) -> Result<(Regex, String), Node> {
+    match eval_args(args, env).as_slice() {
+        [Node::Regex(re), Str(s)] => Ok((re.clone(), s.clone())),
+        _ => Err(Error(err)),
+    }
+}
+
+pub fn re_pattern(args: &mut [Node], env: &mut Env) -> Node {
+    match eval_args(args, env).as_slice() {
+        [Str(s)] => compile(s),
+        [re @ Node::Regex(_)] => re.clone(),
+        _ => Error("Expected a string as argument to re-pattern"),
+    }
+}
+
+pub fn re_find(args: &mut [Node], env: &mut Env) -> Node {
+    match regex_and_str(args, env, "Expected a regex and a string as arguments to re-find") {
+        Ok((re, s)) => re.captures(&s).map_or(Nil, |caps| match_node(&caps)),
+        Err(e) => e,
+    }
+}
+
+/// Like `re-find`, but the pattern has to match the whole string.
+pub fn re_matches(args: &mut [Node], env: &mut Env) -> Node {
+    match regex_and_str(args, env, "Expected a regex and a string as arguments to re-matches") {
+        Ok((re, s)) => {
+            let anchored = match Regex::new(&format!("^(?:{})$", re.as_str())) {
+                Ok(re) => re,
+                Err(_) => return Error("Invalid regular expression"),
+            };
+            anchored.captures(&s).map_or(Nil, |caps| match_node(&caps))
+        },
+        Err(e) => e,
+    }
+}
+
+pub fn re_seq(args: &mut [Node], env: &mut Env) -> Node {
+    match regex_and_str(args, env, "Expected a regex and a string as arguments to re-seq") {
+        Ok((re, s)) => {
+            let matches: Vec<Node> = re.captures_iter(&s).map(|caps| match_node(&caps)).collect();
+            Vector(matches).seq()
+        },
+        Err(e) => e,
+    }
+}
+
+/// Replaces every match with a string using `$1` style group references,
+/// or with the result of calling a function on the match.
+pub fn replace(s: &str, re: &Regex, replacement: &Node) -> Node {
+    match *replacement {
+        Str(ref r) => Str(re.replace_all(s, r.as_str()).into_owned()),
+        Node::Lambda { .. } | Node::Builtin(_) => {
+            let mut error = None;
+            let replaced = re.replace_all(s, |caps: &Captures| {
+                match apply(replacement, vec![match_node(caps)]) {
+                    e @ Error(_) => {
+                        error.get_or_insert(e);
+                        String::new()
+                    },
+                    n => to_str(&n),
+                }
+            });
+            match error {
+                Some(e) => e,
+                None    => Str(replaced.into_owned()),
+            }
+        },
+        _ => Error("Expected a string or a function as replacement"),
+    }
+}
+
+pub fn split(s: &str, re: &Regex) -> Vec<Node> {
+    re.split(s).map(|p| Str(p.to_owned())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use eval::{Env, eval_all};
+
+    fn run(src: &str) -> String {
+        format!("{}", eval_all(src, &mut Env::new()))
+    }
+
+    #[test]
+    fn finds_matches_and_groups() {
+        assert_eq!(run("(re-find #\"\\d+\" \"ab123c45\")"), "\"123\"");
+        assert_eq!(run("(re-find #\"(\\w)(\\d)?\" \"a\")"), "[\"a\" \"a\" nil]");
+        assert_eq!(run("(re-find #\"(?<key>\\w+)=(\\w+)\" \"x k=v\")"), "[\"k=v\" \"k\" \"v\"]");
+        assert_eq!(run("(re-find #\"z\" \"abc\")"), "nil");
+        assert_eq!(run("(re-seq #\"(\\w)(\\d)\" \"a1 b2\")"), "([\"a1\" \"a\" \"1\"] [\"b2\" \"b\" \"2\"])");
+        assert_eq!(run("(re-seq #\"\\d\" \"ab\")"), "nil");
+        assert_eq!(run("(re-find \"a\" \"a\")"), "Error: Expected a regex and a string as arguments to re-find");
+    }
+
+    #[test]
+    fn matches_the_whole_string() {
+        assert_eq!(run("(re-matches #\"a|ab\" \"ab\")"), "\"ab\"");
+        assert_eq!(run("(re-matches #\"\\d+\" \"12a\")"), "nil");
+        assert_eq!(run("(re-matches #\"(\\d+)-(\\d+)\" \"1-22\")"), "[\"1-22\" \"1\" \"22\"]");
+    }
+
+    #[test]
+    fn compiles_patterns() {
+        assert_eq!(run("(re-find (re-pattern \"[aeiou]+\") \"tree\")"), "\"ee\"");
+        assert_eq!(run("(re-pattern #\"a\")"), "#\"a\"");
+        assert_eq!(run("(re-pattern \"(\")"), "Error: Invalid regular expression");
+        assert_eq!(run("(= #\"a+\" (re-pattern \"a+\"))"), "true");
+    }
+
+    #[test]
+    fn replaces_and_splits_with_groups() {
+        assert_eq!(run("(replace \"2024-01\" #\"(\\d+)-(\\d+)\" \"$2/$1\")"), "\"01/2024\"");
+        assert_eq!(run("(replace \"a1b2\" #\"\\d\" (fn [d] (str \"<\" d \">\")))"), "\"a<1>b<2>\"");
+        assert_eq!(run("(replace \"k=v\" #\"(\\w)=(\\w)\" (fn [m] (str (nth m 2) \"=\" (nth m 1))))"), "\"v=k\"");
+        assert_eq!(run("(replace \"a1\" #\"\\d\" (fn [d] (/ 1 0)))"), "Error: Divide by zero");
+        assert_eq!(run("(replace \"a1\" #\"\\d\" 1)"), "Error: Expected a string or a function as replacement");
+        assert_eq!(run("(split \"a1b22c\" #\"\\d+\")"), "[\"a\" \"b\" \"c\"]");
+        assert_eq!(run("(split \"a1\" #\"\\d\")"), "[\"a\"]");
+    }
+}
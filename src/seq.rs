@@ -0,0 +1,531 @@
+use std::sync::{Arc, Mutex};
+use std::mem;
+use std::thread::{self, ThreadId};
+use std::collections::{HashMap, HashSet};
+use node::{Node, hash_of};
+use node::Node::{Int, Str, Char, List, Vector, Map, Set, Cons, ChunkedSeq, LazySeq, Nil, Error};
+use eval::{apply, check_size};
+use io;
+
+/// Number of elements realized at a time by chunked sequences.
+pub const CHUNK_SIZE: usize = 32;
+
+/// The state behind a `Node::LazySeq`. Everything but `Realized` is a recipe
+/// for producing the next step of the sequence.
+#[derive(Debug)]
+pub enum Lazy {
+    Thunk(Node),
+    Range(i64, Option<i64>, i64),
+    Repeat(Node, Option<i64>),
+    Map(Node, Vec<Node>, bool),
+    Filter(Node, Node, bool),
+    Concat(Vec<Node>),
+    Distinct(Node, Arc<Mutex<HashSet<Node>>>),
+    Lines(Arc<Mutex<io::Handle>>),
+    /// Being realized by a thread, which others wait for.
+    Realizing(ThreadId),
+    Realized(Forced),
+}
+
+/// The seq a `LazySeq` was realized as. Dropping it lets go of the cells
+/// after it one at a time, where nothing else holds on to them, instead of
+/// recursing once per cell down a long seq.
+#[derive(Debug)]
+pub struct Forced(Node);
+
+impl Drop for Forced {
+    fn drop(&mut self) {
+        let mut rest = unlink(&mut self.0);
+        while let Some(mut cell) = rest {
+            rest = unlink(&mut cell);
+        }
+    }
+}
+
+/// Takes the rest of the seq out of `cell`, unless it is shared.
+fn unlink(cell: &mut Node) -> Option<Node> {
+    match *cell {
+        ChunkedSeq { ref mut more, .. } => Some(mem::replace(&mut **more, Nil)),
+        Cons(_, ref mut rest) => Arc::get_mut(rest).map(|rest| mem::replace(rest, Nil)),
+        LazySeq(ref mut lazy) => match Arc::get_mut(lazy).and_then(|lazy| lazy.get_mut().ok()) {
+            Some(&mut Lazy::Realized(Forced(ref mut s))) => Some(mem::replace(s, Nil)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Lists and the sequence types themselves, including `nil` as the empty seq.
+pub fn is_seq(n: &Node) -> bool {
+    matches!(*n, List(_) | Cons(..) | ChunkedSeq { .. } | LazySeq(_) | Nil)
+}
+
+pub fn is_seqable(n: &Node) -> bool {
+    is_seq(n) || matches!(*n, Vector(_) | Str(_) | Map(_) | Set(_))
+}
+
+pub fn lazy(l: Lazy) -> Node {
+    LazySeq(Arc::new(Mutex::new(l)))
+}
+
+pub fn cons(x: Node, coll: Node) -> Node {
+    Cons(Arc::new(x), Arc::new(coll))
+}
+
+fn chunk(items: Vec<Node>) -> Node {
+    if items.is_empty() {
+        Nil
+    } else {
+        ChunkedSeq { items: Arc::new(items), offset: 0, more: Box::new(Nil) }
+    }
+}
+
+/// Anything that can be walked one element at a time. Strings are seen as
+/// their characters and maps as `[key value]` entry vectors.
+pub trait Seqable: Sized {
+    /// `Nil` for an empty collection, otherwise a `Cons` or a `ChunkedSeq`.
+    fn seq(&self) -> Node;
+
+    /// Adds `x` where the collection naturally grows: the end of a vector,
+    /// the front of a list or seq, or as a new entry of a map or set.
+    fn conj(self, x: Node) -> Node;
+
+    fn first(&self) -> Node {
+        match self.seq() {
+            Cons(head, _) => (*head).clone(),
+            ChunkedSeq { items, offset, .. } => items[offset].clone(),
+            s @ Error(_) => s,
+            _ => Nil,
+        }
+    }
+
+    /// Everything after the first element, as a possibly empty sequence.
+    fn rest(&self) -> Node {
+        match self.seq() {
+            Cons(_, tail) => (*tail).clone(),
+            ChunkedSeq { items, offset, more } => {
+                if offset + 1 < items.len() {
+                    ChunkedSeq { items, offset: offset + 1, more }
+                } else if *more == Nil {
+                    List(vec![])
+                } else {
+                    *more
+                }
+            },
+            s @ Error(_) => s,
+            _ => List(vec![]),
+        }
+    }
+
+    fn next(&self) -> Node {
+        self.rest().seq()
+    }
+
+    fn count(&self) -> Result<usize, Node> {
+        self.to_vec().map(|v| v.len())
+    }
+
+    fn nth(&self, i: usize) -> Option<Node> {
+        nth_of_seq(self.seq(), i)
+    }
+
+    /// Realizes every element, returning them in order.
+    fn to_vec(&self) -> Result<Vec<Node>, Node> {
+        let mut v = Vec::new();
+        let mut s = self.seq();
+        loop {
+            match s {
+                Nil => return Ok(v),
+                Error(_) => return Err(s),
+                ChunkedSeq { items, offset, more } => {
+                    v.extend(items[offset..].iter().cloned());
+                    s = more.seq();
+                },
+                _ => {
+                    v.push(s.first());
+                    s = s.next();
+                },
+            }
+            check_size(v.len())?;
+        }
+    }
+}
+
+impl Seqable for Node {
+    fn seq(&self) -> Node {
+        match *self {
+            Nil => Nil,
+            Vector(ref v) | List(ref v) | Set(ref v) => chunk(v.clone()),
+            Str(ref s) => chunk(s.chars().map(Char).collect()),
+            Map(ref m) => chunk(m.iter().map(|(k, v)| Vector(vec![k.clone(), v.clone()])).collect()),
+            Cons(..) | ChunkedSeq { .. } => self.clone(),
+            LazySeq(ref cell) => realize(cell),
+            Error(_) => self.clone(),
+            _ => Error("Don't know how to create a seq"),
+        }
+    }
+
+    fn conj(self, x: Node) -> Node {
+        let len = match self {
+            Vector(ref v) | List(ref v) | Set(ref v) => v.len(),
+            Map(ref m) => m.len(),
+            _ => 0,
+        };
+        if let Err(e) = check_size(len + 1) {
+            return e;
+        }
+        match self {
+            Vector(mut v) => {
+                v.push(x);
+                Vector(v)
+            },
+            List(mut v) => {
+                v.insert(0, x);
+                List(v)
+            },
+            Nil => List(vec![x]),
+            Map(m) => {
+                match x {
+                    Vector(ref entry) if entry.len() == 2 => assoc(m, entry[0].clone(), entry[1].clone()),
+                    _ => Error("Expected [key value] vector as argument to conj on a map"),
+                }
+            },
+            Set(mut v) => {
+                if !v.contains(&x) {
+                    v.push(x);
+                }
+                Set(v)
+            },
+            s @ Cons(..) | s @ ChunkedSeq { .. } | s @ LazySeq(_) => cons(x, s),
+            e @ Error(_) => e,
+            _ => Error("Can't conj onto this value"),
+        }
+    }
+
+    fn count(&self) -> Result<usize, Node> {
+        match *self {
+            Vector(ref v) | List(ref v) | Set(ref v) => Ok(v.len()),
+            Map(ref m) => Ok(m.len()),
+            Str(ref s) => Ok(s.chars().count()),
+            _ => count_seq(self.seq()),
+        }
+    }
+
+    fn nth(&self, i: usize) -> Option<Node> {
+        match *self {
+            Vector(ref v) | List(ref v) => v.get(i).cloned(),
+            Str(ref s) => s.chars().nth(i).map(Char),
+            _ => nth_of_seq(self.seq(), i),
+        }
+    }
+}
+
+/// The seq of `n`, letting go of `n` itself, so that walking the seq by
+/// value frees each cell once past it unless something else holds the head.
+pub fn into_seq(n: Node) -> Node {
+    n.seq()
+}
+
+/// Counts a seq by walking it, which realizes every element.
+pub fn count_seq(mut s: Node) -> Result<usize, Node> {
+    let mut len = 0;
+    loop {
+        s = match s {
+            Nil => return Ok(len),
+            Error(_) => return Err(s),
+            ChunkedSeq { ref items, offset, ref more } => {
+                len += items.len() - offset;
+                more.seq()
+            },
+            _ => {
+                len += 1;
+                s.next()
+            },
+        };
+        check_size(len)?;
+    }
+}
+
+pub fn nth_of_seq(mut s: Node, i: usize) -> Option<Node> {
+    for _ in 0..i {
+        s = s.next();
+    }
+    match s {
+        Nil => None,
+        _   => Some(s.first()),
+    }
+}
+
+/// A set of `items` without repeats, in the order they first come. Unlike
+/// `conj`ing them on one by one this takes time in line with their number.
+pub fn set_of(items: Vec<Node>) -> Node {
+    let mut index: HashMap<u64, Vec<usize>> = HashMap::new();
+    let mut v: Vec<Node> = Vec::new();
+    for n in items {
+        let same = index.entry(hash_of(&n)).or_default();
+        if !same.iter().any(|&i| v[i] == n) {
+            same.push(v.len());
+            v.push(n);
+        }
+    }
+    Set(v)
+}
+
+/// A map of `entries`, a later value for a key replacing an earlier one
+/// where the key first came, taking time in line with their number.
+pub fn map_of(entries: Vec<(Node, Node)>) -> Node {
+    let mut index: HashMap<u64, Vec<usize>> = HashMap::new();
+    let mut m: Vec<(Node, Node)> = Vec::new();
+    for (k, v) in entries {
+        let same = index.entry(hash_of(&k)).or_default();
+        match same.iter().find(|&&i| m[i].0 == k) {
+            Some(&i) => m[i].1 = v,
+            None     => {
+                same.push(m.len());
+                m.push((k, v));
+            },
+        }
+    }
+    Map(m)
+}
+
+/// Returns a map with `k` set to `v`, keeping the original insertion order.
+/// Finding `k` goes through the entries in turn.
+pub fn assoc(mut m: Vec<(Node, Node)>, k: Node, v: Node) -> Node {
+    match m.iter().position(|(key, _)| *key == k) {
+        Some(i) => m[i].1 = v,
+        None    => m.push((k, v)),
+    }
+    Map(m)
+}
+
+/// Forces a lazy sequence, caching the result. A thunk returning another
+/// lazy sequence is unwrapped iteratively so long chains don't grow the stack.
+/// A sequence another thread is realizing is waited for.
+pub fn realize(cell: &Arc<Mutex<Lazy>>) -> Node {
+    let me = thread::current().id();
+    let mut pending = vec![cell.clone()];
+    let result = loop {
+        let current = pending.last().unwrap().clone();
+        let state = {
+            let mut state = current.lock().unwrap();
+            match *state {
+                Lazy::Realizing(other) if other != me => None,
+                _ => Some(mem::replace(&mut *state, Lazy::Realizing(me))),
+            }
+        };
+        let stepped = match state {
+            None => {
+                thread::yield_now();
+                continue;
+            },
+            Some(Lazy::Realized(ref s)) => s.0.clone(),
+            Some(Lazy::Realizing(_)) => Error("Lazy sequence depends on itself"),
+            Some(other) => step(other),
+        };
+        match stepped {
+            LazySeq(inner) => {
+                pending.push(inner);
+            },
+            other => break other.seq(),
+        }
+    };
+    for cell in pending {
+        *cell.lock().unwrap() = Lazy::Realized(Forced(result.clone()));
+    }
+    result
+}
+
+fn step(state: Lazy) -> Node {
+    match state {
+        Lazy::Thunk(f) => apply(&f, vec![]),
+        Lazy::Range(from, to, step) => {
+            let mut items = Vec::new();
+            let mut n = from;
+            let mut overflowed = false;
+            while items.len() < CHUNK_SIZE && in_range(n, to, step) {
+                items.push(Int(n));
+                match n.checked_add(step) {
+                    Some(next) => n = next,
+                    None => {
+                        overflowed = true;
+                        break;
+                    },
+                }
+            }
+            if items.is_empty() {
+                return Nil;
+            }
+            // A range running past the largest or smallest integer ends there.
+            let more = if !overflowed && in_range(n, to, step) { lazy(Lazy::Range(n, to, step)) } else { Nil };
+            ChunkedSeq { items: Arc::new(items), offset: 0, more: Box::new(more) }
+        },
+        Lazy::Repeat(x, times) => {
+            let size = match times {
+                Some(t) if t < CHUNK_SIZE as i64 => t.max(0) as usize,
+                _ => CHUNK_SIZE,
+            };
+            if size == 0 {
+                return Nil;
+            }
+            let more = match times {
+                None => lazy(Lazy::Repeat(x.clone(), None)),
+                Some(t) if t > size as i64 => lazy(Lazy::Repeat(x.clone(), Some(t - size as i64))),
+                _ => Nil,
+            };
+            ChunkedSeq { items: Arc::new(vec![x; size]), offset: 0, more: Box::new(more) }
+        },
+        Lazy::Map(f, colls, skip_nil) => map_step(f, colls, skip_nil),
+        Lazy::Filter(pred, coll, keep) => filter_step(pred, coll, keep),
+        Lazy::Concat(mut colls) => {
+            while !colls.is_empty() {
+                // The last one is the rest of the seq, so a concat ending in
+                // another one (as in `cycle`) doesn't nest a level each time.
+                if colls.len() == 1 {
+                    return colls.remove(0).seq();
+                }
+                match colls[0].seq() {
+                    Nil => {
+                        colls.remove(0);
+                    },
+                    ChunkedSeq { items, offset, more } => {
+                        colls[0] = *more;
+                        return ChunkedSeq { items, offset, more: Box::new(lazy(Lazy::Concat(colls))) };
+                    },
+                    s @ Error(_) => return s,
+                    s => {
+                        colls[0] = s.rest();
+                        return cons(s.first(), lazy(Lazy::Concat(colls)));
+                    },
+                }
+            }
+            Nil
+        },
+        Lazy::Distinct(coll, seen) => {
+            let mut s = coll.seq();
+            loop {
+                match s {
+                    Nil => return Nil,
+                    Error(_) => return s,
+                    _ => {
+                        let x = s.first();
+                        if seen.lock().unwrap().insert(x.clone()) {
+                            return cons(x, lazy(Lazy::Distinct(s.rest(), seen)));
+                        }
+                        s = s.next();
+                    },
+                }
+            }
+        },
+        Lazy::Lines(h) => match io::read_line(&h) {
+            Ok(Some(line)) => cons(Str(line), lazy(Lazy::Lines(h))),
+            Ok(None) => Nil,
+            Err(e) => e,
+        },
+        Lazy::Realizing(_) | Lazy::Realized(_) => unreachable!(),
+    }
+}
+
+fn in_range(n: i64, to: Option<i64>, step: i64) -> bool {
+    match to {
+        None => true,
+        Some(to) => if step >= 0 { n < to } else { n > to },
+    }
+}
+
+/// The seq of `items`, or `end` if there are none, followed by `end`: the
+/// rest of the seq, or the error that stopped it.
+fn chunk_then(items: Vec<Node>, end: Node) -> Node {
+    if items.is_empty() {
+        end
+    } else {
+        ChunkedSeq { items: Arc::new(items), offset: 0, more: Box::new(end) }
+    }
+}
+
+/// Maps `f` over one or more collections. A single chunked source is
+/// consumed a whole chunk at a time. A call returning an error ends the
+/// seq with that error.
+fn map_step(f: Node, colls: Vec<Node>, skip_nil: bool) -> Node {
+    let mut seqs: Vec<Node> = colls.iter().map(|c| c.seq()).collect();
+    loop {
+        if let Some(e) = seqs.iter().find(|s| matches!(**s, Error(_))) {
+            return e.clone();
+        }
+        if seqs.is_empty() || seqs.contains(&Nil) {
+            return Nil;
+        }
+        if seqs.len() == 1 {
+            if let ChunkedSeq { ref items, offset, ref more } = seqs[0] {
+                let mut results = Vec::new();
+                for x in &items[offset..] {
+                    match apply(&f, vec![x.clone()]) {
+                        e @ Error(_) => return chunk_then(results, e),
+                        Nil if skip_nil => (),
+                        r => results.push(r),
+                    }
+                }
+                let more = (**more).clone();
+                if results.is_empty() {
+                    seqs = vec![more.seq()];
+                    continue;
+                }
+                let more = lazy(Lazy::Map(f, vec![more], skip_nil));
+                return ChunkedSeq { items: Arc::new(results), offset: 0, more: Box::new(more) };
+            }
+        }
+        let result = apply(&f, seqs.iter().map(|s| s.first()).collect());
+        if let Error(_) = result {
+            return result;
+        }
+        let rests: Vec<Node> = seqs.iter().map(|s| s.rest()).collect();
+        if skip_nil && result == Nil {
+            seqs = rests.iter().map(|r| r.seq()).collect();
+            continue;
+        }
+        return cons(result, lazy(Lazy::Map(f, rests, skip_nil)));
+    }
+}
+
+/// Keeps the elements for which `pred` is truthy, or falsey if `keep` is
+/// false. A call returning an error ends the seq with that error.
+fn filter_step(pred: Node, coll: Node, keep: bool) -> Node {
+    let mut s = coll.seq();
+    loop {
+        match s {
+            Nil => return Nil,
+            Error(_) => return s,
+            ChunkedSeq { items, offset, more } => {
+                let mut kept = Vec::new();
+                for x in &items[offset..] {
+                    match apply(&pred, vec![x.clone()]) {
+                        e @ Error(_) => return chunk_then(kept, e),
+                        r => if truthy(&r) == keep {
+                            kept.push(x.clone());
+                        },
+                    }
+                }
+                if kept.is_empty() {
+                    s = more.seq();
+                    continue;
+                }
+                let more = lazy(Lazy::Filter(pred, *more, keep));
+                return ChunkedSeq { items: Arc::new(kept), offset: 0, more: Box::new(more) };
+            },
+            _ => {
+                let x = s.first();
+                match apply(&pred, vec![x.clone()]) {
+                    e @ Error(_) => return e,
+                    r => if truthy(&r) == keep {
+                        return cons(x, lazy(Lazy::Filter(pred, s.rest(), keep)));
+                    },
+                }
+                s = s.next();
+            },
+        }
+    }
+}
+
+pub fn truthy(n: &Node) -> bool {
+    !matches!(*n, Nil | Node::Bool(false))
+}
@@ -0,0 +1,375 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+use node::Node;
+use node::Node::{Symbol, List, Error};
+use eval::{Env, eval, eval_args, apply, check_waiting};
+
+/// A reference whose changes are made in transactions, each one seeing the
+/// refs as they were when it started.
+pub struct Ref {
+    /// The latest values, oldest first, with the commit that made each.
+    history: Mutex<VecDeque<(u64, Node)>>,
+}
+
+impl fmt::Debug for Ref {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Ref")
+    }
+}
+
+/// How many values a ref keeps for transactions that started before the
+/// latest commits.
+const HISTORY: usize = 10;
+
+impl Ref {
+    /// The latest value.
+    pub fn value(&self) -> Node {
+        self.history.lock().unwrap().back().unwrap().1.clone()
+    }
+
+    fn version(&self) -> u64 {
+        self.history.lock().unwrap().back().unwrap().0
+    }
+
+    /// The value as of commit `point`, if it is still kept.
+    fn value_at(&self, point: u64) -> Option<Node> {
+        self.history.lock().unwrap().iter().rev().find(|&&(v, _)| v <= point).map(|(_, n)| n.clone())
+    }
+}
+
+/// Numbers commits, so that a transaction can tell what was committed
+/// after it started.
+static CLOCK: AtomicU64 = AtomicU64::new(0);
+
+/// Held while a transaction checks for conflicts and commits.
+static COMMIT: Mutex<()> = Mutex::new(());
+
+/// What the transaction running on a thread has read and changed.
+struct Transaction {
+    read_point: u64,
+    values: Vec<(Arc<Ref>, Node)>,
+    /// Refs set with `alter` or `ref-set`, which no other transaction may
+    /// have changed when this one commits.
+    set: Vec<Arc<Ref>>,
+    ensured: Vec<Arc<Ref>>,
+    /// Functions given to `commute`, run again on the latest values when
+    /// committing.
+    commutes: Vec<(Arc<Ref>, Node, Vec<Node>)>,
+    /// Set on a conflict, after which the transaction only runs to its end
+    /// to be started again.
+    retry: bool,
+}
+
+thread_local! {
+    static TRANSACTION: RefCell<Option<Transaction>> = const { RefCell::new(None) };
+}
+
+const RETRY: Node = Error("Transaction is being retried");
+const MAX_RETRIES: usize = 10000;
+
+fn contains(refs: &[Arc<Ref>], r: &Arc<Ref>) -> bool {
+    refs.iter().any(|x| Arc::ptr_eq(x, r))
+}
+
+/// Runs `f` on the transaction in progress, failing outside `dosync`.
+fn in_transaction<T, F: FnOnce(&mut Transaction) -> Result<T, Node>>(f: F) -> Result<T, Node> {
+    TRANSACTION.with(|t| match *t.borrow_mut() {
+        Some(ref mut tx) if tx.retry => Err(RETRY),
+        Some(ref mut tx) => f(tx),
+        None => Err(Error("No transaction running")),
+    })
+}
+
+/// The value of `r` in the transaction in progress.
+fn read(tx: &mut Transaction, r: &Arc<Ref>) -> Result<Node, Node> {
+    if let Some((_, value)) = tx.values.iter().find(|(x, _)| Arc::ptr_eq(x, r)) {
+        return Ok(value.clone());
+    }
+    match r.value_at(tx.read_point) {
+        Some(value) => Ok(value),
+        None => {
+            tx.retry = true;
+            Err(RETRY)
+        },
+    }
+}
+
+fn write(tx: &mut Transaction, r: &Arc<Ref>, value: Node) {
+    match tx.values.iter_mut().find(|(x, _)| Arc::ptr_eq(x, r)) {
+        Some(entry) => entry.1 = value,
+        None => tx.values.push((r.clone(), value)),
+    }
+}
+
+/// `(deref r)` in a transaction sees the value as it was when the
+/// transaction started, or as the transaction has since set it.
+pub fn deref(r: &Arc<Ref>) -> Node {
+    let in_tx = TRANSACTION.with(|t| t.borrow().is_some());
+    if !in_tx {
+        return r.value();
+    }
+    in_transaction(|tx| read(tx, r)).unwrap_or_else(|e| e)
+}
+
+/// `(ref x)` is a ref to change in transactions.
+pub fn new_ref(args: &mut [Node], env: &mut Env) -> Node {
+    let value = eval_args(args, env).pop().unwrap();
+    let version = CLOCK.load(Ordering::SeqCst);
+    Node::Ref(Arc::new(Ref { history: Mutex::new(VecDeque::from(vec![(version, value)])) }))
+}
+
+fn expect_ref(n: &Node, err: &'static str) -> Result<Arc<Ref>, Node> {
+    match *n {
+        Node::Ref(ref r) => Ok(r.clone()),
+        _ => Err(Error(err)),
+    }
+}
+
+/// Sets `r` to `f` of its value in the transaction, retrying the
+/// transaction if another has changed `r` since it started.
+fn set_with(r: &Arc<Ref>, f: Option<(&Node, Vec<Node>)>, value: Node) -> Result<Node, Node> {
+    let current = in_transaction(|tx| {
+        if !contains(&tx.set, r) && r.version() > tx.read_point {
+            tx.retry = true;
+            return Err(RETRY);
+        }
+        read(tx, r)
+    })?;
+    let value = match f {
+        Some((f, mut args)) => {
+            args.insert(0, current);
+            match apply(f, args) {
+                e @ Error(_) => return Err(e),
+                value => value,
+            }
+        },
+        None => value,
+    };
+    in_transaction(|tx| {
+        write(tx, r, value.clone());
+        if !contains(&tx.set, r) {
+            tx.set.push(r.clone());
+        }
+        Ok(value)
+    })
+}
+
+/// `(alter r f & args)` sets `r` to `(f @r args...)` in the transaction.
+pub fn alter(args: &mut [Node], env: &mut Env) -> Node {
+    let mut args = eval_args(args, env);
+    let r = match expect_ref(&args.remove(0), "Expected a ref as first argument to alter") {
+        Ok(r) => r,
+        Err(e) => return e,
+    };
+    let f = args.remove(0);
+    set_with(&r, Some((&f, args)), Node::Nil).unwrap_or_else(|e| e)
+}
+
+/// `(ref-set r x)` sets `r` to `x` in the transaction.
+pub fn ref_set(args: &mut [Node], env: &mut Env) -> Node {
+    let mut args = eval_args(args, env);
+    let value = args.pop().unwrap();
+    match expect_ref(&args[0], "Expected a ref as first argument to ref-set") {
+        Ok(r) => set_with(&r, None, value).unwrap_or_else(|e| e),
+        Err(e) => e,
+    }
+}
+
+/// `(commute r f & args)` is `alter` for changes that can be made in any
+/// order, like adding to a count. When the transaction commits `f` is run
+/// again on the latest value of `r`, so other transactions changing `r`
+/// don't make it retry.
+pub fn commute(args: &mut [Node], env: &mut Env) -> Node {
+    let mut args = eval_args(args, env);
+    let r = match expect_ref(&args.remove(0), "Expected a ref as first argument to commute") {
+        Ok(r) => r,
+        Err(e) => return e,
+    };
+    let f = args.remove(0);
+    let current = match in_transaction(|tx| read(tx, &r)) {
+        Ok(value) => value,
+        Err(e) => return e,
+    };
+    let mut call = args.clone();
+    call.insert(0, current);
+    let value = match apply(&f, call) {
+        e @ Error(_) => return e,
+        value => value,
+    };
+    in_transaction(|tx| {
+        write(tx, &r, value.clone());
+        if !contains(&tx.set, &r) {
+            tx.commutes.push((r.clone(), f, args));
+        }
+        Ok(value)
+    }).unwrap_or_else(|e| e)
+}
+
+/// `(ensure r)` gives the value of `r`, making the transaction retry if
+/// another changes `r` before it commits.
+pub fn ensure(args: &mut [Node], env: &mut Env) -> Node {
+    let args = eval_args(args, env);
+    let r = match expect_ref(&args[0], "Expected a ref as argument to ensure") {
+        Ok(r) => r,
+        Err(e) => return e,
+    };
+    in_transaction(|tx| {
+        let value = read(tx, &r)?;
+        if !contains(&tx.ensured, &r) {
+            tx.ensured.push(r.clone());
+        }
+        Ok(value)
+    }).unwrap_or_else(|e| e)
+}
+
+/// Makes the changes of `tx`, or gives `Ok(false)` if another transaction
+/// got in first.
+fn commit(tx: Transaction) -> Result<bool, Node> {
+    let Transaction { read_point, values, set, ensured, commutes, .. } = tx;
+    let _commit = COMMIT.lock().unwrap();
+    if set.iter().chain(&ensured).any(|r| r.version() > read_point) {
+        return Ok(false);
+    }
+    let mut values: Vec<(Arc<Ref>, Node)> = values.into_iter().filter(|(r, _)| contains(&set, r)).collect();
+    for (r, f, args) in commutes {
+        let current = match values.iter().find(|(x, _)| Arc::ptr_eq(x, &r)) {
+            Some((_, value)) => value.clone(),
+            None => r.value(),
+        };
+        let mut call = args;
+        call.insert(0, current);
+        let value = match apply(&f, call) {
+            e @ Error(_) => return Err(e),
+            value => value,
+        };
+        match values.iter_mut().find(|(x, _)| Arc::ptr_eq(x, &r)) {
+            Some(entry) => entry.1 = value,
+            None => values.push((r, value)),
+        }
+    }
+    let version = CLOCK.load(Ordering::SeqCst) + 1;
+    for (r, value) in values {
+        let mut history = r.history.lock().unwrap();
+        history.push_back((version, value));
+        if history.len() > HISTORY {
+            history.pop_front();
+        }
+    }
+    CLOCK.store(version, Ordering::SeqCst);
+    Ok(true)
+}
+
+/// `(dosync body...)` evaluates the body as a transaction: its changes to
+/// refs are made all at once, if no other transaction has changed the same
+/// refs meanwhile, or else it is evaluated again. So the body shouldn't do
+/// anything else that can be seen. A `dosync` inside another is part of it.
+pub fn dosync(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let mut forms = vec![Symbol("do".to_owned())];
+    forms.append(args);
+    let body = List(forms);
+    if TRANSACTION.with(|t| t.borrow().is_some()) {
+        return eval(&mut body.clone(), env);
+    }
+    for attempt in 0..MAX_RETRIES {
+        if attempt > 0 {
+            if let Err(e) = check_waiting() {
+                return e;
+            }
+            // Gives the transaction that got in first time to finish.
+            thread::sleep(Duration::from_micros(attempt.min(100) as u64 * 10));
+        }
+        let tx = Transaction {
+            read_point: CLOCK.load(Ordering::SeqCst),
+            values: Vec::new(),
+            set: Vec::new(),
+            ensured: Vec::new(),
+            commutes: Vec::new(),
+            retry: false,
+        };
+        TRANSACTION.with(|t| *t.borrow_mut() = Some(tx));
+        let result = eval(&mut body.clone(), env);
+        let tx = TRANSACTION.with(|t| t.borrow_mut().take()).unwrap();
+        if tx.retry {
+            continue;
+        }
+        if let Error(_) = result {
+            return result;
+        }
+        match commit(tx) {
+            Ok(true) => return result,
+            Ok(false) => continue,
+            Err(e) => return e,
+        }
+    }
+    Error("Transaction retried too many times")
+}
+
+#[cfg(test)]
+mod tests {
+    use eval::{Env, eval_all};
+    use ns;
+
+    fn run(env: &mut Env, src: &str) -> String {
+        format!("{}", eval_all(src, env))
+    }
+
+    fn lib() -> Env {
+        let mut env = Env::new();
+        ns::load(include_str!("../lib/lib.clj"), &mut env);
+        env
+    }
+
+    #[test]
+    fn transactions() {
+        let mut env = lib();
+        assert_eq!(run(&mut env, "(def a (ref 100)) (def b (ref 0))
+                                  [(dosync (alter a - 30) (alter b + 30) [@a @b]) @a @b a]"),
+                   "[[70 30] 70 30 #<ref 70>]");
+        assert_eq!(run(&mut env, "[(dosync (ref-set a 5) (ensure a)) (dosync (alter a inc) (dosync (alter a inc)) @a)]"),
+                   "[5 7]");
+        assert_eq!(run(&mut env, "[(dosync (alter a inc) (alter b / 0)) @a @b]"), "[Error: Divide by zero 7 30]");
+        assert_eq!(run(&mut env, "(alter a inc)"), "Error: No transaction running");
+        assert_eq!(run(&mut env, "(dosync (alter (atom 1) inc))"), "Error: Expected a ref as first argument to alter");
+    }
+
+    #[test]
+    fn conflicting_transactions_retry() {
+        let mut env = lib();
+        // Each future's transaction tells the test when it has read, then
+        // waits for the test's own transaction to commit before going on.
+        assert_eq!(run(&mut env, "(def r (ref 0)) (def tries (atom 0)) (def read (promise)) (def written (promise))
+                                  (def f (future (dosync (swap! tries inc) (let [v @r] (deliver read true) @written (alter r + v 1)))))
+                                  @read (dosync (alter r + 10)) (deliver written true)
+                                  [@f @r @tries]"), "[21 21 2]");
+        assert_eq!(run(&mut env, "(def a (ref 0)) (def b (ref 0)) (def tries (atom 0)) (def read (promise)) (def written (promise))
+                                  (def f (future (dosync (swap! tries inc) (ensure a) (deliver read true) @written (alter b inc))))
+                                  @read (dosync (alter a inc)) (deliver written true)
+                                  [@f @tries]"), "[1 2]");
+        assert_eq!(run(&mut env, "(def c (ref 0)) (def tries (atom 0)) (def read (promise)) (def written (promise))
+                                  (def f (future (dosync (swap! tries inc) (commute c inc) (deliver read true) @written @c)))
+                                  @read (dosync (commute c + 10)) (deliver written true)
+                                  [@f @c @tries]"), "[1 11 1]");
+    }
+
+    #[test]
+    fn concurrent_transactions_are_isolated() {
+        let mut env = lib();
+        assert_eq!(run(&mut env, "(def a (ref 100)) (def b (ref 100)) (def n (ref 0))
+                                  (defn transfer [from to] (dosync (alter from dec) (alter to inc) (commute n inc)))
+                                  (defn times [k f] (doall (map (fn [_] (f)) (range k))))
+                                  (def workers (doall (map (fn [i] (future (times 50 (fn [] (if (even? i) (transfer a b) (transfer b a))))))
+                                                           (range 6))))
+                                  (doall (map deref workers))
+                                  [@a @b @n (dosync (+ @a @b))]"), "[100 100 300 200]");
+        assert_eq!(run(&mut env, "(def a (ref 0)) (def b (ref 0))
+                                  (def sums (doall (map (fn [i] (future (doall (map (fn [_] (dosync (alter a inc) (alter b inc) (= @a @b)))
+                                                                                    (range 50)))))
+                                                        (range 4))))
+                                  [(distinct (reduce concat [] (map deref sums))) @a @b]"), "[(true) 200 200]");
+    }
+}
@@ -0,0 +1,226 @@
+use node::Node;
+use node::Node::{Int, Str, Char, Bool, Vector, Regex, Nil, Error};
+use eval::{Env, eval_args};
+use seq::Seqable;
+use re;
+
+/// The text of a value as `str` sees it: strings and characters without
+/// quoting and `nil` as the empty string.
+pub fn to_str(n: &Node) -> String {
+    match *n {
+        Str(ref s) => s.clone(),
+        Char(c)    => c.to_string(),
+        Nil        => String::new(),
+        _          => format!("{}", n),
+    }
+}
+
+/// Converts a character index into a byte offset, allowing one past the end.
+fn byte_offset(s: &str, i: i64) -> Option<usize> {
+    if i < 0 {
+        return None;
+    }
+    let i = i as usize;
+    match s.char_indices().nth(i) {
+        Some((b, _)) => Some(b),
+        None if i == s.chars().count() => Some(s.len()),
+        None => None,
+    }
+}
+
+pub fn str(args: &mut [Node], env: &mut Env) -> Node {
+    Str(eval_args(args, env).iter().map(to_str).collect())
+}
+
+pub fn subs(args: &mut [Node], env: &mut Env) -> Node {
+    let args = eval_args(args, env);
+    let (s, start, end) = match args.as_slice() {
+        [Str(s), Int(start)]          => (s, *start, s.chars().count() as i64),
+        [Str(s), Int(start), Int(end)] => (s, *start, *end),
+        _ => return Error("Expected a string and one or two indices as arguments to subs"),
+    };
+    match (byte_offset(s, start), byte_offset(s, end)) {
+        (Some(b1), Some(b2)) if b1 <= b2 => Str(s[b1..b2].to_owned()),
+        _ => Error("String index out of range"),
+    }
+}
+
+pub fn split(args: &mut [Node], env: &mut Env) -> Node {
+    let args = eval_args(args, env);
+    let mut parts: Vec<Node> = match args.as_slice() {
+        [Str(s), Str(sep)] if sep.is_empty() => s.chars().map(|c| Str(c.to_string())).collect(),
+        [Str(s), Str(sep)] => s.split(sep.as_str()).map(|p| Str(p.to_owned())).collect(),
+        [Str(s), Char(c)]  => s.split(*c).map(|p| Str(p.to_owned())).collect(),
+        [Str(s), Regex(r)] => re::split(s, r),
+        _ => return Error("Expected a string and a separator as arguments to split"),
+    };
+    while parts.len() > 1 && parts.last() == Some(&Str(String::new())) {
+        parts.pop();
+    }
+    Vector(parts)
+}
+
+pub fn split_lines(args: &mut [Node], env: &mut Env) -> Node {
+    match eval_args(args, env).as_slice() {
+        [Str(s)] => Vector(s.lines().map(|l| Str(l.to_owned())).collect()),
+        _ => Error("Expected a string as argument to split-lines"),
+    }
+}
+
+pub fn join(args: &mut [Node], env: &mut Env) -> Node {
+    let args = eval_args(args, env);
+    let (sep, coll) = match args.as_slice() {
+        [coll]      => (String::new(), coll),
+        [sep, coll] => (to_str(sep), coll),
+        _ => return Error("Expected an optional separator and a collection as arguments to join"),
+    };
+    match coll.to_vec() {
+        Ok(v)  => Str(v.iter().map(to_str).collect::<Vec<String>>().join(&sep)),
+        Err(e) => e,
+    }
+}
+
+/// Applies `f` to a single string argument.
+fn with_str(args: &mut [Node], env: &mut Env, err: &'static str, f: fn(&str) -> Node) -> Node {
+    match eval_args(args, env).as_slice() {
+        [Str(s)] => f(s),
+        _ => Error(err),
+    }
+}
+
+pub fn trim(args: &mut [Node], env: &mut Env) -> Node {
+    with_str(args, env, "Expected a string as argument to trim", |s| Str(s.trim().to_owned()))
+}
+
+pub fn upper_case(args: &mut [Node], env: &mut Env) -> Node {
+    with_str(args, env, "Expected a string as argument to upper-case", |s| Str(s.to_uppercase()))
+}
+
+pub fn lower_case(args: &mut [Node], env: &mut Env) -> Node {
+    with_str(args, env, "Expected a string as argument to lower-case", |s| Str(s.to_lowercase()))
+}
+
+/// Checks a string against a string or character argument.
+fn test_str(args: &mut [Node], env: &mut Env, err: &'static str, f: fn(&str, &str) -> bool) -> Node {
+    match eval_args(args, env).as_slice() {
+        [Str(s), Str(sub)] => Bool(f(s, sub)),
+        [Str(s), Char(c)]  => Bool(f(s, &c.to_string())),
+        _ => Error(err),
+    }
+}
+
+pub fn starts_with(args: &mut [Node], env: &mut Env) -> Node {
+    test_str(args, env, "Expected two strings as arguments to starts-with?", |s, sub| s.starts_with(sub))
+}
+
+pub fn ends_with(args: &mut [Node], env: &mut Env) -> Node {
+    test_str(args, env, "Expected two strings as arguments to ends-with?", |s, sub| s.ends_with(sub))
+}
+
+pub fn includes(args: &mut [Node], env: &mut Env) -> Node {
+    test_str(args, env, "Expected two strings as arguments to includes?", |s, sub| s.contains(sub))
+}
+
+pub fn replace(args: &mut [Node], env: &mut Env) -> Node {
+    match eval_args(args, env).as_slice() {
+        [Str(s), Str(from), Str(to)] if !from.is_empty() => Str(s.replace(from.as_str(), to)),
+        [Str(s), Char(from), Char(to)] => Str(s.replace(*from, &to.to_string())),
+        [Str(s), Regex(r), replacement] => re::replace(s, r, replacement),
+        _ => Error("Expected a string, a match and a replacement as arguments to replace"),
+    }
+}
+
+/// The character index of the first match at or after `from`, or `nil`.
+pub fn index_of(args: &mut [Node], env: &mut Env) -> Node {
+    let args = eval_args(args, env);
+    let (s, needle, from) = match args.as_slice() {
+        [Str(s), needle]            => (s, to_str(needle), 0),
+        [Str(s), needle, Int(from)] => (s, to_str(needle), *from),
+        _ => return Error("Expected a string, a value and an optional index as arguments to index-of"),
+    };
+    let start = match byte_offset(s, from.max(0)) {
+        Some(b) => b,
+        None    => return Nil,
+    };
+    match s[start..].find(needle.as_str()) {
+        Some(b) => Int(s[..start + b].chars().count() as i64),
+        None    => Nil,
+    }
+}
+
+/// Reverses a string by characters, or any other collection as a seq.
+pub fn reverse(args: &mut [Node], env: &mut Env) -> Node {
+    let args = eval_args(args, env);
+    match args.as_slice() {
+        [Str(s)] => Str(s.chars().rev().collect()),
+        [coll]   => {
+            match coll.to_vec() {
+                Ok(v) if v.is_empty() => Node::List(vec![]),
+                Ok(mut v) => {
+                    v.reverse();
+                    Vector(v).seq()
+                },
+                Err(e) => e,
+            }
+        },
+        _ => Error("Expected one argument to reverse"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use node::Node::{Str, Char, Nil};
+    use eval::{Env, eval_all};
+    use super::to_str;
+
+    fn run(src: &str) -> String {
+        format!("{}", eval_all(src, &mut Env::new()))
+    }
+
+    #[test]
+    fn str_drops_quotes_and_nil() {
+        assert_eq!(to_str(&Str("a\"b".to_owned())), "a\"b");
+        assert_eq!(to_str(&Char('λ')), "λ");
+        assert_eq!(to_str(&Nil), "");
+        assert_eq!(run("(str \\a \"b\" nil 1 :c [\"d\"])"), "\"ab1:c[\\\"d\\\"]\"");
+    }
+
+    #[test]
+    fn indexes_by_character() {
+        assert_eq!(run("(subs \"héλlo\" 1 3)"), "\"éλ\"");
+        assert_eq!(run("(subs \"héλlo\" 5)"), "\"\"");
+        assert_eq!(run("(subs \"héλlo\" 6)"), "Error: String index out of range");
+        assert_eq!(run("(subs \"abc\" 2 1)"), "Error: String index out of range");
+        assert_eq!(run("(subs \"abc\" -1)"), "Error: String index out of range");
+        assert_eq!(run("(index-of \"héλlo\" \"l\")"), "3");
+        assert_eq!(run("(index-of \"héλlo\" \\l 3)"), "3");
+        assert_eq!(run("(index-of \"héλlo\" \"l\" 4)"), "nil");
+        assert_eq!(run("(reverse \"héλ\")"), "\"λéh\"");
+        assert_eq!(run("(count \"héλ\")"), "3");
+    }
+
+    #[test]
+    fn splits_and_joins() {
+        assert_eq!(run("(split \"a,b,,\" \",\")"), "[\"a\" \"b\"]");
+        assert_eq!(run("(split \",\" \",\")"), "[\"\"]");
+        assert_eq!(run("(split \"héλ\" \"\")"), "[\"h\" \"é\" \"λ\"]");
+        assert_eq!(run("(split \"a b\" \\space)"), "[\"a\" \"b\"]");
+        assert_eq!(run("(split-lines \"a\\r\\nb\\n\")"), "[\"a\" \"b\"]");
+        assert_eq!(run("(join [1 nil \"b\"])"), "\"1b\"");
+        assert_eq!(run("(join \\, (list 1 2))"), "\"1,2\"");
+        assert_eq!(run("(join \", \" 1)"), "Error: Don't know how to create a seq");
+    }
+
+    #[test]
+    fn tests_and_changes_strings() {
+        assert_eq!(run("[(starts-with? \"abc\" \"ab\") (ends-with? \"abc\" \\c) (includes? \"abc\" \"d\")]"),
+                   "[true true false]");
+        assert_eq!(run("(starts-with? \"abc\" 1)"), "Error: Expected two strings as arguments to starts-with?");
+        assert_eq!(run("(replace \"a.b\" \\. \\-)"), "\"a-b\"");
+        assert_eq!(run("(replace \"ab\" \"\" \"x\")"),
+                   "Error: Expected a string, a match and a replacement as arguments to replace");
+        assert_eq!(run("[(trim \" \\ta \\n\") (upper-case \"straße\") (lower-case \"ÉA\")]"),
+                   "[\"a\" \"STRASSE\" \"éa\"]");
+        assert_eq!(run("(trim 1)"), "Error: Expected a string as argument to trim");
+    }
+}
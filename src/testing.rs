@@ -0,0 +1,432 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use node::Node;
+use node::Node::{Int, Bool, Symbol, Keyword, List, Vector, Map, Set, Lambda, Nil, Error};
+use eval::{Env, eval, eval_args, eval_all, apply, is_special_form};
+use string::to_str;
+use fmt::clj_files;
+use ns;
+use io;
+
+/// Pass, fail and error counts, kept in `*report-counters*` like
+/// clojure.test does.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Counts {
+    pub test: i64,
+    pub pass: i64,
+    pub fail: i64,
+    pub error: i64,
+}
+
+const COUNTERS: [&str; 4] = ["test", "pass", "fail", "error"];
+
+impl Counts {
+    fn get(&self, key: &str) -> i64 {
+        match key {
+            "test" => self.test,
+            "pass" => self.pass,
+            "fail" => self.fail,
+            _      => self.error,
+        }
+    }
+
+    fn to_node(self) -> Node {
+        Map(COUNTERS.iter().map(|k| (Keyword(k.to_string()), Int(self.get(k)))).collect())
+    }
+
+    fn from_node(n: Option<Node>) -> Counts {
+        let mut counts = Counts::default();
+        if let Some(Map(m)) = n {
+            for (k, v) in m {
+                match (k, v) {
+                    (Keyword(ref k), Int(n)) if k == "test"  => counts.test = n,
+                    (Keyword(ref k), Int(n)) if k == "pass"  => counts.pass = n,
+                    (Keyword(ref k), Int(n)) if k == "fail"  => counts.fail = n,
+                    (Keyword(ref k), Int(n)) if k == "error" => counts.error = n,
+                    _ => (),
+                }
+            }
+        }
+        counts
+    }
+
+    pub fn add(&mut self, other: Counts) {
+        self.test += other.test;
+        self.pass += other.pass;
+        self.fail += other.fail;
+        self.error += other.error;
+    }
+}
+
+fn counts(env: &Env) -> Counts {
+    Counts::from_node(env.get("*report-counters*"))
+}
+
+fn inc_counter(env: &Env, key: &str) {
+    let mut c = counts(env);
+    match key {
+        "test" => c.test += 1,
+        "pass" => c.pass += 1,
+        "fail" => c.fail += 1,
+        _      => c.error += 1,
+    }
+    env.define("*report-counters*".to_owned(), c.to_node());
+}
+
+fn global_vec(env: &Env, name: &str) -> Vec<Node> {
+    match env.get(name) {
+        Some(Vector(v)) => v,
+        _ => Vec::new(),
+    }
+}
+
+/// Writes a failure or error to `*out*` along with the test and `testing`
+/// contexts it happened in.
+fn report(env: &Env, kind: &str, msg: &Option<String>, lines: &[(&str, String)]) {
+    let test = match env.get("*testing-vars*") {
+        Some(Symbol(s)) => s,
+        _ => String::new(),
+    };
+    let mut s = format!("\n{} in ({})\n", kind, test);
+    let contexts: Vec<String> = global_vec(env, "*testing-contexts*").iter().map(to_str).collect();
+    if !contexts.is_empty() {
+        s += &format!("{}\n", contexts.join(" "));
+    }
+    if let Some(ref msg) = *msg {
+        s += &format!("{}\n", msg);
+    }
+    for &(label, ref value) in lines {
+        s += &format!("{:>9} {}\n", format!("{}:", label), value);
+    }
+    io::out(&s, env);
+}
+
+/// `(deftest name body...)` defines a test function and registers it to
+/// be run by `run-tests`.
+pub fn deftest(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    let name = match args.first() {
+        Some(Symbol(s)) => s.clone(),
+        _ => return Error("Expected a symbol as first argument to deftest"),
+    };
+    let mut body = args.split_off(1);
+    body.insert(0, Symbol("do".to_owned()));
+    let test = Lambda { name: Some(name.clone()), params: vec![], body: Arc::new(List(body)), env: env.clone() };
+    env.define(name.clone(), test);
+    let mut tests = global_vec(env, "*tests*");
+    if !tests.contains(&Symbol(name.clone())) {
+        tests.push(Symbol(name));
+    }
+    env.define("*tests*".to_owned(), Vector(tests));
+    Nil
+}
+
+/// `(is form)` or `(is form message)`. Comparisons with `=` report the
+/// expected and actual values, other calls the values they were given,
+/// and `(is (thrown? body...))` passes when the body evaluates to an error.
+pub fn is(args: &mut [Node], env: &mut Env) -> Node {
+    if args.is_empty() || args.len() > 2 {
+        return Error("Expected a form and an optional message as arguments to is");
+    }
+    let form = args[0].clone();
+    let msg = args.get_mut(1).map(|m| to_str(&eval(m, env)));
+
+    let call = match form {
+        List(ref items) if !items.is_empty() => Some((items[0].clone(), items[1..].to_vec())),
+        _ => None,
+    };
+    let (passed, lines) = match call {
+        Some((Symbol(ref head), ref body)) if head == "thrown?" => {
+            let mut body = body.clone();
+            body.insert(0, Symbol("do".to_owned()));
+            let value = eval(&mut List(body), env);
+            (matches!(value, Error(_)), vec![("expected", format!("{}", form)), ("actual", format!("{}", value))])
+        },
+        Some((Symbol(ref head), ref operands)) if head == "=" && operands.len() >= 2 => {
+            let values = eval_args(&mut operands.clone(), env);
+            if let Some(e) = values.iter().find(|v| matches!(v, Error(_))) {
+                return error(env, &msg, &form, e);
+            }
+            let actual = if values.len() == 2 {
+                format!("{}", values[1])
+            } else {
+                format!("{}", List(values[1..].to_vec()))
+            };
+            (values.windows(2).all(|w| w[0] == w[1]),
+             vec![("form", format!("{}", form)), ("expected", format!("{}", values[0])), ("actual", actual)])
+        },
+        Some((Symbol(ref head), ref operands)) if !is_special_form(head) => {
+            let f = eval(&mut Symbol(head.clone()), env);
+            let values = eval_args(&mut operands.clone(), env);
+            if let Some(e) = values.iter().chain(Some(&f)).find(|v| matches!(v, Error(_))) {
+                return error(env, &msg, &form, e);
+            }
+            let result = apply(&f, values.clone());
+            if let Error(_) = result {
+                return error(env, &msg, &form, &result);
+            }
+            let mut called = vec![Symbol(head.clone())];
+            called.extend(values);
+            (truthy(&result), vec![("expected", format!("{}", form)), ("actual", format!("(not {})", List(called)))])
+        },
+        _ => {
+            let value = eval(&mut form.clone(), env);
+            if let Error(_) = value {
+                return error(env, &msg, &form, &value);
+            }
+            (truthy(&value), vec![("expected", format!("{}", form)), ("actual", format!("{}", value))])
+        },
+    };
+    if passed {
+        inc_counter(env, "pass");
+    } else {
+        inc_counter(env, "fail");
+        report(env, "FAIL", &msg, &lines);
+    }
+    Bool(passed)
+}
+
+fn truthy(n: &Node) -> bool {
+    !matches!(*n, Nil | Bool(false))
+}
+
+fn error(env: &Env, msg: &Option<String>, form: &Node, e: &Node) -> Node {
+    inc_counter(env, "error");
+    report(env, "ERROR", msg, &[("expected", format!("{}", form)), ("actual", format!("{}", e))]);
+    Bool(false)
+}
+
+/// Replaces the symbols in `names` throughout `form`.
+fn substitute(form: &Node, names: &[Node], values: &[Node]) -> Node {
+    if let Some(i) = names.iter().position(|n| n == form && matches!(*n, Symbol(_))) {
+        return values[i].clone();
+    }
+    match *form {
+        List(ref v)   => List(v.iter().map(|n| substitute(n, names, values)).collect()),
+        Vector(ref v) => Vector(v.iter().map(|n| substitute(n, names, values)).collect()),
+        Set(ref v)    => Set(v.iter().map(|n| substitute(n, names, values)).collect()),
+        Map(ref m)    => Map(m.iter().map(|(k, v)| (substitute(k, names, values), substitute(v, names, values))).collect()),
+        _ => form.clone(),
+    }
+}
+
+/// `(are [x y] (= x y) 1 1 2 2)` checks the template once for each group
+/// of arguments.
+pub fn are(args: &mut [Node], env: &mut Env) -> Node {
+    let names = match args.first() {
+        Some(Vector(v)) if !v.is_empty() => v.clone(),
+        _ => return Error("Expected a vector of names as first argument to are"),
+    };
+    let template = match args.get(1) {
+        Some(t) => t.clone(),
+        None => return Error("Expected a template as second argument to are"),
+    };
+    let values = &args[2..];
+    if !values.len().is_multiple_of(names.len()) {
+        return Error("Expected the arguments to are to fill the template evenly");
+    }
+    let mut passed = true;
+    for group in values.chunks(names.len()) {
+        let mut form = vec![substitute(&template, &names, group)];
+        if is(&mut form, env) != Bool(true) {
+            passed = false;
+        }
+    }
+    Bool(passed)
+}
+
+/// `(testing "context" body...)` adds the context to any failures in body.
+pub fn testing(args: &mut Vec<Node>, env: &mut Env) -> Node {
+    if args.is_empty() {
+        return Error("Expected a context string as first argument to testing");
+    }
+    let context = eval(&mut args.remove(0), env);
+    let outer = global_vec(env, "*testing-contexts*");
+    let mut contexts = outer.clone();
+    contexts.push(context);
+    env.define("*testing-contexts*".to_owned(), Vector(contexts));
+    let mut result = Nil;
+    for form in args.iter_mut() {
+        result = eval(form, env);
+    }
+    env.define("*testing-contexts*".to_owned(), Vector(outer));
+    result
+}
+
+/// `(use-fixtures :each f...)` wraps every test, and `:once` the whole run,
+/// in functions that take the test to run as their argument.
+pub fn use_fixtures(args: &mut [Node], env: &mut Env) -> Node {
+    let args = eval_args(args, env);
+    let name = match args.first() {
+        Some(Keyword(k)) if k == "each" => "*each-fixtures*",
+        Some(Keyword(k)) if k == "once" => "*once-fixtures*",
+        _ => return Error("Expected :each or :once as first argument to use-fixtures"),
+    };
+    env.define(name.to_owned(), Vector(args[1..].to_vec()));
+    Nil
+}
+
+/// `(test-var 'name)` runs one test, counting it as an error if its body
+/// evaluates to one outside of `is`.
+pub fn test_var(args: &mut [Node], env: &mut Env) -> Node {
+    let name = match eval_args(args, env).pop() {
+        Some(Symbol(s)) => s,
+        _ => return Error("Expected a test name as argument to test-var"),
+    };
+    let test = match env.get(&name) {
+        Some(test) => test,
+        None => return Error("Couldn't resolve test"),
+    };
+    env.define("*testing-vars*".to_owned(), Symbol(name));
+    inc_counter(env, "test");
+    let result = apply(&test, vec![]);
+    if let Error(_) = result {
+        inc_counter(env, "error");
+        report(env, "ERROR", &None, &[("actual", format!("{}", result))]);
+    }
+    env.define("*testing-vars*".to_owned(), Nil);
+    Nil
+}
+
+/// A function of no arguments calling `fixture` with `test`.
+fn wrap(fixture: Node, test: Node, env: &Env) -> Node {
+    let mut env = env.clone();
+    env.insert("fixture".to_owned(), fixture);
+    env.insert("test".to_owned(), test);
+    Lambda {
+        name: None,
+        params: vec![],
+        body: Arc::new(List(vec![Symbol("fixture".to_owned()), Symbol("test".to_owned())])),
+        env,
+    }
+}
+
+/// Runs every test defined in `env` under its fixtures.
+pub fn run_all(env: &mut Env) -> Counts {
+    env.define("*report-counters*".to_owned(), Counts::default().to_node());
+    let each = global_vec(env, "*each-fixtures*");
+    let mut calls = vec![Symbol("do".to_owned())];
+    for name in global_vec(env, "*tests*") {
+        let run = Lambda {
+            name: None,
+            params: vec![],
+            body: Arc::new(List(vec![
+                Symbol("test-var".to_owned()),
+                List(vec![Symbol("quote".to_owned()), name]),
+            ])),
+            env: env.clone(),
+        };
+        calls.push(List(vec![each.iter().rev().fold(run, |inner, f| wrap(f.clone(), inner, env))]));
+    }
+    let all = Lambda { name: None, params: vec![], body: Arc::new(List(calls)), env: env.clone() };
+    let all = global_vec(env, "*once-fixtures*").iter().rev().fold(all, |inner, f| wrap(f.clone(), inner, env));
+    if let e @ Error(_) = apply(&all, vec![]) {
+        inc_counter(env, "error");
+        report(env, "ERROR", &Some("in fixtures".to_owned()), &[("actual", format!("{}", e))]);
+    }
+    counts(env)
+}
+
+fn summary(c: &Counts) -> String {
+    format!("\nRan {} tests containing {} assertions.\n{} failures, {} errors.\n",
+            c.test, c.pass + c.fail + c.error, c.fail, c.error)
+}
+
+/// `(run-tests)` runs the tests defined so far and returns the counts.
+pub fn run_tests(_args: &mut [Node], env: &mut Env) -> Node {
+    let c = run_all(env);
+    io::out(&summary(&c), env);
+    c.to_node()
+}
+
+/// Loads `lib` and then `src` into `env` and runs the tests they define.
+pub fn run_source(mut env: Env, src: &str, lib: &str) -> Counts {
+    ns::load(lib, &mut env);
+    if let e @ Error(_) = eval_all(src, &mut env) {
+        io::out(&format!("\nERROR while loading\n   actual: {}\n", e), &env);
+        let mut c = run_all(&mut env);
+        c.error += 1;
+        return c;
+    }
+    run_all(&mut env)
+}
+
+/// `brojure test [paths...]` runs the `*_test.clj` files under each path,
+/// `test` by default, and exits with 1 if any test failed. Directories are
+/// put on the load path ahead of `BROJURE_PATH`, so tests can require
+/// namespaces kept alongside them.
+pub fn run(args: &[String]) -> i32 {
+    let lib = fs::read_to_string("lib/lib.clj").unwrap_or_default();
+    let default = ["test".to_owned()];
+    let paths = if args.is_empty() { &default[..] } else { args };
+
+    let mut files = Vec::new();
+    for path in paths {
+        let path = Path::new(path);
+        let mut found = Vec::new();
+        if let Err(e) = clj_files(path, &mut found) {
+            eprintln!("{}: {}", path.display(), e);
+            return 2;
+        }
+        files.extend(found.into_iter().filter(|f| {
+            !path.is_dir() || f.file_name().is_some_and(|n| n.to_string_lossy().ends_with("_test.clj"))
+        }));
+    }
+
+    let mut load_path: Vec<PathBuf> = paths.iter().map(PathBuf::from).filter(|p| p.is_dir()).collect();
+    load_path.extend(ns::load_path());
+
+    let mut total = Counts::default();
+    for file in files {
+        println!("\nTesting {}", file.display());
+        let env = Env::new();
+        env.registry().write().unwrap().set_load_path(load_path.clone());
+        match fs::read_to_string(&file) {
+            Ok(src) => total.add(run_source(env, &src, &lib)),
+            Err(e) => {
+                println!("\nERROR while loading\n   actual: {}", e);
+                total.error += 1;
+            },
+        }
+    }
+    print!("{}", summary(&total));
+    if total.fail + total.error > 0 { 1 } else { 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use eval::{Env, eval_all};
+    use super::{run_source, Counts};
+
+    #[test]
+    fn counts_passes_failures_and_errors() {
+        let src = "(deftest t (is (= 1 1)) (is (= 1 2)) (is (nth [1])) (are [x y] (= x y) 1 1 2 2))";
+        assert_eq!(run_source(Env::new(), src, ""), Counts { test: 1, pass: 3, fail: 1, error: 1 });
+    }
+
+    #[test]
+    fn reports_to_out() {
+        let mut env = Env::new();
+        let out = eval_all("(deftest t (is (= 1 2))) (with-out-str (run-tests))", &mut env);
+        assert_eq!(format!("{}", out),
+                   "\"\\nFAIL in (t)\\n    form: (= 1 2)\\nexpected: 1\\n  actual: 2\\n\\n\
+                    Ran 1 tests containing 1 assertions.\\n1 failures, 0 errors.\\n\"");
+    }
+
+    #[test]
+    fn runs_fixtures_around_tests() {
+        let src = "(def log [])
+                   (defn fixture [t] (do (def log (conj log :before)) (t) (def log (conj log :after))))
+                   (use-fixtures :each fixture)
+                   (deftest a (is (= log [:before])))
+                   (deftest b (is (= log [:before :after :before])))";
+        assert_eq!(run_source(Env::new(), src, ""), Counts { test: 2, pass: 2, fail: 0, error: 0 });
+    }
+
+    #[test]
+    fn lib_suite_passes() {
+        let c = run_source(Env::new(), include_str!("../test/lib_test.clj"), include_str!("../lib/lib.clj"));
+        assert!(c.test > 0);
+        assert_eq!((c.fail, c.error), (0, 0));
+    }
+}
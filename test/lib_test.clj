@@ -0,0 +1,52 @@
+(deftest predicates
+  (testing "not"
+    (is (= false (not true)))
+    (is (= true (not nil))))
+  (testing "signs"
+    (are [x pos neg] (= [pos neg] [(pos? x) (neg? x)])
+      1 true false
+      0 false false
+      -3 false true))
+  (testing "parity"
+    (are [x] (odd? x) 1 3 -5)
+    (are [x] (even? x) 0 2 -4)))
+
+(deftest arithmetic
+  (is (= 2 (inc 1)))
+  (is (= 0 (dec 1)))
+  (is (= 10 (sum [1 2 3 4])))
+  (is (= 0 (sum [])))
+  (is (= 24 (product [1 2 3 4])))
+  (is (= 1 (product []))))
+
+(deftest pop-drops-the-last-item
+  (is (= [5 6] (pop [5 6 7])))
+  (is (vector? (pop [5 6 7])))
+  (is (= [] (pop [:a]))))
+
+(deftest take-and-drop
+  (testing "take"
+    (is (= '(1 2) (take 2 [1 2 3])))
+    (is (= '() (take 0 [1 2 3])))
+    (is (= '(1 2 3) (take 5 [1 2 3]))))
+  (testing "drop"
+    (is (= '(3) (drop 2 [1 2 3])))
+    (is (= '() (drop 5 [1 2 3])))))
+
+(deftest take-while-and-drop-while
+  (is (= '(1 3) (take-while odd? [1 3 4 5])))
+  (is (= '(4 5) (drop-while odd? [1 3 4 5])))
+  (is (= '() (take-while even? [1 2]))))
+
+(deftest infinite-sequences
+  (testing "iterate"
+    (is (= '(1 2 4 8) (take 4 (iterate (fn [x] (* 2 x)) 1)))))
+  (testing "cycle"
+    (is (= '(1 2 1 2 1) (take 5 (cycle [1 2]))))
+    (is (= '() (cycle []))))
+  (testing "interleave"
+    (is (= '(1 :a 2 :b) (interleave [1 2 3] [:a :b])))
+    (is (= '(0 1 1 2 2 3) (take 6 (interleave (iterate inc 0) (iterate inc 1)))))))
+
+(deftest project-euler
+  (is (= 233168 (euler1))))
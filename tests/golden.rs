@@ -0,0 +1,106 @@
+//! Runs each `tests/golden/*.clj` file through the REPL a line at a time
+//! and compares the transcript with the `.out` file next to it. Set
+//! `BLESS=1` to write the transcripts instead, after checking them by hand.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+const PROMPT: &str = "λ> ";
+
+/// The REPL's output for `src`, with each line of input after the prompt
+/// it was read at. Blank lines and comments aren't sent.
+fn transcript(src: &str) -> String {
+    let lines: Vec<&str> = src.lines().filter(|l| !l.trim().is_empty() && !l.starts_with(';')).collect();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_brojure"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Couldn't start the REPL");
+    {
+        let stdin = child.stdin.as_mut().unwrap();
+        for line in &lines {
+            writeln!(stdin, "{}", line).unwrap();
+        }
+        writeln!(stdin, ":exit").unwrap();
+    }
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    let mut out = String::new();
+    for (line, result) in lines.iter().zip(stdout.split(PROMPT).skip(1)) {
+        out.push_str(PROMPT);
+        out.push_str(line);
+        out.push('\n');
+        out.push_str(result);
+    }
+    out
+}
+
+#[test]
+fn golden_transcripts() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let bless = env::var_os("BLESS").is_some();
+    let mut files: Vec<_> = fs::read_dir(&dir).unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "clj"))
+        .collect();
+    files.sort();
+    assert!(!files.is_empty());
+
+    let mut failed = Vec::new();
+    for file in files {
+        let actual = transcript(&fs::read_to_string(&file).unwrap());
+        let golden = file.with_extension("out");
+        if bless {
+            fs::write(&golden, &actual).unwrap();
+            continue;
+        }
+        let expected = fs::read_to_string(&golden).unwrap_or_default();
+        if actual != expected {
+            eprintln!("--- {}\n+++ actual\n{}", golden.display(), diff(&expected, &actual));
+            failed.push(file.display().to_string());
+        }
+    }
+    assert!(failed.is_empty(), "transcripts differ: {}", failed.join(", "));
+}
+
+/// `brojure -e` reads standard input the way a Unix filter does.
+#[test]
+fn eval_option_filters_stdin() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_brojure"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(["-e", "(println (read-line)) (count (filter (fn [l] (includes? l \",\")) (line-seq *in*)))"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Couldn't start brojure");
+    child.stdin.as_mut().unwrap().write_all(b"name,n\na,1\nb\nc,3\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "name,n\n2\n");
+}
+
+/// The lines that differ, marked with `-` and `+`.
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let mut out = String::new();
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) if e == a => (),
+            (e, a) => {
+                if let Some(e) = e {
+                    out.push_str(&format!("-{}\n", e));
+                }
+                if let Some(a) = a {
+                    out.push_str(&format!("+{}\n", a));
+                }
+            },
+        }
+    }
+    out
+}
@@ -0,0 +1,22 @@
+; Atoms keep state that survives environment cloning.
+(def counter (atom 0))
+counter
+(defn next-id! [] (swap! counter inc))
+(next-id!)
+(next-id!)
+@counter
+(deref counter)
+(reset! counter 10)
+(swap! counter + 1 2)
+(compare-and-set! counter 0 1)
+(compare-and-set! counter 13 1)
+@counter
+(def seen (atom []))
+(add-watch counter :seen (fn [key ref old new] (swap! seen conj [key old new])))
+(swap! counter inc)
+@seen
+(remove-watch counter :seen)
+(def positive (atom 1 :validator pos?))
+(reset! positive -1)
+@positive
+(deref 1)
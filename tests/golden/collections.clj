@@ -0,0 +1,30 @@
+; Vectors, lists, maps and sets.
+[1 (+ 1 1) 3]
+'(1 2 3)
+(list 1 2 3)
+{:a 1 :b (+ 1 1)}
+#{1 2 3}
+(count [1 2 3])
+(count {:a 1})
+(nth [1 2 3] 1)
+(nth [1 2 3] 5)
+(nth (range 10) 7)
+(conj [1 2] 3)
+(conj '(1 2) 0)
+(conj {:a 1} [:b 2])
+(conj #{1} 1)
+(get {:a 1} :a)
+(get {:a 1} :b :missing)
+(get [10 20] 1)
+(assoc {:a 1} :b 2 :a 3)
+(assoc [1 2 3] 0 :x)
+(contains? {:a 1} :a)
+(contains? #{1 2} 3)
+(hash-map :a 1 :b 2)
+(hash-set 1 2 2 3)
+(distinct [1 2 1 3 2])
+(sort [3 1 2])
+(sort > [3 1 2])
+(= [1 2] '(1 2))
+(= {:a 1 :b 2} {:b 2 :a 1})
+(= (hash [1 2]) (hash '(1 2)))
@@ -0,0 +1,29 @@
+; Dynamic vars are rebound per thread with binding, and restored after.
+(def ^:dynamic *depth* 0)
+(defn depth [] *depth*)
+(binding [*depth* 1] (depth))
+(depth)
+(binding [*depth* 1] (binding [*depth* 2] (depth)))
+(binding [*depth* 1] (set! *depth* 5) (depth))
+(set! *depth* 5)
+(binding [*depth* 1] (/ 1 0))
+(depth)
+(binding [*depth* 1] @(future (depth)))
+(binding [*depth* 1] (def later (future (sleep 20) (depth))))
+@later
+(def other (future (binding [*depth* 9] (sleep 20) (depth))))
+(depth)
+@other
+(def ^{:dynamic true} *name* "world")
+(binding [*name* "you"] (str "hello " *name*))
+(def fixed 1)
+(binding [fixed 2] fixed)
+(binding [undefined 2] undefined)
+; with-redefs changes the root for every thread, for stubbing in tests.
+(defn fetch [] :real)
+(defn load-page [] (fetch))
+(with-redefs [fetch (fn [] :stub)] [(load-page) @(future (load-page))])
+(load-page)
+(with-redefs [slurp (fn [path] "stubbed")] (slurp "no-such-file"))
+(slurp "no-such-file")
+(binding [slurp (fn [path] "stubbed")] 1)
@@ -0,0 +1,17 @@
+; Reading and writing files, with errors as values.
+(def dir "target/golden-files")
+(mkdir dir)
+(spit (str dir "/notes.txt") "first\nsecond\n")
+(spit (str dir "/notes.txt") "third\n" :append true)
+(slurp (str dir "/notes.txt"))
+(with-open [r (reader (str dir "/notes.txt"))] (doall (map upper-case (line-seq r))))
+(with-open [w (writer (str dir "/out.txt"))] (write w "n = " 42))
+(slurp (str dir "/out.txt"))
+(file-exists? (str dir "/out.txt"))
+(list-dir dir)
+(delete-file (str dir "/out.txt"))
+(delete-file (str dir "/out.txt"))
+(delete-file (str dir "/out.txt") true)
+(list-dir dir)
+(slurp "target/golden-files/missing.txt")
+(line-seq 1)
@@ -0,0 +1,21 @@
+; Namespaces, require and load-file.
+(def x 1)
+(in-ns 'scratch)
+(def x 2)
+[x user/x scratch/x]
+(inc x)
+(in-ns 'user)
+x
+(require '[tests.golden.ns.shapes :as shapes :refer [area]])
+(area 2)
+(shapes/area 1)
+tests.golden.ns.geometry-util/pi
+(require '[brojure.string :as str])
+(str/join "," [1 2 3])
+(brojure.core/count [1 2])
+(require 'tests.golden.ns.missing)
+(require 'tests.golden.ns.cycle-a)
+(load-file "tests/golden/ns/geometry_util.clj")
+pi
+(ns my.app (:require [tests.golden.ns.shapes :refer [area]]))
+(area 3)
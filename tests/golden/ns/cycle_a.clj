@@ -0,0 +1,2 @@
+(ns tests.golden.ns.cycle-a
+  (:require tests.golden.ns.cycle-b))
@@ -0,0 +1,3 @@
+(ns tests.golden.ns.geometry-util)
+
+(def pi 3)
@@ -0,0 +1,6 @@
+(ns tests.golden.ns.shapes
+  "Areas of shapes, required by namespaces.clj."
+  (:require [tests.golden.ns.geometry-util :as util]))
+
+(defn area [r]
+  (* util/pi r r))
@@ -0,0 +1,25 @@
+; Arithmetic across the numeric tower.
+(+ 1 2 3)
+(- 10 4 3)
+(- 5)
+(* 2 3 4)
+(/ 10 4)
+(/ 6 3)
+(/ 1.0 4)
+(+ 1/3 1/6)
+(* 9223372036854775807 2)
+(+ 1N 2)
+(+ 1.5M 1)
+(mod -7 3)
+(rem -7 3)
+(quot 7 2)
+(< 1 2 3)
+(> 3 1 2)
+(<= 1 1 2)
+(>= 2 2 1)
+(= 1 1.0)
+(== 1 1.0)
+(not= 1 2)
+(compare 1 2)
+(+ 1 "a")
+(/ 1 0)
@@ -0,0 +1,19 @@
+; Printing values readably and for people.
+(println "a" "b" [1 "c"])
+(prn "a" \b [1 "c"])
+(pr-str "quoted" \c)
+(print-str "plain" \c)
+(format "%5d|%-5s|%.2f|%x" 42 "ab" 3.14159 255)
+(printf "%s and %s%n" 1 2)
+(pprint {:a [1 2 3] :b "x"})
+(pprint-code '(defn f [x] (if (pos? x) (inc x) (dec x))))
+(fn [x] x)
+(defn named [x] x)
+named
++
+(def *print-length* 3)
+(range 10)
+(def *print-length* nil)
+(def *print-level* 1)
+[1 [2 [3]]]
+(def *print-level* nil)
@@ -0,0 +1,29 @@
+; Lazy sequences and the functions from lib.clj.
+(range 5)
+(range 2 10 3)
+(first [1 2 3])
+(rest [1 2 3])
+(next [1])
+(seq [])
+(cons 0 [1 2])
+(map inc [1 2 3])
+(map + [1 2] [10 20 30])
+(filter odd? (range 10))
+(remove odd? (range 10))
+(reduce + 0 [1 2 3 4])
+(concat [1 2] '(3) [])
+(repeat 3 :x)
+(take 5 (repeat :y))
+(take 5 (iterate inc 0))
+(take 5 (cycle [1 2]))
+(drop 2 [1 2 3 4])
+(take-while neg? [-2 -1 0 1])
+(drop-while neg? [-2 -1 0 1])
+(interleave [1 2 3] [:a :b :c])
+(for [x (range 4)] (* x x))
+(lazy-seq (cons 1 nil))
+(doall (map inc [1 2]))
+(dorun (map inc [1 2]))
+(sum (range 101))
+(product [1 2 3 4 5])
+(euler1)
@@ -0,0 +1,21 @@
+; Special forms, closures and errors.
+(def x 10)
+x
+(defn add-x [y] (+ x y))
+(add-x 5)
+(if true :yes :no)
+(if nil :yes)
+(or nil false 3)
+(and 1 2 nil)
+(do 1 2 3)
+(let [a 1 b (+ a 1)] [a b])
+((fn [a b] (* a b)) 3 4)
+(let [adder (fn [n] (fn [m] (+ n m)))] ((adder 2) 3))
+(quote (a b c))
+'sym
+(undefined-symbol)
+(add-x)
+(nth [1])
+("not a function" 1)
+(map (fn [x] (* x 10)) [1 2])
+(reduce (fn [acc x] (conj acc (inc x))) [] [1 2 3])
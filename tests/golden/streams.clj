@@ -0,0 +1,18 @@
+; Printing to *out*, capturing it and rebinding it.
+(with-out-str (print "a" 1) (prn [:b "c"]) (printf "%d%%" 5))
+(with-out-str)
+(with-out-str (println "before") (nth [] 1))
+(println "plain")
+(binding [*out* *err*] (println "to stderr") :done)
+(def w (writer "target/golden-streams.txt"))
+(binding [*out* w] (println "into a file") (pprint {:a 1}))
+(close w)
+(slurp "target/golden-streams.txt")
+(flush)
+[*in* *out* *err*]
+(def ^:dynamic x 1)
+(defn show [] (println "x is" x))
+(binding [x 2] (show))
+(show)
+(binding [y 1] y)
+(binding [*out* 1] (println "nowhere"))
@@ -0,0 +1,25 @@
+; Strings, characters and regular expressions.
+"hello\nworld"
+\a
+(str "a" 1 :b nil \c)
+(subs "hello" 1 3)
+(split "a,b,,c" ",")
+(split "a1b22c" #"\d+")
+(split-lines "one\ntwo")
+(join ", " [1 2 3])
+(trim "  padded  ")
+(upper-case "shout")
+(lower-case "QUIET")
+(starts-with? "brojure" "bro")
+(ends-with? "brojure" "jure")
+(includes? "brojure" "oj")
+(replace "a-b-c" "-" "+")
+(replace "a1b2" #"\d" "<$0>")
+(index-of "hello" "l")
+(reverse "abc")
+(re-pattern "a+")
+(re-find #"\d+" "abc123def45")
+(re-find #"(\w)(\d)" "a1")
+(re-matches #"\d+" "123")
+(re-matches #"\d+" "123a")
+(re-seq #"\d" "a1b2c3")